@@ -5,7 +5,10 @@ extern crate bytes;
 use angel_whisper::{AngelSystem, ClientSession, Sendable, ServerSession};
 
 use angel_whisper::crypto::gen_keypair;
+use angel_whisper::crypto::{gen_nonce, open, open_precomputed, precompute, seal,
+                            seal_precomputed};
 use angel_whisper::errors::{AWError, AWResult};
+use angel_whisper::llsd::session::loopback::handshake;
 use angel_whisper::system::Handler;
 use angel_whisper::system::ServiceHub;
 use angel_whisper::system::authenticator::DumbAuthenticator;
@@ -66,3 +69,55 @@ fn ping_pong_bench(b: &mut Bencher) {
                assert!(res.is_ok());
            })
 }
+
+// Payload size for per-message crypto benches.
+const MSG_SIZE: usize = 1024;
+
+fn ready_sessions() -> (ClientSession, ServerSession) {
+    let client_lt = gen_keypair();
+    let server_lt = gen_keypair();
+
+    let mut client_session = ClientSession::new(server_lt.0, client_lt);
+    let (server_session, _) = handshake(&mut client_session, &server_lt).unwrap();
+    (client_session, server_session)
+}
+
+// Baseline: what every message cost before keys were precomputed.
+#[bench]
+fn seal_open_bench(b: &mut Bencher) {
+    let (client_pk, client_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+    let data = vec![0u8; MSG_SIZE];
+    b.bytes = MSG_SIZE as u64;
+    b.iter(|| {
+               let nonce = gen_nonce();
+               let sealed = seal(&data, &nonce, &server_pk, &client_sk);
+               test::black_box(open(&sealed, &nonce, &client_pk, &server_sk).unwrap());
+           })
+}
+
+#[bench]
+fn seal_open_precomputed_bench(b: &mut Bencher) {
+    let (client_pk, client_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+    let client_key = precompute(&server_pk, &client_sk);
+    let server_key = precompute(&client_pk, &server_sk);
+    let data = vec![0u8; MSG_SIZE];
+    b.bytes = MSG_SIZE as u64;
+    b.iter(|| {
+               let nonce = gen_nonce();
+               let sealed = seal_precomputed(&data, &nonce, &client_key);
+               test::black_box(open_precomputed(&sealed, &nonce, &server_key).unwrap());
+           })
+}
+
+#[bench]
+fn session_message_bench(b: &mut Bencher) {
+    let (client_session, server_session) = ready_sessions();
+    let data = vec![0u8; MSG_SIZE];
+    b.bytes = MSG_SIZE as u64;
+    b.iter(|| {
+               let frame = client_session.make_message(&data).unwrap();
+               test::black_box(server_session.read_msg(&frame).unwrap());
+           })
+}
@@ -1,116 +1,365 @@
-use errors::{AWError, AWResult};
+use crate::errors::{AWError, AWResult};
 
-use llsd::errors::LlsdError;
-use llsd::frames::{Frame, FrameKind};
-use llsd::session::Sendable;
-use llsd::session::server::Session;
+use bytes::{Bytes, BytesMut};
+use crate::llsd::errors::LlsdError;
+use crate::llsd::frames::{ErrorCode, Frame, FrameKind, Termination};
+use crate::llsd::session::{KeyPair, Sendable, SessionConfig};
+use crate::llsd::session::cookie::{COOKIE_SIZE, CookieJar};
+use crate::llsd::session::keyring::KeyRing;
+use crate::llsd::session::server::{Session, make_welcome};
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
 use std::sync::{Arc, RwLock};
-use system::{Handler, ServiceHub};
-use system::authenticator::Authenticator;
-use system::sessionstore::SessionStore;
-use typemap::TypeMap;
+use crate::system::{AsyncHandler, Handler, ServiceHub, Services, ShareSession};
+use crate::system::authenticator::Authenticator;
+use crate::system::push::PushRegistry;
+use crate::system::sessionstore::{ReapStats, SessionStore};
 
-pub struct AngelSystem<S: SessionStore, A: Authenticator, H: Handler> {
+/// Knobs of `AngelSystem`. Default is what system used before it was
+/// configurable, except that multiplexed connections can't have unlimited
+/// number of requests in flight anymore.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Lifetimes of every session system creates.
+    pub session: SessionConfig,
+    /// Initiate is rejected once there are this many sessions in the store.
+    /// `None` means no limit.
+    pub max_sessions: Option<usize>,
+    /// How many requests multiplexed connection can have in flight at once.
+    /// Server stops reading from connection until one of them is answered.
+    /// Zero is treated as one.
+    pub max_in_flight: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            session: SessionConfig::default(),
+            max_sessions: None,
+            max_in_flight: 64,
+        }
+    }
+}
+
+pub struct AngelSystem<S: SessionStore, A: Authenticator, H> {
     sessions: S,
     authenticator: A,
-    public_key: PublicKey,
-    secret_key: SecretKey,
+    keys: Arc<RwLock<KeyRing>>,
 
     services: ServiceHub,
+    pushes: PushRegistry,
     handler: Arc<H>,
+    config: Config,
+    cookies: Arc<RwLock<CookieJar>>,
 }
 
-impl<S: SessionStore, A: Authenticator, H: Handler> Clone for AngelSystem<S, A, H> {
+impl<S: SessionStore, A: Authenticator, H> Clone for AngelSystem<S, A, H> {
     fn clone(&self) -> AngelSystem<S, A, H> {
         AngelSystem {
             sessions: self.sessions.clone(),
             authenticator: self.authenticator.clone(),
-            public_key: self.public_key,
-            secret_key: self.secret_key.clone(),
+            keys: self.keys.clone(),
             services: self.services.clone(),
+            pushes: self.pushes.clone(),
             handler: self.handler.clone(),
+            config: self.config,
+            cookies: self.cookies.clone(),
         }
     }
 }
 
-impl<S: SessionStore, A: Authenticator, H: Handler> AngelSystem<S, A, H> {
+/// Builds `AngelSystem`. Services can only be registered here: once system
+/// is built handlers read them without locks.
+///
+/// ```ignore
+/// let system = AngelSystem::builder(store, authenticator, keys, handler)
+///     .config(config)
+///     .services(Services::new().with(pool).with(settings))
+///     .build();
+/// ```
+pub struct Builder<S: SessionStore, A: Authenticator, H> {
+    store: S,
+    authenticator: A,
+    handler: H,
+    keys: KeyRing,
+    config: Config,
+    services: Services,
+}
+
+impl<S: SessionStore, A: Authenticator, H> Builder<S, A, H> {
+    /// Knobs of the system. Default if not set.
+    pub fn config(mut self, config: Config) -> Builder<S, A, H> {
+        self.config = config;
+        self
+    }
+
+    /// Services handlers get through `ServiceHub`. `PushRegistry` is added
+    /// to them by the system.
+    pub fn services(mut self, services: Services) -> Builder<S, A, H> {
+        self.services = services;
+        self
+    }
+
+    /// Make the system.
+    pub fn build(self) -> AngelSystem<S, A, H> {
+        let pushes = PushRegistry::default();
+        let services = self.services.with(pushes.clone());
+        AngelSystem {
+            sessions: self.store,
+            authenticator: self.authenticator,
+            keys: Arc::new(RwLock::new(self.keys)),
+            services: Arc::new(services),
+            pushes: pushes,
+            handler: Arc::new(self.handler),
+            config: self.config,
+            cookies: Arc::new(RwLock::new(CookieJar::new())),
+        }
+    }
+}
+
+impl<S: SessionStore, A: Authenticator, H> AngelSystem<S, A, H> {
+    /// Start building system. See `Builder`. `keys` are server long-term
+    /// keys: either a single key pair or a `KeyRing`.
+    pub fn builder<K: Into<KeyRing>>(store: S,
+                                     authenticator: A,
+                                     keys: K,
+                                     handler: H)
+                                     -> Builder<S, A, H> {
+        Builder {
+            store: store,
+            authenticator: authenticator,
+            handler: handler,
+            keys: keys.into(),
+            config: Config::default(),
+            services: Services::new(),
+        }
+    }
+
     pub fn new(store: S,
                authenticator: A,
                pk: PublicKey,
                sk: SecretKey,
                handler: H)
                -> AngelSystem<S, A, H> {
-        AngelSystem {
-            sessions: store,
-            authenticator: authenticator,
-            public_key: pk,
-            secret_key: sk,
-            services: Arc::new(RwLock::new(TypeMap::custom())),
-            handler: Arc::new(handler),
+        AngelSystem::builder(store, authenticator, (pk, sk), handler).build()
+    }
+
+    pub fn with_config(store: S,
+                       authenticator: A,
+                       pk: PublicKey,
+                       sk: SecretKey,
+                       handler: H,
+                       config: Config)
+                       -> AngelSystem<S, A, H> {
+        AngelSystem::builder(store, authenticator, (pk, sk), handler)
+            .config(config)
+            .build()
+    }
+
+    /// Same as `with_config`, but server starts with several long-term keys.
+    pub fn with_key_ring(store: S,
+                         authenticator: A,
+                         keys: KeyRing,
+                         handler: H,
+                         config: Config)
+                         -> AngelSystem<S, A, H> {
+        AngelSystem::builder(store, authenticator, keys, handler)
+            .config(config)
+            .build()
+    }
+
+    /// Services handlers get.
+    pub fn services(&self) -> ServiceHub {
+        self.services.clone()
+    }
+
+    /// Registry of connections that can take pushed messages. Same one is
+    /// available to handlers through `ServiceHub`.
+    pub fn push_registry(&self) -> PushRegistry {
+        self.pushes.clone()
+    }
+
+    /// Long-term public keys server answers Hello with, current first.
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        match self.keys.read() {
+            Ok(keys) => keys.public_keys(),
+            Err(_) => Vec::new(),
         }
     }
 
-    pub fn process(&self, req: Frame) -> AWResult<Frame> {
-        match req.kind {
-            FrameKind::Hello => self.process_hello(&req),
-            FrameKind::Initiate => self.process_initiate(&req),
-            FrameKind::Message => self.process_message(&req),
-            _ => unreachable!(),
+    /// Start using `next` as current long-term key. Clients that still trust
+    /// only the old one keep working until it's retired with `retire_key`.
+    pub fn rotate_key(&self, next: KeyPair) -> AWResult<()> {
+        match self.keys.write() {
+            Ok(mut keys) => {
+                keys.rotate(next);
+                Ok(())
+            }
+            Err(_) => Err(AWError::ServerFault),
+        }
+    }
+
+    /// Stop answering Hello sealed to retiring key. Sessions that were made
+    /// with it are not affected. Returns false if key is current or unknown.
+    pub fn retire_key(&self, pk: &PublicKey) -> AWResult<bool> {
+        match self.keys.write() {
+            Ok(mut keys) => Ok(keys.retire(pk)),
+            Err(_) => Err(AWError::ServerFault),
         }
     }
 
+    /// Erase sessions that are of no use anymore. See `SessionStore::reap`.
+    /// Cookie key is rotated as well, so it doesn't outlive its period when
+    /// there are no new clients. Pushes to erased sessions stop going
+    /// anywhere.
+    pub fn reap(&self) -> ReapStats {
+        self.rotate_cookies();
+        let (stats, reaped) = self.sessions.reap();
+        for session_id in &reaped {
+            self.pushes.unregister(session_id);
+        }
+        stats
+    }
+
+    /// Send unsolicited message to the client that owns the session. Only
+    /// works if client is connected over transport that supports it.
+    pub fn push(&self, session_id: &PublicKey, data: &[u8]) -> AWResult<()> {
+        let session_lock = match self.sessions.find_by_pk(session_id) {
+            None => return Err(AWError::SessionNotFound),
+            Some(session_lock) => session_lock,
+        };
+        let session = match session_lock.read() {
+            Err(_) => return Err(AWError::ServerFault),
+            Ok(session) => session,
+        };
+        self.pushes.push(&session, data)
+    }
+
+    /// Reply to a frame that couldn't be processed: handshake failed, session
+    /// is unknown, frame is broken and so on. Errors returned by handler don't
+    /// get here, they are sent back in reply Message. Client gets Termination
+    /// frame with error code instead of a dropped connection. Frame is
+    /// encrypted if session is still ready, so only its owner can read it.
+    pub fn terminate(&self, session_id: &PublicKey, err: &AWError) -> Frame {
+        let err = err.to_app_error();
+        let termination = Termination::new(err.code, err.message);
+        if let Some(session_lock) = self.sessions.find_by_pk(session_id) {
+            if let Ok(session) = session_lock.read() {
+                return session.make_termination(&termination);
+            }
+        }
+        termination.to_plain_frame(*session_id)
+    }
+
+    /// Replace cookie key once it's used for half of handshake timeout, so
+    /// cookies live no longer than handshake is allowed to take. Called on
+    /// every Hello and `reap`.
+    pub fn rotate_cookies(&self) {
+        let period = self.config.session.handshake_timeout / 2;
+        let stale = match self.cookies.read() {
+            Ok(cookies) => cookies.is_older_than(period),
+            Err(_) => return,
+        };
+        if stale {
+            if let Ok(mut cookies) = self.cookies.write() {
+                cookies.rotate(period);
+            }
+        }
+    }
+
+    // Nothing is stored until client proves it can finish the handshake, so
+    // flood of Hello frames costs server only CPU.
     fn process_hello(&self, frame: &Frame) -> AWResult<Frame> {
         // Verify it's a new session
         if self.sessions.find_by_pk(&frame.id).is_some() {
             let llsd_error = LlsdError::InvalidSessionState;
             return Err(llsd_error.into());
         }
-        let session = Session::new(frame.id);
+        self.rotate_cookies();
+        let cookies = match self.cookies.read() {
+            Err(_) => return Err(AWError::ServerFault),
+            Ok(cookies) => cookies,
+        };
+        let keys = match self.keys.read() {
+            Err(_) => return Err(AWError::ServerFault),
+            Ok(keys) => keys,
+        };
+        let welcome = make_welcome(frame, &keys, &cookies)?;
+        Ok(welcome)
+    }
 
-        // If inserting session failed - bail out early.
-        if self.sessions.insert(session).is_none() {
-            return Err(AWError::ServerFault);
+    fn process_initiate(&self, frame: &Frame) -> AWResult<Frame> {
+        // Initiate can be replayed for as long as cookie is valid. Don't let
+        // it take over existing session. Cookie of accepted Initiate is
+        // spent, so replay can't bring back closed or reaped session either.
+        if self.sessions.find_by_pk(&frame.id).is_some() {
+            return Err(LlsdError::InvalidSessionState.into());
         }
-
-        if let Some(session_lock) = self.sessions.find_by_pk(&frame.id) {
-            let session_guard = session_lock.write();
-            if let Ok(mut session) = session_guard {
-                let welcome = try!(session.make_welcome(frame, &self.secret_key));
-                return Ok(welcome);
-            }
-        } else {
-            return Err(AWError::SessionNotFound);
+        let mut session = {
+            let cookies = match self.cookies.read() {
+                Err(_) => return Err(AWError::ServerFault),
+                Ok(cookies) => cookies,
+            };
+            Session::from_cookie(frame, &cookies, self.config.session)?
+        };
+        let key = session.validate_initiate(frame)?;
+        let identity = match self.authenticator.authenticate(&key) {
+            Some(identity) => identity,
+            None => return Err(LlsdError::HandshakeFailed.into()),
+        };
+        let ready_frame = session.make_ready(frame, &key)?;
+        session.set_identity(identity);
+        match self.cookies.write() {
+            Err(_) => return Err(AWError::ServerFault),
+            Ok(mut cookies) => cookies.spend(&frame.payload[..COOKIE_SIZE], &frame.id)?,
         }
-        Err(AWError::ServerFault)
+        // Store checks for existing session and the limit under the same lock
+        // it inserts with, so concurrent Initiates can't go over the limit or
+        // both take the same session.
+        let max_sessions = self.config.max_sessions.unwrap_or(usize::MAX);
+        self.sessions.try_insert_bounded(session, max_sessions)?;
+        Ok(ready_frame)
     }
 
-    // TODO: Rewrite this madness
-    fn process_initiate(&self, frame: &Frame) -> AWResult<Frame> {
-        match self.sessions.find_by_pk(&frame.id) {
-            None => Err(LlsdError::InvalidSessionState.into()),
-            Some(session_lock) => {
-                let session_guard = session_lock.write();
-                if let Ok(mut session) = session_guard {
-                    match session.validate_initiate(frame) {
-                        Err(err) => Err(err.into()),
-                        Ok(key) => {
-                            if !self.authenticator.is_valid(&key) {
-                                return Err(AWError::SessionNotFound);
-                            }
-                            let ready_frame = try!(session.make_ready(frame, &key));
-                            Ok(ready_frame)
-                        }
-                    }
-                } else {
-                    // Failed to aquire write lock for a session.
-                    return Err(AWError::ServerFault);
-                }
-            }
+    // Client is done with the session. Reply is sealed before session is
+    // destroyed, so client knows it was really closed.
+    fn process_termination(&self, frame: &Frame) -> AWResult<Frame> {
+        let session_lock = match self.sessions.find_by_pk(&frame.id) {
+            None => return Err(AWError::SessionNotFound),
+            Some(session_lock) => session_lock,
+        };
+        let ack = {
+            let mut session = match session_lock.write() {
+                Err(_) => return Err(AWError::ServerFault),
+                Ok(session) => session,
+            };
+            session.read_termination(frame)?;
+            let closed = Termination::new(ErrorCode::SessionClosed, "Session closed");
+            let ack = session.make_termination(&closed);
+            session.destroy();
+            ack
+        };
+        self.sessions.destroy(&frame.id);
+        self.pushes.unregister(&frame.id);
+        Ok(ack)
+    }
+
+    /// Process any frame that doesn't need a handler. Message frames are
+    /// left to `process` and `process_async`. Frames only server is supposed
+    /// to send are rejected.
+    fn process_control(&self, req: &Frame) -> AWResult<Frame> {
+        match req.kind {
+            FrameKind::Hello => self.process_hello(req),
+            FrameKind::Initiate => self.process_initiate(req),
+            FrameKind::Termination => self.process_termination(req),
+            FrameKind::Welcome |
+            FrameKind::Ready |
+            FrameKind::Message => Err(LlsdError::UnexpectedFrame(req.kind).into()),
         }
     }
 
-    fn process_message(&self, frame: &Frame) -> AWResult<Frame> {
+    // Find session for the frame and decrypt its payload. Session that
+    // outlived its lifetime is refused even if reaper didn't get to it yet.
+    fn open_message(&self, frame: &Frame) -> AWResult<(ShareSession, BytesMut)> {
         let session_lock = match self.sessions.find_by_pk(&frame.id) {
             None => return Err(LlsdError::InvalidSessionState.into()),
             Some(session_lock) => session_lock,
@@ -120,17 +369,58 @@ impl<S: SessionStore, A: Authenticator, H: Handler> AngelSystem<S, A, H> {
                 Err(_) => return Err(AWError::ServerFault),
                 Ok(session) => session,
             };
-            try!(session.read_msg(frame))
+            if !session.is_valid() {
+                return Err(LlsdError::ExpiredSession.into());
+            }
+            session.read_msg(frame)?
         };
-        // this is going to take Arc<RWLock<Session>> as argument.
-        let mut payload = req.into();
-        let res = try!(self.handler
-                           .handle(self.services.clone(), session_lock.clone(), &mut payload));
+        Ok((session_lock, req))
+    }
+
+    // Encrypt handler's reply with the session it came from. Handler errors
+    // go back in the reply too: session is fine, only the request failed.
+    fn seal_reply(&self, session_lock: &ShareSession, res: AWResult<Bytes>) -> AWResult<Frame> {
         let session = match session_lock.read() {
             Err(_) => return Err(AWError::ServerFault),
             Ok(session) => session,
         };
-        session.make_message(&res).map_err(|e| e.into())
+        let reply = match res {
+            Ok(ref response) => session.make_reply(Ok(response)),
+            Err(ref err) => session.make_reply(Err(&err.to_app_error())),
+        };
+        reply.map_err(|e| e.into())
+    }
+}
+
+impl<S: SessionStore, A: Authenticator, H: Handler> AngelSystem<S, A, H> {
+    pub fn process(&self, req: Frame) -> AWResult<Frame> {
+        match req.kind {
+            FrameKind::Message => self.process_message(&req),
+            _ => self.process_control(&req),
+        }
+    }
+
+    fn process_message(&self, frame: &Frame) -> AWResult<Frame> {
+        let (session_lock, mut payload) = self.open_message(frame)?;
+        let res = self.handler
+            .handle(self.services.clone(), session_lock.clone(), &mut payload);
+        self.seal_reply(&session_lock, res)
+    }
+}
+
+impl<S: SessionStore, A: Authenticator, H: AsyncHandler> AngelSystem<S, A, H> {
+    /// Same as `process`, but message frames are handed to an `AsyncHandler`,
+    /// so the handler can wait on whatever it needs without blocking the
+    /// caller's thread. Other frames are still processed inline.
+    pub async fn process_async(&self, req: Frame) -> AWResult<Frame> {
+        if req.kind != FrameKind::Message {
+            return self.process_control(&req);
+        }
+        let (session_lock, payload) = self.open_message(&req)?;
+        let res = self.handler
+            .handle(self.services.clone(), session_lock.clone(), payload)
+            .await;
+        self.seal_reply(&session_lock, res)
     }
 }
 
@@ -142,7 +432,7 @@ impl<S: SessionStore, A: Authenticator, H: Handler> AngelSystem<S, A, H> {
 pub mod tokio {
 
     use super::{AngelSystem, Authenticator, Handler, SessionStore};
-    use frames::Frame;
+    use crate::frames::Frame;
     use futures::{BoxFuture, Future, future};
     use std::io;
     use std::sync::Arc;
@@ -170,10 +460,319 @@ pub mod tokio {
         type Future = BoxFuture<Self::Response, Self::Error>;
 
         fn call(&self, req: Self::Request) -> Self::Future {
+            let session_id = req.id;
             match self.system.process(req) {
                 Ok(res) => future::ok(res).boxed(),
-                Err(err) => future::err(io::Error::new(io::ErrorKind::Other, err)).boxed(),
+                Err(err) => future::ok(self.system.terminate(&session_id, &err)).boxed(),
+            }
+        }
+    }
+}
+
+/* System On std futures
+ * */
+
+#[cfg(feature = "async-runtime")]
+pub mod runtime {
+    use super::{AngelSystem, AsyncHandler, Authenticator, SessionStore};
+    use bytes::BytesMut;
+    use crate::errors::AWError;
+    use crate::frames::{Frame, FrameKind};
+    use crate::llsd::POISONED_LOCK_MSG;
+    use crate::llsd::errors::LlsdError;
+    use crate::llsd::runtime::{RequestId, Transport, read_frame, read_tagged_frame, write_frame,
+                               write_tagged_frame};
+    use crate::llsd::runtime::PUSH_REQUEST_ID;
+    use crate::system::{Handler, HandlerFuture, ServiceHub, ShareSession};
+    use crate::system::push::{PushRegistry, PushSink};
+    use crate::system::sessionstore::ReapStats;
+    use sodiumoxide::crypto::box_::PublicKey;
+    use std::cmp;
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio1::net::{TcpListener, TcpStream};
+    use tokio1::sync::{Semaphore, mpsc};
+    use tokio1::task::{self, JoinHandle};
+    use tokio1::time;
+
+    /// Runs `Handler` on tokio's blocking thread pool, so existing handlers
+    /// can be used with `Server` without stalling the reactor.
+    pub struct Blocking<H: Handler> {
+        inner: Arc<H>,
+    }
+
+    impl<H: Handler> Blocking<H> {
+        pub fn new(handler: H) -> Blocking<H> {
+            Blocking { inner: Arc::new(handler) }
+        }
+    }
+
+    impl<H: Handler> AsyncHandler for Blocking<H> {
+        fn handle(&self,
+                  services: ServiceHub,
+                  session: ShareSession,
+                  msg: BytesMut)
+                  -> HandlerFuture {
+            let handler = self.inner.clone();
+            let job = task::spawn_blocking(move || {
+                let mut msg = msg;
+                handler.handle(services, session, &mut msg)
+            });
+            Box::pin(async move {
+                match job.await {
+                    Ok(res) => res,
+                    Err(_) => Err(AWError::ServerFault),
+                }
+            })
+        }
+    }
+
+    type Responses = mpsc::Sender<io::Result<(RequestId, Frame)>>;
+
+    // Pushes go through the same queue as responses. Push that doesn't fit
+    // into the queue is not delivered.
+    struct ConnectionSink(Responses);
+
+    impl PushSink for ConnectionSink {
+        fn deliver(&self, frame: Frame) -> bool {
+            self.0.try_send(Ok((PUSH_REQUEST_ID, frame))).is_ok()
+        }
+    }
+
+    // Sessions whose pushes go through this connection. Session gets here
+    // only after message sealed with it came over the connection, so nobody
+    // can take pushes of a session they don't own.
+    struct ConnectionPushes {
+        pushes: PushRegistry,
+        sink: Arc<dyn PushSink>,
+        // `None` once connection is closed.
+        sessions: Mutex<Option<HashSet<PublicKey>>>,
+    }
+
+    impl ConnectionPushes {
+        fn new(pushes: PushRegistry, sink: Arc<dyn PushSink>) -> ConnectionPushes {
+            ConnectionPushes {
+                pushes: pushes,
+                sink: sink,
+                sessions: Mutex::new(Some(HashSet::new())),
+            }
+        }
+
+        // Session opened a message that came over this connection.
+        fn attach(&self, session_id: PublicKey) {
+            let mut sessions = self.sessions.lock().expect(POISONED_LOCK_MSG);
+            if let Some(ref mut sessions) = *sessions {
+                if sessions.insert(session_id) {
+                    self.pushes.register(session_id, self.sink.clone());
+                }
+            }
+        }
+
+        // Leave sessions that moved to another connection alone.
+        fn close(&self) {
+            let sessions = self.sessions.lock().expect(POISONED_LOCK_MSG).take();
+            for session_id in sessions.into_iter().flatten() {
+                self.pushes.release(&session_id, &self.sink);
+            }
+        }
+    }
+
+    /// TCP server for `AngelSystem` on top of tokio 1.x. Each connection is
+    /// served by its own task. With pipelined transport requests on a
+    /// connection are answered in order, with multiplexed transport every
+    /// request gets its own task and is answered as soon as it's done. Only
+    /// `Config::max_in_flight` requests of a connection run at once.
+    /// Messages can only be pushed to clients using multiplexed transport.
+    pub struct Server<S: SessionStore, A: Authenticator, H: AsyncHandler> {
+        system: Arc<AngelSystem<S, A, H>>,
+        transport: Transport,
+    }
+
+    impl<S: SessionStore, A: Authenticator, H: AsyncHandler> Clone for Server<S, A, H> {
+        fn clone(&self) -> Server<S, A, H> {
+            Server::with_transport(self.system.clone(), self.transport)
+        }
+    }
+
+    impl<S, A, H> Server<S, A, H>
+    where
+        S: SessionStore + 'static,
+        A: Authenticator + 'static,
+        H: AsyncHandler,
+    {
+        /// Create server with pipelined transport.
+        pub fn new(system: Arc<AngelSystem<S, A, H>>) -> Server<S, A, H> {
+            Server::with_transport(system, Transport::Pipelined)
+        }
+
+        pub fn with_transport(system: Arc<AngelSystem<S, A, H>>,
+                              transport: Transport)
+                              -> Server<S, A, H> {
+            Server {
+                system: system,
+                transport: transport,
+            }
+        }
+
+        /// Accept connections until listener fails.
+        pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let server = self.clone();
+                tokio1::spawn(async move {
+                    // Connection errors only affect that connection.
+                    let _ = server.serve_connection(stream).await;
+                });
+            }
+        }
+
+        /// Serve a single connection until client hangs up.
+        pub async fn serve_connection(self, stream: TcpStream) -> io::Result<()> {
+            match self.transport {
+                Transport::Pipelined => self.serve_pipelined(stream).await,
+                Transport::Multiplexed => self.serve_multiplexed(stream).await,
+            }
+        }
+
+        async fn serve_pipelined(self, stream: TcpStream) -> io::Result<()> {
+            let (mut reader, mut writer) = stream.into_split();
+            while let Some(req) = read_frame(&mut reader).await? {
+                let session_id = req.id;
+                let res = match self.system.process_async(req).await {
+                    Ok(res) => res,
+                    Err(err) => self.system.terminate(&session_id, &err),
+                };
+                write_frame(&mut writer, &res).await?;
             }
+            Ok(())
+        }
+
+        // Every session that sent a message over this connection can receive
+        // pushes until the connection is closed.
+        async fn serve_multiplexed(self, stream: TcpStream) -> io::Result<()> {
+            let (mut reader, mut writer) = stream.into_split();
+            let limit = cmp::max(self.system.config.max_in_flight, 1);
+            let in_flight = Arc::new(Semaphore::new(limit));
+            let (tx, mut rx) = mpsc::channel(limit);
+            let pushes = Arc::new(ConnectionPushes::new(self.system.push_registry(),
+                                                        Arc::new(ConnectionSink(tx.clone()))));
+
+            // Only this task writes to the socket, so responses never interleave.
+            let responder = tokio1::spawn(async move {
+                while let Some(res) = rx.recv().await {
+                    let (request_id, frame) = res?;
+                    write_tagged_frame(&mut writer, request_id, &frame).await?;
+                }
+                Ok::<(), io::Error>(())
+            });
+
+            let read = loop {
+                // Next request waits until there's room for it.
+                let permit = match in_flight.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break Ok(()),
+                };
+                let (request_id, req) = match read_tagged_frame(&mut reader).await {
+                    Ok(Some(tagged)) => tagged,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                };
+                // Reply to it would be taken for a push.
+                if request_id == PUSH_REQUEST_ID {
+                    break Err(io::Error::new(io::ErrorKind::InvalidData, LlsdError::BadFrame));
+                }
+                // Responder gave up on this connection.
+                if tx.is_closed() {
+                    break Ok(());
+                }
+                let system = self.system.clone();
+                let pushes = pushes.clone();
+                let tx = tx.clone();
+                tokio1::spawn(async move {
+                    let session_id = req.id;
+                    let is_message = req.kind == FrameKind::Message;
+                    let res = match system.process_async(req).await {
+                        Ok(res) => {
+                            // Message was opened with the session, so it came
+                            // from session's owner.
+                            if is_message {
+                                pushes.attach(session_id);
+                            }
+                            res
+                        }
+                        Err(err) => system.terminate(&session_id, &err),
+                    };
+                    let _ = tx.send(Ok((request_id, res))).await;
+                    drop(permit);
+                });
+            };
+            pushes.close();
+            drop(pushes);
+            drop(tx);
+            let written = match responder.await {
+                Ok(res) => res,
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Responder task failed")),
+            };
+            read.and(written)
+        }
+    }
+
+    /// Background task that periodically erases useless sessions. Keeps
+    /// running totals of what it removed. Task is stopped when `Reaper` is
+    /// dropped.
+    pub struct Reaper {
+        totals: Arc<Mutex<(usize, ReapStats)>>,
+        task: JoinHandle<()>,
+    }
+
+    impl Reaper {
+        /// Start reaping sessions of the system every `period`. Must be called
+        /// inside of tokio runtime.
+        pub fn spawn<S, A, H>(system: Arc<AngelSystem<S, A, H>>, period: Duration) -> Reaper
+        where
+            S: SessionStore + 'static,
+            A: Authenticator + 'static,
+            H: Send + Sync + 'static,
+        {
+            let totals = Arc::new(Mutex::new((0, ReapStats::default())));
+            let task_totals = totals.clone();
+            let task = tokio1::spawn(async move {
+                let mut ticks = time::interval(period);
+                loop {
+                    ticks.tick().await;
+                    let system = system.clone();
+                    // Reaping takes store's write lock, keep it off the reactor.
+                    let stats = match task::spawn_blocking(move || system.reap()).await {
+                        Ok(stats) => stats,
+                        Err(_) => continue,
+                    };
+                    let mut totals = task_totals.lock().expect(POISONED_LOCK_MSG);
+                    totals.0 += 1;
+                    totals.1 += stats;
+                }
+            });
+            Reaper {
+                totals: totals,
+                task: task,
+            }
+        }
+
+        /// How many times reaper ran so far.
+        pub fn runs(&self) -> usize {
+            self.totals.lock().expect(POISONED_LOCK_MSG).0
+        }
+
+        /// Sessions removed so far.
+        pub fn removed(&self) -> ReapStats {
+            self.totals.lock().expect(POISONED_LOCK_MSG).1
+        }
+    }
+
+    impl Drop for Reaper {
+        fn drop(&mut self) {
+            self.task.abort();
         }
     }
 }
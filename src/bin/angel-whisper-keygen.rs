@@ -0,0 +1,72 @@
+//! Generate long-term key pair for a server or a client.
+//!
+//! ```text
+//! angel-whisper-keygen [--base64] <path>    write <path> and <path>.pub
+//! angel-whisper-keygen --public <path>      print public key of <path>
+//! ```
+//!
+//! Public key is printed in both cases, ready to be pasted into client
+//! configuration or authenticator allow-list.
+extern crate angel_whisper;
+
+use angel_whisper::crypto::gen_keypair;
+use angel_whisper::keyfile::{Encoding, load_keypair, public_key_to_string, save_keypair,
+                             save_public_key};
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage:
+    angel-whisper-keygen [--base64] <path>    generate key pair, write <path> and <path>.pub
+    angel-whisper-keygen --public <path>      print public key from secret key file";
+
+enum Command {
+    Generate(Encoding, String),
+    Public(String),
+}
+
+fn parse_args(args: &[String]) -> Option<Command> {
+    match args {
+        [path] if !path.starts_with('-') => Some(Command::Generate(Encoding::Hex, path.clone())),
+        [flag, path] if flag == "--base64" => {
+            Some(Command::Generate(Encoding::Base64, path.clone()))
+        }
+        [flag, path] if flag == "--public" => Some(Command::Public(path.clone())),
+        _ => None,
+    }
+}
+
+fn run(command: Command) -> Result<String, String> {
+    match command {
+        Command::Generate(encoding, path) => {
+            let pair = gen_keypair();
+            let public_path = format!("{}.pub", path);
+            save_keypair(&path, &pair, encoding)
+                .map_err(|e| format!("Can't write {}: {}", path, e))?;
+            save_public_key(&public_path, &pair.0, encoding)
+                .map_err(|e| format!("Can't write {}: {}", public_path, e))?;
+            Ok(public_key_to_string(&pair.0, encoding))
+        }
+        Command::Public(path) => {
+            let pair = load_keypair(&path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+            Ok(public_key_to_string(&pair.0, Encoding::default()))
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Some(command) => command,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match run(command) {
+        Ok(public_key) => println!("{}", public_key),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
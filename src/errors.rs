@@ -1,6 +1,7 @@
 #![allow(missing_docs)]
 
-use llsd::errors::LlsdError;
+use crate::llsd::errors::LlsdError;
+use crate::llsd::frames::{AppError, ErrorCode};
 use std::io;
 
 pub type AWResult<T> = Result<T, AWError>;
@@ -28,5 +29,94 @@ quick_error! {
         }
         InvalidRoute {}
         SessionNotFound {}
+        TooManySessions {
+            description("Server reached its limit of sessions.")
+        }
+        NotConnected {
+            description("Session has no connection that can take pushed messages.")
+        }
+        PermissionDenied {
+            description("Client is not allowed to use this route.")
+        }
+        PayloadTooLarge {
+            description("Request payload is bigger than server accepts.")
+        }
+        RouteCollision(name: String, existing: String) {
+            description("Route is already taken.")
+            display("Route {:?} is already taken by {:?}", name, existing)
+        }
+        Application(err: AppError) {
+            from()
+            description("Application error to be sent back to the client as is.")
+            display("Application error ({})", err)
+        }
+    }
+}
+
+impl AWError {
+    /// Error code sent to the client in Termination frame or reply.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            AWError::LlsdError(ref err) => err.code(),
+            AWError::NotImplemented => ErrorCode::NotImplemented,
+            AWError::InvalidRoute => ErrorCode::InvalidRoute,
+            AWError::SessionNotFound => ErrorCode::UnknownSession,
+            AWError::TooManySessions => ErrorCode::TooManySessions,
+            AWError::PermissionDenied => ErrorCode::PermissionDenied,
+            AWError::PayloadTooLarge => ErrorCode::PayloadTooLarge,
+            AWError::Application(ref err) => err.code,
+            AWError::Io(_) |
+            AWError::ServerFault |
+            AWError::RouteCollision(..) |
+            AWError::NotConnected => ErrorCode::ServerFault,
+        }
+    }
+
+    /// Error client gets in reply to a request that failed.
+    pub fn to_app_error(&self) -> AppError {
+        match *self {
+            AWError::Application(ref err) => err.clone(),
+            // Internals of server faults are none of client's business.
+            _ if self.code() == ErrorCode::ServerFault => {
+                AppError::new(ErrorCode::ServerFault, "Server fault")
+            }
+            _ => AppError::new(self.code(), self.to_string()),
+        }
+    }
+}
+
+pub type KeyFileResult<T> = Result<T, KeyFileError>;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum KeyFileError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("Underlying I/O error.")
+            display("I/O error: {}", err)
+        }
+        Malformed {
+            description("Key file doesn't look like a key file.")
+        }
+        UnknownEncoding(encoding: String) {
+            description("Key is in encoding that is not supported.")
+            display("Unknown key encoding: {}", encoding)
+        }
+        WrongLength(expected: usize, got: usize) {
+            description("Key has wrong length.")
+            display("Key has to be {} bytes long, got {}", expected, got)
+        }
+        InsecurePermissions(mode: u32) {
+            description("Secret key file can be accessed by someone other than its owner.")
+            display("Secret key file has insecure permissions {:o}", mode)
+        }
+        KeyMismatch {
+            description("Public and secret keys in the file don't belong together.")
+        }
+        InvalidEntry(line: usize) {
+            description("Allow-list has an entry that can't be parsed.")
+            display("Invalid allow-list entry on line {}", line)
+        }
     }
 }
@@ -0,0 +1,244 @@
+//! Long-term keys on disk.
+//!
+//! Every key is a single line of text: encoding, colon and encoded key bytes,
+//! e.g. `hex:5f0e...` or `base64:Xw6...`. Empty lines and lines starting with
+//! `#` are ignored.
+//!
+//! Public key file has exactly one key in it. That line is what goes into
+//! client configuration and authenticator allow-lists.
+//!
+//! Secret key file has two keys: public key first, secret key second. It's
+//! created readable by owner only and refused on load if group or others can
+//! access it.
+
+use crate::errors::{KeyFileError, KeyFileResult};
+use crate::llsd::session::KeyPair;
+use sodiumoxide::crypto::box_::{PUBLICKEYBYTES, PublicKey, SECRETKEYBYTES, SecretKey,
+                                gen_keypair, gen_nonce, open, seal};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// How key bytes are turned into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Lowercase hex.
+    Hex,
+    /// Standard base64 with padding.
+    Base64,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Hex
+    }
+}
+
+/// Turn key bytes into a line of text.
+pub fn encode_key(bytes: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Hex => format!("hex:{}", hex::encode(bytes)),
+        Encoding::Base64 => format!("base64:{}", base64::encode(bytes)),
+    }
+}
+
+/// Get key bytes out of a line of text. Either encoding is accepted.
+pub fn decode_key(line: &str) -> KeyFileResult<Vec<u8>> {
+    let line = line.trim();
+    let colon = line.find(':').ok_or(KeyFileError::Malformed)?;
+    let (encoding, encoded) = (&line[..colon], &line[colon + 1..]);
+    let decoded = match encoding {
+        "hex" => hex::decode(encoded).ok(),
+        "base64" => base64::decode(encoded).ok(),
+        other => return Err(KeyFileError::UnknownEncoding(other.to_owned())),
+    };
+    decoded.ok_or(KeyFileError::Malformed)
+}
+
+/// Public key the way it's written to public key file.
+pub fn public_key_to_string(pk: &PublicKey, encoding: Encoding) -> String {
+    encode_key(&pk.0, encoding)
+}
+
+/// Parse public key from a line of text.
+pub fn parse_public_key(line: &str) -> KeyFileResult<PublicKey> {
+    let bytes = decode_key(line)?;
+    PublicKey::from_slice(&bytes).ok_or(KeyFileError::WrongLength(PUBLICKEYBYTES, bytes.len()))
+}
+
+fn parse_secret_key(line: &str) -> KeyFileResult<SecretKey> {
+    let bytes = decode_key(line)?;
+    SecretKey::from_slice(&bytes).ok_or(KeyFileError::WrongLength(SECRETKEYBYTES, bytes.len()))
+}
+
+// Lines that have keys in them.
+fn key_lines(content: &str) -> Vec<&str> {
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+fn read_to_string(file: &mut File) -> KeyFileResult<String> {
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Write public key file. Existing file is replaced.
+pub fn save_public_key<P: AsRef<Path>>(path: P,
+                                       pk: &PublicKey,
+                                       encoding: Encoding)
+                                       -> KeyFileResult<()> {
+    let content = format!("# angel-whisper public key\n{}\n", public_key_to_string(pk, encoding));
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Read public key file.
+pub fn load_public_key<P: AsRef<Path>>(path: P) -> KeyFileResult<PublicKey> {
+    let content = read_to_string(&mut File::open(path)?)?;
+    match key_lines(&content)[..] {
+        [line] => parse_public_key(line),
+        _ => Err(KeyFileError::Malformed),
+    }
+}
+
+/// Write secret key file. Refuses to replace existing file, so a key that is
+/// in use can't be lost by accident.
+pub fn save_keypair<P: AsRef<Path>>(path: P,
+                                    pair: &KeyPair,
+                                    encoding: Encoding)
+                                    -> KeyFileResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    let content = format!("# angel-whisper secret key. Keep it private.\n{}\n{}\n",
+                          encode_key(&(pair.0).0, encoding),
+                          encode_key(&(pair.1).0, encoding));
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Read secret key file. File must be private and keys in it must belong to
+/// each other.
+pub fn load_keypair<P: AsRef<Path>>(path: P) -> KeyFileResult<KeyPair> {
+    let mut file = File::open(path)?;
+    check_permissions(&file)?;
+    let content = read_to_string(&mut file)?;
+    let pair = match key_lines(&content)[..] {
+        [public, secret] => (parse_public_key(public)?, parse_secret_key(secret)?),
+        _ => return Err(KeyFileError::Malformed),
+    };
+    if !is_pair(&pair) {
+        return Err(KeyFileError::KeyMismatch);
+    }
+    Ok(pair)
+}
+
+#[cfg(unix)]
+fn check_permissions(file: &File) -> KeyFileResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = file.metadata()?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeyFileError::InsecurePermissions(mode));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_: &File) -> KeyFileResult<()> {
+    Ok(())
+}
+
+// Box sealed to the public key can only be opened with matching secret key.
+fn is_pair(pair: &KeyPair) -> bool {
+    let (other_pk, other_sk) = gen_keypair();
+    let nonce = gen_nonce();
+    let sealed = seal(b"", &nonce, &pair.0, &other_sk);
+    open(&sealed, &nonce, &other_pk, &pair.1).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("angel-whisper-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let (pk, _) = gen_keypair();
+        for encoding in &[Encoding::Hex, Encoding::Base64] {
+            let line = public_key_to_string(&pk, *encoding);
+            assert_eq!(parse_public_key(&line).unwrap(), pk);
+        }
+        assert_eq!(decode_key("hex:00ff").unwrap(), vec![0, 255]);
+        match parse_public_key("hex:00ff") {
+            Err(KeyFileError::WrongLength(32, 2)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match decode_key("rot13:abc") {
+            Err(KeyFileError::UnknownEncoding(ref encoding)) if encoding == "rot13" => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        assert!(decode_key("hex:zz").is_err());
+        assert!(decode_key("00ff").is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let pair = gen_keypair();
+        let secret = temp_path("save_and_load");
+        let public = temp_path("save_and_load.pub");
+
+        save_keypair(&secret, &pair, Encoding::Base64).unwrap();
+        save_public_key(&public, &pair.0, Encoding::Hex).unwrap();
+        assert_eq!(load_keypair(&secret).unwrap(), pair);
+        assert_eq!(load_public_key(&public).unwrap(), pair.0);
+
+        // Key that is there stays there.
+        assert!(save_keypair(&secret, &gen_keypair(), Encoding::Hex).is_err());
+        assert_eq!(load_keypair(&secret).unwrap(), pair);
+
+        let _ = fs::remove_file(&secret);
+        let _ = fs::remove_file(&public);
+    }
+
+    #[test]
+    fn mismatched_keys() {
+        let path = temp_path("mismatched_keys");
+        let pair = (gen_keypair().0, gen_keypair().1);
+        save_keypair(&path, &pair, Encoding::Hex).unwrap();
+        match load_keypair(&path) {
+            Err(KeyFileError::KeyMismatch) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_file_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path("secret_file_must_be_private");
+        save_keypair(&path, &gen_keypair(), Encoding::Hex).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        match load_keypair(&path) {
+            Err(KeyFileError::InsecurePermissions(0o644)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
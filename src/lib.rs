@@ -7,8 +7,9 @@ extern crate uuid;
 extern crate chrono;
 extern crate byteorder;
 extern crate bytes;
-extern crate typemap;
 extern crate murmurhash64;
+extern crate hex;
+extern crate base64;
 #[macro_use]
 extern crate nom;
 #[macro_use]
@@ -24,11 +25,21 @@ extern crate tokio_proto;
 extern crate tokio_service;
 #[cfg(feature = "system-on-tokio")]
 extern crate tokio_core;
+#[cfg(feature = "async-runtime")]
+extern crate tokio1;
 #[cfg(feature = "protobuf")]
 extern crate prost;
 #[cfg(feature = "protobuf")]
 #[macro_use]
 extern crate prost_derive;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "serde")]
+extern crate rmp_serde;
+#[cfg(feature = "serde")]
+extern crate bincode;
 
 #[cfg(test)]
 extern crate mockers;
@@ -43,11 +54,12 @@ extern crate mockers;
 /// sibling modules.
 pub mod llsd;
 pub use llsd::frames;
-pub use llsd::session::Sendable;
+pub use llsd::session::{Sendable, SessionConfig};
 pub use llsd::session::client::Session as ClientSession;
 pub use llsd::session::server::Session as ServerSession;
 pub mod errors;
 pub mod system;
+pub mod keyfile;
 
 pub mod angel_system;
 pub use angel_system::AngelSystem;
@@ -55,7 +67,9 @@ pub use angel_system::AngelSystem;
 
 /// Reexport libsodium things.
 pub mod crypto {
-    pub use sodiumoxide::crypto::box_::{PublicKey, SecretKey, gen_keypair};
+    pub use sodiumoxide::crypto::box_::{PUBLICKEYBYTES, PrecomputedKey, PublicKey, SecretKey,
+                                        gen_keypair, gen_nonce, open, open_precomputed,
+                                        precompute, seal, seal_precomputed};
 }
 
 /// Reexport tokio things for building a client.
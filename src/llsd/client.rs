@@ -4,13 +4,13 @@ use bytes::BufMut;
 use futures::Poll;
 use futures::future;
 use futures::future::Future;
-use llsd::errors::LlsdError;
-use llsd::frames::Frame;
-use llsd::route::Route;
-use llsd::session::KeyPair;
-use llsd::session::Sendable;
-use llsd::session::client::Session;
-use sodiumoxide::crypto::box_::PublicKey;
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::frames::{ErrorCode, Frame, FrameKind};
+use crate::llsd::route::Route;
+use crate::llsd::session::KeyPair;
+use crate::llsd::session::{Sendable, SessionConfig};
+use crate::llsd::session::client::Session;
+use crate::llsd::session::keyring::ServerKeys;
 use std::cell::RefCell;
 use std::io;
 use std::rc::Rc;
@@ -18,32 +18,47 @@ use std::rc::Rc;
 /// See Into<T> from std lib. Created own trait so I can implement it for types
 /// that I don't own...like Message from prost trait.
 pub trait IntoBytes {
-    /// Consume self and return Bytes.
-    fn into_bytes(self) -> Bytes;
+    /// Consume self and return Bytes or `LlsdError::InvalidPayload` if self
+    /// can't be encoded.
+    fn into_bytes(self) -> LlsdResult<Bytes>;
 }
 
 impl IntoBytes for Bytes {
-    fn into_bytes(self) -> Bytes {
-        self
+    fn into_bytes(self) -> LlsdResult<Bytes> {
+        Ok(self)
     }
 }
 
-/// See From<T> from std lib. Created own trait so I can implement it for
+/// See TryFrom<T> from std lib. Created own trait so I can implement it for
 /// types that I don't own...like Message from prost trait.
-pub trait FromBytes {
-    /// consume BytesMut and return Self.
-    fn from(bytes: BytesMut) -> Self;
+pub trait FromBytes: Sized {
+    /// consume BytesMut and return Self or `LlsdError::InvalidPayload` if
+    /// payload can't be decoded.
+    fn from(bytes: BytesMut) -> LlsdResult<Self>;
 }
 
 impl FromBytes for BytesMut {
-    fn from(bytes: BytesMut) -> BytesMut {
-        bytes
+    fn from(bytes: BytesMut) -> LlsdResult<BytesMut> {
+        Ok(bytes)
     }
 }
 
 impl FromBytes for Bytes {
-    fn from(bytes: BytesMut) -> Bytes {
-        bytes.freeze()
+    fn from(bytes: BytesMut) -> LlsdResult<Bytes> {
+        Ok(bytes.freeze())
+    }
+}
+
+// Prefix payload with route if there is one.
+fn routed_payload(route: Option<Route>, bytes: Bytes) -> Bytes {
+    match route {
+        None => bytes,
+        Some(r) => {
+            let mut ret = BytesMut::with_capacity(8 + bytes.len());
+            ret.put_u64::<BigEndian>(r.as_u64());
+            ret.extend(bytes);
+            ret.freeze()
+        }
     }
 }
 
@@ -84,43 +99,29 @@ where
                                                                  route: Option<Route>,
                                                                  req: Req)
                                                                  -> RequestResult<Res> {
-        if self.connection_state() == ConnectionState::Ready {
-            let payload = {
-                match route {
-                    None => req.into_bytes(),
-                    Some(r) => {
-                        let bytes = req.into_bytes();
-                        let mut ret = BytesMut::with_capacity(8 + bytes.len());
-                        ret.put_u64::<BigEndian>(r.as_u64());
-                        ret.extend(bytes);
-                        ret.freeze()
-                    }
-                }
-            };
-            let session = self.session().clone();
-            let frame = session
-                .borrow_mut()
-                .make_message(&payload)
-                .expect("Failed to create Message Frame");
-            let f = self.call_raw(frame)
-                .then(move |resp| match resp {
-                          Err(e) => future::err(LlsdError::from(e)),
-                          Ok(frame) => {
-                              let session = session.clone();
-                              let payload = session
-                                  .borrow_mut()
-                                  .read_msg(&frame)
-                                  .expect("Failed to read the message");
-                              future::ok(Res::from(payload))
-                          }
-                      });
-
-            RequestResult(Box::new(f))
+        let payload = match req.into_bytes() {
+            Ok(bytes) => routed_payload(route, bytes),
+            Err(e) => return RequestResult(Box::new(future::err(e))),
+        };
+        let session = self.session();
+        let call = self.call_handle();
+
+        // Don't bother sending with session that is about to expire.
+        let attempt = if session.borrow().needs_renewal() {
+            renew_and_send(call.clone(), session.clone(), payload.clone())
         } else {
-            let err = LlsdError::InvalidSessionState;
-            let f = future::err(err);
-            RequestResult(Box::new(f))
-        }
+            send(call.clone(), session.clone(), payload.clone())
+        };
+        // Server might have dropped session on its own. Try once more with a
+        // new one.
+        let f = attempt
+            .or_else(move |e| match e {
+                         LlsdError::SessionRejected => renew_and_send(call, session, payload),
+                         e => Box::new(future::err(e)) as FutureMessage,
+                     })
+            .and_then(|payload| Res::from(payload));
+
+        RequestResult(Box::new(f))
     }
 
     fn call(&self, req: Frame) -> FutureResponse {
@@ -134,9 +135,116 @@ where
     }
 
     fn generate_session(&self) -> Session {
-        Session::new(self.server_public_key(), self.our_long_term_keys())
+        Session::with_config(self.server_keys(), self.our_long_term_keys(), self.session_config())
+    }
+}
+type FutureMessage = Box<dyn Future<Item = BytesMut, Error = LlsdError> + 'static>;
+
+// Send message with current session. Server replies with Termination frame
+// if it couldn't process the message and with error reply if handler failed.
+fn send(call: CallHandle, session: Rc<RefCell<Session>>, payload: Bytes) -> FutureMessage {
+    let frame = match session.borrow().make_message(&payload) {
+        Ok(frame) => frame,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let f = call.call(frame)
+        .map_err(LlsdError::from)
+        .and_then(move |frame| {
+                      let session = session.borrow();
+                      if frame.kind == FrameKind::Termination {
+                          return Err(rejection(&session, &frame));
+                      }
+                      session.read_reply(&frame)
+                  });
+    Box::new(f)
+}
+
+// Replace session with a new one, do a handshake and then send message.
+fn renew_and_send(call: CallHandle,
+                  session: Rc<RefCell<Session>>,
+                  payload: Bytes)
+                  -> FutureMessage {
+    let fresh = session.borrow().renewed();
+    *session.borrow_mut() = fresh;
+    let f = exchange(call.clone(), session.clone()).and_then(move |_| send(call, session, payload));
+    Box::new(f)
+}
+
+// Error server replied with instead of the frame client asked for.
+fn rejection(session: &Session, frame: &Frame) -> LlsdError {
+    match session.read_termination(frame) {
+        Ok(termination) => termination.into_error(),
+        Err(e) => e,
     }
 }
+
+// Server couldn't open Hello: it doesn't have the key session trusts (yet or
+// anymore). Next trusted key is worth a try.
+fn fallback(session: &Session, err: &LlsdError) -> Option<Session> {
+    match *err {
+        LlsdError::Terminated(ErrorCode::HandshakeFailed, _) => session.fallback(),
+        _ => None,
+    }
+}
+
+type FutureExchange = Box<dyn Future<Item = (), Error = LlsdError>>;
+
+// Same as `handshake`, but keeps errors typed.
+fn exchange(call: CallHandle, session: Rc<RefCell<Session>>) -> FutureExchange {
+    let hello = session.borrow().make_hello();
+    let initiate_call = call.clone();
+    let f = call.call(hello)
+        .map_err(LlsdError::from)
+        .and_then(move |welcome| -> FutureExchange {
+            if welcome.kind == FrameKind::Termination {
+                let err = rejection(&session.borrow(), &welcome);
+                let next = fallback(&session.borrow(), &err);
+                return match next {
+                           Some(next) => {
+                               *session.borrow_mut() = next;
+                               exchange(initiate_call, session)
+                           }
+                           None => Box::new(future::err(err)),
+                       };
+            }
+            let initiate = session.borrow_mut().make_initiate(&welcome);
+            let f = future::result(initiate)
+                .and_then(move |initiate| initiate_call.call(initiate).map_err(LlsdError::from))
+                .and_then(move |ready| if ready.kind == FrameKind::Termination {
+                              Err(rejection(&session.borrow(), &ready))
+                          } else {
+                              session.borrow_mut().read_ready(&ready)
+                          });
+            Box::new(f)
+        });
+    Box::new(f)
+}
+
+/// Run Hello/Welcome/Initiate/Ready exchange for the session. Session is
+/// ready to send messages once returned future resolves.
+pub fn handshake(call: CallHandle, session: Rc<RefCell<Session>>) -> FutureHandshake {
+    let f = exchange(call, session).map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    FutureHandshake(Box::new(f))
+}
+
+/// Handle that sends frames over engine's connection. Unlike engine itself it
+/// can be moved into futures, so follow-up calls (like handshake during
+/// session renewal) can be made from them.
+#[derive(Clone)]
+pub struct CallHandle(Rc<dyn Fn(Frame) -> FutureResponse>);
+
+impl CallHandle {
+    /// Wrap anything that can make a call.
+    pub fn new<F: Fn(Frame) -> FutureResponse + 'static>(f: F) -> CallHandle {
+        CallHandle(Rc::new(f))
+    }
+
+    /// Make a call. Doesn't take care of handshake and session.
+    pub fn call(&self, req: Frame) -> FutureResponse {
+        (self.0)(req)
+    }
+}
+
 /// Engine is the core of client. See module level documentation.
 #[derive(Mock)]
 pub trait Engine {
@@ -145,25 +253,32 @@ pub trait Engine {
     /// Return reference to session.
     fn session(&mut self) -> Rc<RefCell<Session>>;
 
-    /// Server long term public key.
-    fn server_public_key(&self) -> PublicKey;
+    /// Server long term public keys client trusts.
+    fn server_keys(&self) -> ServerKeys;
 
     /// Return key pair representing out long term keys.
     fn our_long_term_keys(&self) -> KeyPair;
 
+    /// Lifetimes of sessions engine makes.
+    fn session_config(&self) -> SessionConfig;
+
     /// Helper method to authenticate client with the server.
     fn authenticate(&mut self) -> FutureHandshake;
 
     /// Make a call. This call doesn't take care of handshake and session;
     /// regeneration.
     fn call_raw(&self, req: Frame) -> FutureResponse;
+
+    /// Same as `call_raw`, but can be moved into futures.
+    fn call_handle(&self) -> CallHandle;
 }
 /// Future that return by Engine#request method.
-pub struct RequestResult<Res: FromBytes + Sized>(Box<Future<Item = Res, Error = LlsdError> + 'static>);
+pub struct RequestResult<Res: FromBytes + Sized>(Box<dyn Future<Item = Res, Error = LlsdError>
+                                                          + 'static>);
 /// Future reprensenting the result of RPC call.
-pub struct FutureResponse(Box<Future<Item = Frame, Error = io::Error> + 'static>);
+pub struct FutureResponse(Box<dyn Future<Item = Frame, Error = io::Error> + 'static>);
 /// Future representing the result of handshake.
-pub struct FutureHandshake(Box<Future<Item = (), Error = io::Error> + 'static>);
+pub struct FutureHandshake(Box<dyn Future<Item = (), Error = io::Error> + 'static>);
 
 impl Future for FutureResponse {
     type Item = Frame;
@@ -192,14 +307,14 @@ impl<Res: FromBytes + Sized> Future for RequestResult<Res> {
 /// Tokio backed implementation of client.
 #[cfg(feature = "system-on-tokio")]
 pub mod tokio {
-    use super::{ConnectionState, Engine, EngineSugar, FutureHandshake, FutureResponse};
-    use futures;
+    use super::{CallHandle, ConnectionState, Engine, EngineSugar, FutureHandshake,
+                FutureResponse, handshake};
     use futures::Future;
-    use llsd::frames::Frame;
-    use llsd::session::{KeyPair, Sendable};
-    use llsd::session::client::Session;
-    use llsd::tokio::WhisperPipelinedProtocol;
-    use sodiumoxide::crypto::box_::PublicKey;
+    use crate::llsd::frames::Frame;
+    use crate::llsd::session::{KeyPair, Sendable, SessionConfig, SessionState};
+    use crate::llsd::session::client::Session;
+    use crate::llsd::session::keyring::ServerKeys;
+    use crate::llsd::tokio::{WhisperMultiplexedProtocol, WhisperPipelinedProtocol};
     use std::cell::RefCell;
     use std::io;
     use std::net::SocketAddr;
@@ -207,10 +322,20 @@ pub mod tokio {
     use tokio_core::net::TcpStream;
     use tokio_core::reactor::Handle;
     use tokio_proto::TcpClient;
+    use tokio_proto::multiplex;
     use tokio_proto::pipeline::ClientService;
     use tokio_service::Service;
 
-
+    // Handshake always starts with a fresh session. Shared by pipelined and
+    // multiplexed engines.
+    fn fresh_session(engine: &mut dyn Engine) -> Rc<RefCell<Session>> {
+        let session = engine.session();
+        if *session.borrow().state() != SessionState::Fresh {
+            let fresh = session.borrow().renewed();
+            *session.borrow_mut() = fresh;
+        }
+        session
+    }
 
     /// Pipeline TCP client on top of tokio.
     pub struct TcpPipelineEngine {
@@ -218,17 +343,35 @@ pub mod tokio {
         inner: Rc<RefCell<ClientService<TcpStream, WhisperPipelinedProtocol>>>,
         long_term_keys: KeyPair,
         session: Option<Rc<RefCell<Session>>>,
-        server_public_key: PublicKey,
+        server_keys: ServerKeys,
+        session_config: SessionConfig,
     }
 
 
     impl TcpPipelineEngine {
-        /// Create backend for the client powered by existing reactor.
-        pub fn connect(addr: &SocketAddr,
-                       handle: Handle,
-                       long_term_keys: KeyPair,
-                       server_key: PublicKey)
-                       -> Box<Future<Item = Self, Error = io::Error>> {
+        /// Create backend for the client powered by existing reactor. Takes
+        /// either a single server key or a set of `ServerKeys`.
+        pub fn connect<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                            handle: Handle,
+                                            long_term_keys: KeyPair,
+                                            server_keys: K)
+                                            -> Box<dyn Future<Item = Self, Error = io::Error>> {
+            TcpPipelineEngine::connect_with_config(addr,
+                                                   handle,
+                                                   long_term_keys,
+                                                   server_keys,
+                                                   SessionConfig::default())
+        }
+
+        /// Same as `connect`, but sessions are made with `session_config`.
+        pub fn connect_with_config<K: Into<ServerKeys>>
+            (addr: &SocketAddr,
+             handle: Handle,
+             long_term_keys: KeyPair,
+             server_keys: K,
+             session_config: SessionConfig)
+             -> Box<dyn Future<Item = Self, Error = io::Error>> {
+            let server_keys = server_keys.into();
             let ret = TcpClient::new(WhisperPipelinedProtocol)
                 .connect(addr, &handle)
                 .map(move |connection| {
@@ -236,8 +379,9 @@ pub mod tokio {
                         _handle: handle.clone(),
                         inner: Rc::new(RefCell::new(connection)),
                         long_term_keys: long_term_keys,
-                        server_public_key: server_key,
+                        server_keys: server_keys,
                         session: None,
+                        session_config: session_config,
                     }
                 });
             Box::new(ret)
@@ -268,49 +412,21 @@ pub mod tokio {
             }
         }
 
-        fn server_public_key(&self) -> PublicKey {
-            self.server_public_key
+        fn server_keys(&self) -> ServerKeys {
+            self.server_keys.clone()
         }
 
         fn our_long_term_keys(&self) -> KeyPair {
             self.long_term_keys.clone()
         }
-        fn authenticate(&mut self) -> FutureHandshake {
-            let service = self.inner.clone();
-            let session = self.session();
-
-            let hello_frame = session.borrow().make_hello();
-            let hello_request = service.borrow().call(hello_frame);
-
-            let initaite_request = hello_request.and_then(move |hello_response| {
-                let initiate_future = {
-                    let session_initiate = session.clone();
-                    let service_marker = service.clone();
-                    let service_initiate = service_marker.borrow();
-                    let initiate = session_initiate
-                        .borrow_mut()
-                        .make_initiate(&hello_response)
-                        .unwrap();
-                    service_initiate.call(initiate)
-                };
-                initiate_future.map(move |frame| (session, service, frame))
-            });
-
-            let handshake = initaite_request
-                .then(move |result| if let Ok((session, _service, initiate_respose)) = result {
-                          let ready = session.borrow_mut().read_ready(&initiate_respose);
-                          if ready.is_ok() {
-                              futures::future::ok(())
-                          } else {
-                              futures::future::err(io::Error::new(io::ErrorKind::Other,
-                                                                  "handshake failed"))
-                          }
-                      } else {
-                          futures::future::err(io::Error::new(io::ErrorKind::Other,
-                                                              "handshake failed"))
-                      });
 
-            FutureHandshake(Box::new(handshake))
+        fn session_config(&self) -> SessionConfig {
+            self.session_config
+        }
+
+        fn authenticate(&mut self) -> FutureHandshake {
+            let session = fresh_session(self);
+            handshake(self.call_handle(), session)
         }
 
         fn call_raw(&self, req: Frame) -> FutureResponse {
@@ -319,12 +435,113 @@ pub mod tokio {
 
             FutureResponse(Box::new(f))
         }
+
+        fn call_handle(&self) -> CallHandle {
+            let service = self.inner.clone();
+            CallHandle::new(move |req| FutureResponse(Box::new(service.borrow().call(req))))
+        }
+    }
+    /// Multiplexed TCP client on top of tokio. Every request is tagged with id,
+    /// so server is free to reply in any order and slow request doesn't block
+    /// the rest of the connection.
+    pub struct TcpMultiplexEngine {
+        _handle: Handle,
+        inner: Rc<RefCell<multiplex::ClientService<TcpStream, WhisperMultiplexedProtocol>>>,
+        long_term_keys: KeyPair,
+        session: Option<Rc<RefCell<Session>>>,
+        server_keys: ServerKeys,
+        session_config: SessionConfig,
+    }
+
+    impl TcpMultiplexEngine {
+        /// Create backend for the client powered by existing reactor. Takes
+        /// either a single server key or a set of `ServerKeys`.
+        pub fn connect<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                            handle: Handle,
+                                            long_term_keys: KeyPair,
+                                            server_keys: K)
+                                            -> Box<dyn Future<Item = Self, Error = io::Error>> {
+            TcpMultiplexEngine::connect_with_config(addr,
+                                                    handle,
+                                                    long_term_keys,
+                                                    server_keys,
+                                                    SessionConfig::default())
+        }
+
+        /// Same as `connect`, but sessions are made with `session_config`.
+        pub fn connect_with_config<K: Into<ServerKeys>>
+            (addr: &SocketAddr,
+             handle: Handle,
+             long_term_keys: KeyPair,
+             server_keys: K,
+             session_config: SessionConfig)
+             -> Box<dyn Future<Item = Self, Error = io::Error>> {
+            let server_keys = server_keys.into();
+            let ret = TcpClient::new(WhisperMultiplexedProtocol)
+                .connect(addr, &handle)
+                .map(move |connection| {
+                    TcpMultiplexEngine {
+                        _handle: handle.clone(),
+                        inner: Rc::new(RefCell::new(connection)),
+                        long_term_keys: long_term_keys,
+                        server_keys: server_keys,
+                        session: None,
+                        session_config: session_config,
+                    }
+                });
+            Box::new(ret)
+        }
+    }
+
+    impl Engine for TcpMultiplexEngine {
+        fn connection_state(&self) -> ConnectionState {
+            match self.session {
+                Some(ref session) if session.borrow().can_send() => ConnectionState::Ready,
+                _ => ConnectionState::NotReady,
+            }
+        }
+        fn session(&mut self) -> Rc<RefCell<Session>> {
+            if let Some(ref session) = self.session {
+                return session.clone();
+            }
+            let cell = Rc::new(RefCell::new(self.generate_session()));
+            self.session = Some(cell.clone());
+            cell
+        }
+
+        fn server_keys(&self) -> ServerKeys {
+            self.server_keys.clone()
+        }
+
+        fn our_long_term_keys(&self) -> KeyPair {
+            self.long_term_keys.clone()
+        }
+
+        fn session_config(&self) -> SessionConfig {
+            self.session_config
+        }
+
+        fn authenticate(&mut self) -> FutureHandshake {
+            let session = fresh_session(self);
+            handshake(self.call_handle(), session)
+        }
+
+        fn call_raw(&self, req: Frame) -> FutureResponse {
+            let f = self.inner.borrow().call(req);
+            FutureResponse(Box::new(f))
+        }
+
+        fn call_handle(&self) -> CallHandle {
+            let service = self.inner.clone();
+            CallHandle::new(move |req| FutureResponse(Box::new(service.borrow().call(req))))
+        }
     }
+
     #[cfg(test)]
     mod test {
         use super::*;
 
-        use crypto::gen_keypair;
+        use crate::crypto::gen_keypair;
         use tokio_core::reactor::Core;
 
         #[test]
@@ -336,117 +553,692 @@ pub mod tokio {
 
                 let addr = "0.0.0.0:12345".parse().unwrap();
 
-                let _client = TcpPipelineEngine::connect(&addr, core.handle(), pair, key);
+                let _client = TcpPipelineEngine::connect(&addr, core.handle(), pair.clone(), key);
+                let _client = TcpMultiplexEngine::connect(&addr, core.handle(), pair.clone(), key);
+                let config = SessionConfig::default();
+                let _client = TcpPipelineEngine::connect_with_config(&addr,
+                                                                     core.handle(),
+                                                                     pair.clone(),
+                                                                     key,
+                                                                     config);
+                let _client = TcpMultiplexEngine::connect_with_config(&addr,
+                                                                      core.handle(),
+                                                                      pair,
+                                                                      key,
+                                                                      config);
             }
         }
     }
 }
 
 
+/// Client on top of std futures and tokio 1.x.
+#[cfg(feature = "async-runtime")]
+pub mod runtime {
+    use super::{ConnectionState, FromBytes, IntoBytes, fallback, rejection, routed_payload};
+    use crate::llsd::POISONED_LOCK_MSG;
+    use crate::llsd::errors::{LlsdError, LlsdResult};
+    use crate::llsd::frames::{ErrorCode, Frame, FrameKind};
+    use crate::llsd::route::Route;
+    use bytes::BytesMut;
+    use crate::llsd::runtime::{PUSH_REQUEST_ID, RequestId, read_frame, read_tagged_frame,
+                               write_frame, write_tagged_frame};
+    use crate::llsd::session::{KeyPair, Sendable, SessionConfig};
+    use crate::llsd::session::client::Session;
+    use crate::llsd::session::keyring::ServerKeys;
+    use sodiumoxide::crypto::box_::PublicKey;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use tokio1::net::TcpStream;
+    use tokio1::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio1::sync::Mutex as AsyncMutex;
+    use tokio1::sync::{mpsc, oneshot};
+
+    type Pending = Arc<Mutex<HashMap<RequestId, oneshot::Sender<Frame>>>>;
+    type Subscribers = Arc<Mutex<Vec<mpsc::UnboundedSender<BytesMut>>>>;
+    type SharedSession = Arc<Mutex<Sessions>>;
+
+    // Session requests are sealed with and the one it replaced. Replies and
+    // pushes sealed with the old one can still be on the way after renewal.
+    #[derive(Default)]
+    struct Sessions {
+        current: Option<Arc<Session>>,
+        previous: Option<Arc<Session>>,
+    }
+
+    /// TCP client. Requests are sent one at a time, hence `&mut self`
+    /// everywhere.
+    ///
+    /// Calls are not cancel-safe: if a call is dropped after its request was
+    /// written, the reply is left in the socket and would be taken for the
+    /// reply to the next request. Engine refuses every call after that with
+    /// an error, so a new one has to be connected.
+    pub struct TcpEngine {
+        // Never waited for, requests go one at a time anyway. Lets handshake
+        // make calls through `&self`.
+        io: AsyncMutex<(OwnedReadHalf, OwnedWriteHalf)>,
+        // Set while a call is on the wire. Stays set if it was dropped.
+        poisoned: AtomicBool,
+        long_term_keys: KeyPair,
+        session: Option<Session>,
+        server_keys: ServerKeys,
+        session_config: SessionConfig,
+    }
+
+    impl TcpEngine {
+        /// Connect to the server. Session is not established until
+        /// `authenticate` is called. Takes either a single server key or a set
+        /// of `ServerKeys`.
+        pub async fn connect<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                                  long_term_keys: KeyPair,
+                                                  server_keys: K)
+                                                  -> io::Result<TcpEngine> {
+            TcpEngine::connect_with_config(addr,
+                                           long_term_keys,
+                                           server_keys,
+                                           SessionConfig::default())
+                .await
+        }
+
+        /// Same as `connect`, but sessions are made with `session_config`.
+        pub async fn connect_with_config<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                                              long_term_keys: KeyPair,
+                                                              server_keys: K,
+                                                              session_config: SessionConfig)
+                                                              -> io::Result<TcpEngine> {
+            let stream = TcpStream::connect(addr).await?;
+            let (reader, writer) = stream.into_split();
+            Ok(TcpEngine {
+                   io: AsyncMutex::new((reader, writer)),
+                   poisoned: AtomicBool::new(false),
+                   long_term_keys: long_term_keys,
+                   session: None,
+                   server_keys: server_keys.into(),
+                   session_config: session_config,
+               })
+        }
+
+        /// Get state of the current connection.
+        pub fn connection_state(&self) -> ConnectionState {
+            match self.session {
+                Some(ref session) if session.can_send() => ConnectionState::Ready,
+                _ => ConnectionState::NotReady,
+            }
+        }
+
+        /// Current session, if handshake was made.
+        pub fn session(&self) -> Option<&Session> {
+            self.session.as_ref()
+        }
+
+        /// Perform handshake with a brand new session. Previous session (if
+        /// any) is replaced only if handshake succeeded.
+        pub async fn authenticate(&mut self) -> LlsdResult<()> {
+            let session = match self.session {
+                Some(ref session) => session.renewed(),
+                None => {
+                    Session::with_config(self.server_keys.clone(),
+                                         self.long_term_keys.clone(),
+                                         self.session_config)
+                }
+            };
+            let engine = &*self;
+            let session = handshake(session, |req| engine.call(req)).await?;
+            self.session = Some(session);
+            Ok(())
+        }
+
+        /// Send a frame and wait for the reply. Doesn't take care of
+        /// handshake.
+        pub async fn call_raw(&mut self, req: Frame) -> io::Result<Frame> {
+            self.call(req).await
+        }
+
+        async fn call(&self, req: Frame) -> io::Result<Frame> {
+            let mut io = self.io.lock().await;
+            if self.poisoned.swap(true, Ordering::SeqCst) {
+                return Err(connection_poisoned());
+            }
+            let (ref mut reader, ref mut writer) = *io;
+            write_frame(writer, &req).await?;
+            let reply = match read_frame(reader).await? {
+                Some(frame) => frame,
+                None => return Err(connection_closed()),
+            };
+            self.poisoned.store(false, Ordering::SeqCst);
+            Ok(reply)
+        }
+
+        /// Same as `EngineSugar::request`.
+        /// Handshake is made (or redone, if session is about to expire)
+        /// before sending. If server rejects the session, request is retried
+        /// once with a new one.
+        pub async fn request<Req: IntoBytes, Res: FromBytes>(&mut self,
+                                                             route: Option<Route>,
+                                                             req: Req)
+                                                             -> LlsdResult<Res> {
+            let payload = routed_payload(route, req.into_bytes()?);
+            if self.session.as_ref().map_or(true, |session| session.needs_renewal()) {
+                self.authenticate().await?;
+            }
+            let msg = match self.send(&payload).await {
+                Err(LlsdError::SessionRejected) => {
+                    self.authenticate().await?;
+                    self.send(&payload).await?
+                }
+                res => res?,
+            };
+            Res::from(msg)
+        }
+
+        async fn send(&mut self, payload: &[u8]) -> LlsdResult<BytesMut> {
+            let frame = match self.session {
+                Some(ref session) => session.make_message(payload)?,
+                None => return Err(LlsdError::InvalidSessionState),
+            };
+            let resp = self.call_raw(frame).await?;
+            match self.session {
+                Some(ref session) if resp.kind == FrameKind::Termination => {
+                    Err(rejection(session, &resp))
+                }
+                Some(ref session) => session.read_reply(&resp),
+                None => Err(LlsdError::InvalidSessionState),
+            }
+        }
+    }
+
+    /// Multiplexed TCP client. Every request is tagged with id and responses are
+    /// matched by it, so any number of requests can be in flight at once and
+    /// all methods take `&self`. Must be created inside of tokio runtime,
+    /// because reading from the socket happens in a background task. Messages
+    /// pushed by the server are available through `subscribe`.
+    ///
+    /// Call dropped while waiting for the reply is forgotten and its reply is
+    /// thrown away. Call dropped halfway through writing the request leaves
+    /// broken frame on the wire, so engine refuses every call after that with
+    /// an error.
+    pub struct TcpMultiplexEngine {
+        writer: AsyncMutex<OwnedWriteHalf>,
+        pending: Pending,
+        subscribers: Subscribers,
+        closed: Arc<AtomicBool>,
+        // Set while a request is being written. Stays set if it was dropped.
+        poisoned: AtomicBool,
+        next_id: AtomicU64,
+        long_term_keys: KeyPair,
+        session: SharedSession,
+        // Held while session is being replaced.
+        renewing: AsyncMutex<()>,
+        server_keys: ServerKeys,
+        session_config: SessionConfig,
+    }
+
+    /// Stream of messages pushed by the server. Messages pushed before
+    /// subscription was made are not delivered to it.
+    pub struct Subscription {
+        rx: mpsc::UnboundedReceiver<BytesMut>,
+    }
+
+    impl Subscription {
+        /// Wait for next pushed message. Returns `None` once connection is
+        /// closed.
+        pub async fn next(&mut self) -> Option<BytesMut> {
+            self.rx.recv().await
+        }
+    }
+
+    impl TcpMultiplexEngine {
+        /// Connect to the server. Session is not established until
+        /// `authenticate` is called. Takes either a single server key or a set
+        /// of `ServerKeys`.
+        pub async fn connect<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                                  long_term_keys: KeyPair,
+                                                  server_keys: K)
+                                                  -> io::Result<TcpMultiplexEngine> {
+            TcpMultiplexEngine::connect_with_config(addr,
+                                                    long_term_keys,
+                                                    server_keys,
+                                                    SessionConfig::default())
+                .await
+        }
+
+        /// Same as `connect`, but sessions are made with `session_config`.
+        pub async fn connect_with_config<K: Into<ServerKeys>>(addr: &SocketAddr,
+                                                              long_term_keys: KeyPair,
+                                                              server_keys: K,
+                                                              session_config: SessionConfig)
+                                                              -> io::Result<TcpMultiplexEngine> {
+            let stream = TcpStream::connect(addr).await?;
+            let (reader, writer) = stream.into_split();
+            let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+            let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+            let closed = Arc::new(AtomicBool::new(false));
+            let session: SharedSession = Arc::new(Mutex::new(Sessions::default()));
+            tokio1::spawn(dispatch(reader,
+                                   pending.clone(),
+                                   subscribers.clone(),
+                                   session.clone(),
+                                   closed.clone()));
+            Ok(TcpMultiplexEngine {
+                   writer: AsyncMutex::new(writer),
+                   pending: pending,
+                   subscribers: subscribers,
+                   closed: closed,
+                   poisoned: AtomicBool::new(false),
+                   next_id: AtomicU64::new(0),
+                   long_term_keys: long_term_keys,
+                   session: session,
+                   renewing: AsyncMutex::new(()),
+                   server_keys: server_keys.into(),
+                   session_config: session_config,
+               })
+        }
+
+        /// Subscribe to messages pushed by the server. Every subscription gets
+        /// its own copy of each message.
+        pub fn subscribe(&self) -> Subscription {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.subscribers
+                .lock()
+                .expect(POISONED_LOCK_MSG)
+                .push(tx);
+            Subscription { rx: rx }
+        }
+
+        /// Get state of the current connection.
+        pub fn connection_state(&self) -> ConnectionState {
+            match self.current_session() {
+                Some(ref session) if session.can_send() => ConnectionState::Ready,
+                _ => ConnectionState::NotReady,
+            }
+        }
+
+        /// Perform handshake with a brand new session. Previous session (if
+        /// any) is replaced only if handshake succeeded.
+        pub async fn authenticate(&self) -> LlsdResult<()> {
+            let session = match self.current_session() {
+                Some(ref session) => session.renewed(),
+                None => {
+                    Session::with_config(self.server_keys.clone(),
+                                         self.long_term_keys.clone(),
+                                         self.session_config)
+                }
+            };
+            let session = handshake(session, |req| self.call_raw(req)).await?;
+            let mut sessions = self.session.lock().expect(POISONED_LOCK_MSG);
+            sessions.previous = sessions.current.take();
+            sessions.current = Some(Arc::new(session));
+            Ok(())
+        }
+
+        /// Send a frame and wait for the reply to it. Doesn't take care of
+        /// handshake.
+        pub async fn call_raw(&self, req: Frame) -> io::Result<Frame> {
+            let request_id = self.next_request_id();
+            let (tx, rx) = oneshot::channel();
+            self.pending
+                .lock()
+                .expect(POISONED_LOCK_MSG)
+                .insert(request_id, tx);
+            let _waiting = Waiting {
+                pending: &self.pending,
+                request_id: request_id,
+            };
+            // Reader is gone and won't ever answer.
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(connection_closed());
+            }
+            {
+                let mut writer = self.writer.lock().await;
+                if self.poisoned.swap(true, Ordering::SeqCst) {
+                    return Err(connection_poisoned());
+                }
+                write_tagged_frame(&mut *writer, request_id, &req).await?;
+                self.poisoned.store(false, Ordering::SeqCst);
+            }
+            rx.await.map_err(|_| connection_closed())
+        }
+
+        /// Same as `EngineSugar::request`.
+        /// Handshake is made (or redone, if session is about to expire)
+        /// before sending. If server rejects the session, request is retried
+        /// once with a new one. Concurrent requests share a single renewal.
+        pub async fn request<Req: IntoBytes, Res: FromBytes>(&self,
+                                                             route: Option<Route>,
+                                                             req: Req)
+                                                             -> LlsdResult<Res> {
+            let payload = routed_payload(route, req.into_bytes()?);
+            if self.needs_renewal() {
+                self.renew(None).await?;
+            }
+            let used = self.session_id();
+            let msg = match self.send(&payload).await {
+                Err(LlsdError::SessionRejected) => {
+                    self.renew(used).await?;
+                    self.send(&payload).await?
+                }
+                res => res?,
+            };
+            Res::from(msg)
+        }
+
+        // Id reserved for pushes is skipped once counter wraps around.
+        fn next_request_id(&self) -> RequestId {
+            loop {
+                let request_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                if request_id != PUSH_REQUEST_ID {
+                    return request_id;
+                }
+            }
+        }
+
+        fn current_session(&self) -> Option<Arc<Session>> {
+            self.session
+                .lock()
+                .expect(POISONED_LOCK_MSG)
+                .current
+                .clone()
+        }
+
+        fn session_id(&self) -> Option<PublicKey> {
+            self.current_session().map(|session| session.id())
+        }
+
+        fn needs_renewal(&self) -> bool {
+            match self.current_session() {
+                Some(session) => session.needs_renewal(),
+                None => true,
+            }
+        }
+
+        // Whoever gets the lock first does the handshake, the rest see the new
+        // session once they get it. `rejected` is the session server refused;
+        // it's only replaced if it's still the current one.
+        async fn renew(&self, rejected: Option<PublicKey>) -> LlsdResult<()> {
+            let _renewing = self.renewing.lock().await;
+            let stale = match rejected {
+                Some(session_id) => self.session_id() == Some(session_id),
+                None => self.needs_renewal(),
+            };
+            if stale {
+                self.authenticate().await?;
+            }
+            Ok(())
+        }
+
+        // Reply is opened with the session that sealed the request, even if
+        // another request replaced it in the meantime.
+        async fn send(&self, payload: &[u8]) -> LlsdResult<BytesMut> {
+            let session = match self.current_session() {
+                Some(session) => session,
+                None => return Err(LlsdError::InvalidSessionState),
+            };
+            let frame = session.make_message(payload)?;
+            let resp = self.call_raw(frame).await?;
+            if resp.kind == FrameKind::Termination {
+                return Err(rejection(&session, &resp));
+            }
+            session.read_reply(&resp)
+        }
+    }
+
+    // Hello/Welcome/Initiate/Ready exchange made through `call`. If server
+    // can't open Hello, next trusted server key is tried. Returns the session
+    // once it's ready.
+    async fn handshake<F, R>(mut session: Session, call: F) -> LlsdResult<Session>
+    where
+        F: Fn(Frame) -> R,
+        R: Future<Output = io::Result<Frame>>,
+    {
+        let welcome = loop {
+            let welcome = call(session.make_hello()).await?;
+            if welcome.kind != FrameKind::Termination {
+                break welcome;
+            }
+            let err = rejection(&session, &welcome);
+            match fallback(&session, &err) {
+                Some(next) => session = next,
+                None => return Err(err),
+            }
+        };
+        let initiate = session.make_initiate(&welcome)?;
+        let ready = call(initiate).await?;
+        if ready.kind == FrameKind::Termination {
+            return Err(rejection(&session, &ready));
+        }
+        session.read_ready(&ready)?;
+        Ok(session)
+    }
+
+    // Read responses and hand them to whoever is waiting for them. Pushes go
+    // to subscribers. Once connection is gone every pending request is failed
+    // and subscriptions are ended.
+    async fn dispatch(mut reader: OwnedReadHalf,
+                      pending: Pending,
+                      subscribers: Subscribers,
+                      session: SharedSession,
+                      closed: Arc<AtomicBool>) {
+        while let Ok(Some((request_id, frame))) = read_tagged_frame(&mut reader).await {
+            if request_id == PUSH_REQUEST_ID {
+                // Pushes share nonce window with replies, so they are opened
+                // as they come, not when subscriber gets to them. Ones that
+                // can't be opened with the session they were sealed with are
+                // dropped.
+                let sealed_with = {
+                    let sessions = session.lock().expect(POISONED_LOCK_MSG);
+                    sessions
+                        .current
+                        .iter()
+                        .chain(sessions.previous.iter())
+                        .find(|sealer| sealer.id() == frame.id)
+                        .cloned()
+                };
+                let msg = sealed_with.and_then(|session| session.read_msg(&frame).ok());
+                if let Some(msg) = msg {
+                    subscribers
+                        .lock()
+                        .expect(POISONED_LOCK_MSG)
+                        .retain(|tx| tx.send(msg.clone()).is_ok());
+                }
+                continue;
+            }
+            let waiter = pending
+                .lock()
+                .expect(POISONED_LOCK_MSG)
+                .remove(&request_id);
+            if let Some(tx) = waiter {
+                let _ = tx.send(frame);
+            }
+        }
+        closed.store(true, Ordering::SeqCst);
+        // Dropping senders wakes up everyone with an error.
+        pending.lock().expect(POISONED_LOCK_MSG).clear();
+        subscribers.lock().expect(POISONED_LOCK_MSG).clear();
+    }
+
+    // Forgets request once call is over, whether it got the reply, failed or
+    // was dropped.
+    struct Waiting<'a> {
+        pending: &'a Pending,
+        request_id: RequestId,
+    }
+
+    impl<'a> Drop for Waiting<'a> {
+        fn drop(&mut self) {
+            if let Ok(mut pending) = self.pending.lock() {
+                pending.remove(&self.request_id);
+            }
+        }
+    }
+
+    fn connection_closed() -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")
+    }
+
+    fn connection_poisoned() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+                       "Connection is out of sync after a call was dropped halfway")
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{ConnectionState, Engine, EngineSugar, FutureResponse};
+    use super::{CallHandle, ConnectionState, Engine, EngineSugar, FutureResponse, handshake};
     use byteorder::{BigEndian, ByteOrder};
     use bytes::Bytes;
+    use chrono::Duration;
     use futures::{Future, Poll};
     use futures::future;
-    use llsd::errors::LlsdError;
-    use llsd::frames::Frame;
-    use llsd::route::Route;
-    use llsd::session::Sendable;
-    use llsd::session::client::Session;
-    use llsd::session::server::Session as ServerSession;
+    use crate::llsd::errors::LlsdError;
+    use crate::llsd::frames::{AppError, ErrorCode, Frame, FrameKind, Termination};
+    use crate::llsd::route::Route;
+    use crate::llsd::session::{Sendable, SessionConfig};
+    use crate::llsd::session::client::Session;
+    use crate::llsd::session::cookie::CookieJar;
+    use crate::llsd::session::keyring::{KeyRing, ServerKeys};
+    use crate::llsd::session::loopback;
+    use crate::llsd::session::server::{Session as ServerSession, make_welcome};
     use mockers::Scenario;
-    use mockers::matchers::ANY;
-    use sodiumoxide::crypto::box_::SecretKey;
+    use sodiumoxide::crypto::box_::PublicKey;
     use sodiumoxide::crypto::box_::gen_keypair;
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
     use std::io;
     use std::rc::Rc;
 
-    fn do_handshake(client_session: &mut Session,
-                    server_session: &mut ServerSession,
-                    server_lt_secret: &SecretKey) {
-        let hello_frame = client_session.make_hello();
-        let welcome_frame = server_session
-            .make_welcome(&hello_frame, &server_lt_secret).unwrap();
-
-        let initiate_frame = client_session
-            .make_initiate(&welcome_frame).unwrap();
-
-        let client_lt_pk = server_session
-            .validate_initiate(&initiate_frame).unwrap();
-
-        let ready_frame = server_session
-            .make_ready(&initiate_frame, &client_lt_pk).unwrap();
-        client_session.read_ready(&ready_frame).unwrap();
+    // Answers frames the way AngelSystem would, except that first `rejects`
+    // messages find their sessions gone and get plain Termination in reply,
+    // same as `AngelSystem::terminate` sends.
+    fn fake_server<K: Into<KeyRing>>(server_keys: K, rejects: usize) -> CallHandle {
+        let server_keys = server_keys.into();
+        let sessions: RefCell<HashMap<PublicKey, ServerSession>> = RefCell::new(HashMap::new());
+        let cookies = CookieJar::new();
+        let rejects = Cell::new(rejects);
+        CallHandle::new(move |req| {
+            let mut sessions = sessions.borrow_mut();
+            let resp = match req.kind {
+                FrameKind::Hello => {
+                    match make_welcome(&req, &server_keys, &cookies) {
+                        Ok(welcome) => welcome,
+                        Err(e) => Termination::new(e.code(), "").to_plain_frame(req.id),
+                    }
+                }
+                FrameKind::Initiate => {
+                    let mut session =
+                        ServerSession::from_cookie(&req, &cookies, SessionConfig::default())
+                            .unwrap();
+                    let client_lt_pk = session.validate_initiate(&req).unwrap();
+                    let ready = session.make_ready(&req, &client_lt_pk).unwrap();
+                    sessions.insert(req.id, session);
+                    ready
+                }
+                _ if rejects.get() > 0 => {
+                    rejects.set(rejects.get() - 1);
+                    sessions.remove(&req.id);
+                    Termination::new(ErrorCode::UnknownSession, "").to_plain_frame(req.id)
+                }
+                _ => {
+                    let session = &sessions[&req.id];
+                    session.read_msg(&req).unwrap();
+                    session.make_reply(Ok(b"well hello")).unwrap()
+                }
+            };
+            FutureResponse(Box::new(future::ok(resp)))
+        })
     }
 
     #[test]
-    fn request_bytes_not_authenticated() {
+    fn request_bytes_handshakes_first() {
         let scenario = Scenario::new();
-        let mut engine = scenario.create_mock_for::<Engine>();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let session = Rc::new(RefCell::new(Session::new(server_lt.0, client_lt)));
+        scenario.expect(engine.session_call().and_return(session.clone()));
         scenario.expect(engine
-                            .connection_state_call()
-                            .and_return(ConnectionState::NotReady));
-        let call_result: Poll<Bytes, LlsdError> = engine.request(None, Bytes::new()).poll();
-        assert!(call_result.is_err());
+                            .call_handle_call()
+                            .and_return(fake_server(server_lt, 0)));
+
+        let call_result: Result<Bytes, LlsdError> = engine.request(None, Bytes::new()).wait();
+        assert_eq!(call_result.unwrap(), Bytes::from(&b"well hello"[..]));
+        assert!(session.borrow().can_send());
     }
 
     #[test]
     fn request_bytes_authenticated() {
         let scenario = Scenario::new();
-        let mut engine = scenario.create_mock_for::<Engine>();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
         let client_lt = gen_keypair();
         let server_lt = gen_keypair();
 
         let mut client_session = Session::new(server_lt.0.clone(), client_lt.clone());
-        let mut server_session = ServerSession::new(client_session.id());
-        do_handshake(&mut client_session, &mut server_session, &server_lt.1);
+        let (server_session, _) = loopback::handshake(&mut client_session, &server_lt).unwrap();
         let session = Rc::new(RefCell::new(client_session));
-        scenario.expect(engine
-                            .connection_state_call()
-                            .and_return(ConnectionState::Ready));
-        scenario.expect(engine
-                            .call_raw_call(ANY)
-                            .and_call(move |req| {
-            let server_session = server_session.clone();
+        let call = CallHandle::new(move |req| {
             let payload = server_session
                 .read_msg(&req).unwrap();
             assert_eq!(payload.len(), 0);
 
             let resp = server_session
-                .make_message(b"well hello").unwrap();
+                .make_reply(Ok(b"well hello")).unwrap();
             FutureResponse(Box::new(future::ok(resp)))
-        }));
+        });
         scenario.expect(engine.session_call().and_return(session));
+        scenario.expect(engine.call_handle_call().and_return(call));
 
         let call_result: Poll<Bytes, LlsdError> = engine.request(None, Bytes::new()).poll();
         assert!(call_result.is_ok());
     }
 
 
+    #[test]
+    fn request_application_error() {
+        let scenario = Scenario::new();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let mut client_session = Session::new(server_lt.0.clone(), client_lt.clone());
+        let (server_session, _) = loopback::handshake(&mut client_session, &server_lt).unwrap();
+        let session = Rc::new(RefCell::new(client_session));
+        let err = AppError::new(ErrorCode::Other(1000), "out of stock").with_detail(&b"sku-42"[..]);
+        let reply = err.clone();
+        let call = CallHandle::new(move |req| {
+            server_session.read_msg(&req).unwrap();
+            let resp = server_session.make_reply(Err(&reply)).unwrap();
+            FutureResponse(Box::new(future::ok(resp)))
+        });
+        scenario.expect(engine.session_call().and_return(session.clone()));
+        scenario.expect(engine.call_handle_call().and_return(call));
+
+        let call_result: Result<Bytes, LlsdError> = engine.request(None, Bytes::new()).wait();
+        match call_result {
+            Err(LlsdError::Application(ref got)) if *got == err => {}
+            other => panic!("Expected application error, got {:?}", other),
+        }
+        // Request failed, session is fine.
+        assert!(session.borrow().can_send());
+    }
+
     #[test]
     fn request_bytes_with_route() {
         let scenario = Scenario::new();
-        let mut engine = scenario.create_mock_for::<Engine>();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
         let client_lt = gen_keypair();
         let server_lt = gen_keypair();
 
         let mut client_session = Session::new(server_lt.0.clone(), client_lt.clone());
-        let mut server_session = ServerSession::new(client_session.id());
-        do_handshake(&mut client_session, &mut server_session, &server_lt.1);
+        let (server_session, _) = loopback::handshake(&mut client_session, &server_lt).unwrap();
         let session = Rc::new(RefCell::new(client_session));
 
         let route = Route::from("wat");
         let route_copy = route.clone();
-        scenario.expect(engine
-                            .connection_state_call()
-                            .and_return(ConnectionState::Ready));
-        scenario.expect(engine
-                            .call_raw_call(ANY)
-                            .and_call(move |req| {
-            let server_session = server_session.clone();
+        let call = CallHandle::new(move |req| {
             let payload = server_session
                 .read_msg(&req).unwrap();
             assert_eq!(payload.len(), 8);
@@ -454,29 +1246,124 @@ mod test {
             let dst = Route::from(hash);
             assert_eq!(dst, route.clone());
             let resp = server_session
-                .make_message(b"well hello").unwrap();
+                .make_reply(Ok(b"well hello")).unwrap();
             FutureResponse(Box::new(future::ok(resp)))
-        }));
+        });
         scenario.expect(engine.session_call().and_return(session));
+        scenario.expect(engine.call_handle_call().and_return(call));
 
         let call_result: Poll<Bytes, LlsdError> =
             engine.request(Some(route_copy), Bytes::new()).poll();
         assert!(call_result.is_ok());
     }
 
+    #[test]
+    fn request_renews_expiring_session() {
+        let scenario = Scenario::new();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+        let call = fake_server(server_lt.clone(), 0);
+
+        // Session that is always about to expire.
+        let config = SessionConfig { renew_before: Duration::days(1), ..SessionConfig::default() };
+        let session = Session::with_config(server_lt.0, client_lt, config);
+        let session = Rc::new(RefCell::new(session));
+        handshake(call.clone(), session.clone()).wait().unwrap();
+        let old_id = session.borrow().id();
+
+        scenario.expect(engine.session_call().and_return(session.clone()));
+        scenario.expect(engine.call_handle_call().and_return(call));
+
+        let call_result: Result<Bytes, LlsdError> = engine.request(None, Bytes::new()).wait();
+        assert!(call_result.is_ok());
+        assert!(session.borrow().id() != old_id);
+        assert_eq!(session.borrow().config(), config);
+    }
+
+    #[test]
+    fn request_retries_rejected_session() {
+        let scenario = Scenario::new();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+        let call = fake_server(server_lt.clone(), 1);
+
+        let session = Rc::new(RefCell::new(Session::new(server_lt.0, client_lt)));
+        handshake(call.clone(), session.clone()).wait().unwrap();
+        let old_id = session.borrow().id();
+
+        scenario.expect(engine.session_call().and_return(session.clone()));
+        scenario.expect(engine.call_handle_call().and_return(call));
+
+        let call_result: Result<Bytes, LlsdError> = engine.request(None, Bytes::new()).wait();
+        assert_eq!(call_result.unwrap(), Bytes::from(&b"well hello"[..]));
+        assert!(session.borrow().id() != old_id);
+    }
+
+    #[test]
+    fn request_gives_up_after_one_retry() {
+        let scenario = Scenario::new();
+        let mut engine = scenario.create_mock_for::<dyn Engine>();
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let session = Rc::new(RefCell::new(Session::new(server_lt.0, client_lt)));
+        scenario.expect(engine.session_call().and_return(session));
+        scenario.expect(engine
+                            .call_handle_call()
+                            .and_return(fake_server(server_lt, 2)));
+
+        let call_result: Result<Bytes, LlsdError> = engine.request(None, Bytes::new()).wait();
+        match call_result {
+            Err(LlsdError::SessionRejected) => {}
+            other => panic!("Expected SessionRejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_falls_back_to_next_server_key() {
+        let client_lt = gen_keypair();
+        let retired = gen_keypair();
+        let current = gen_keypair();
+        let call = fake_server(current.clone(), 0);
+
+        // Client still prefers the key server doesn't have anymore.
+        let keys = ServerKeys::new(retired.0).with_fallback(current.0);
+        let session = Rc::new(RefCell::new(Session::new(keys, client_lt.clone())));
+        handshake(call.clone(), session.clone()).wait().unwrap();
+        assert!(session.borrow().can_send());
+        assert_eq!(session.borrow().server_public_key(), current.0);
+
+        // Renewed session goes straight to the key that worked, retired one
+        // is only kept to fall back to.
+        let renewed = session.borrow().renewed();
+        assert_eq!(renewed.server_public_key(), current.0);
+        assert_eq!(renewed.fallback().unwrap().server_public_key(), retired.0);
+        let renewed = Rc::new(RefCell::new(renewed));
+        handshake(call.clone(), renewed.clone()).wait().unwrap();
+        assert!(renewed.borrow().can_send());
+        assert_eq!(renewed.borrow().server_public_key(), current.0);
+
+        // Nothing to fall back to.
+        let session = Rc::new(RefCell::new(Session::new(retired.0, client_lt)));
+        assert!(handshake(call, session).wait().is_err());
+    }
+
     #[test]
     fn test_call_not_authenticated() {
         let scenario = Scenario::new();
-        let engine = scenario.create_mock_for::<Engine>();
+        let engine = scenario.create_mock_for::<dyn Engine>();
         let (server_lt_pk, _) = gen_keypair();
 
         scenario.expect(engine
                             .connection_state_call()
                             .and_return(ConnectionState::NotReady));
         scenario.expect(engine
-                            .server_public_key_call()
-                            .and_return(server_lt_pk.clone()));
+                            .server_keys_call()
+                            .and_return(ServerKeys::new(server_lt_pk)));
         scenario.expect(engine.our_long_term_keys_call().and_return(gen_keypair()));
+        scenario.expect(engine.session_config_call().and_return(SessionConfig::default()));
 
         let session = engine.generate_session();
         let frame = session.make_hello();
@@ -487,13 +1374,12 @@ mod test {
     #[test]
     fn test_call_authenticated() {
         let scenario = Scenario::new();
-        let engine = scenario.create_mock_for::<Engine>();
+        let engine = scenario.create_mock_for::<dyn Engine>();
         let client_lt = gen_keypair();
         let server_lt = gen_keypair();
 
         let mut client_session = Session::new(server_lt.0.clone(), client_lt.clone());
-        let mut server_session = ServerSession::new(client_session.id());
-        do_handshake(&mut client_session, &mut server_session, &server_lt.1);
+        let (server_session, _) = loopback::handshake(&mut client_session, &server_lt).unwrap();
         let session = Rc::new(RefCell::new(client_session));
         let frame = session.borrow().make_message(b"well hello").unwrap();
         let resp_frame = server_session.make_message(b"well hello").unwrap();
@@ -0,0 +1,180 @@
+use bytes::{Bytes, BytesMut};
+use crate::llsd::client::{FromBytes, IntoBytes};
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+
+fn invalid<E: Display>(e: E) -> LlsdError {
+    LlsdError::InvalidPayload(e.to_string())
+}
+
+/// Way to turn serde types into payload and back.
+pub trait Codec: Send + Sync + 'static {
+    /// Encode value into payload.
+    fn encode<T: Serialize>(value: &T) -> LlsdResult<Bytes>;
+    /// Decode value from payload.
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> LlsdResult<T>;
+}
+
+/// JSON. Easy to read and to talk to from anywhere.
+pub enum JsonCodec {}
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(value: &T) -> LlsdResult<Bytes> {
+        serde_json::to_vec(value).map(Bytes::from).map_err(invalid)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> LlsdResult<T> {
+        serde_json::from_slice(payload).map_err(invalid)
+    }
+}
+
+/// MessagePack. Structs are written as maps, so fields can be added and
+/// reordered without breaking the other side.
+pub enum MsgPackCodec {}
+
+impl Codec for MsgPackCodec {
+    fn encode<T: Serialize>(value: &T) -> LlsdResult<Bytes> {
+        rmp_serde::to_vec_named(value).map(Bytes::from).map_err(invalid)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> LlsdResult<T> {
+        rmp_serde::from_slice(payload).map_err(invalid)
+    }
+}
+
+/// bincode. Smallest and fastest, but both sides must have exactly the same
+/// types.
+pub enum BincodeCodec {}
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(value: &T) -> LlsdResult<Bytes> {
+        bincode::serialize(value).map(Bytes::from).map_err(invalid)
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> LlsdResult<T> {
+        bincode::deserialize(payload).map_err(invalid)
+    }
+}
+
+// Wrapper that makes serde type a request or response of
+// `EngineSugar::request`. Wrapper is needed for the same reason as `Proto`.
+macro_rules! codec_payload {
+    ($(#[$attr:meta])* $name:ident, $codec:ty) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Default)]
+        pub struct $name<T>(pub T);
+
+        impl<T> $name<T> {
+            /// Unwrap the value.
+            pub fn into_inner(self) -> T {
+                self.0
+            }
+        }
+
+        impl<T> From<T> for $name<T> {
+            fn from(value: T) -> $name<T> {
+                $name(value)
+            }
+        }
+
+        impl<T: Serialize> IntoBytes for $name<T> {
+            fn into_bytes(self) -> LlsdResult<Bytes> {
+                <$codec>::encode(&self.0)
+            }
+        }
+
+        impl<T: DeserializeOwned> FromBytes for $name<T> {
+            fn from(bytes: BytesMut) -> LlsdResult<$name<T>> {
+                <$codec>::decode(&bytes).map($name)
+            }
+        }
+    };
+}
+
+codec_payload!(
+    /// Value sent as JSON.
+    Json,
+    JsonCodec
+);
+codec_payload!(
+    /// Value sent as MessagePack.
+    MsgPack,
+    MsgPackCodec
+);
+codec_payload!(
+    /// Value sent as bincode.
+    Bincode,
+    BincodeCodec
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+        label: Option<String>,
+    }
+
+    fn point() -> Point {
+        Point {
+            x: 3,
+            y: -4,
+            label: Some("a".to_owned()),
+        }
+    }
+
+    fn round_trip<C: Codec>() {
+        let payload = C::encode(&point()).unwrap();
+        assert_eq!(C::decode::<Point>(&payload).unwrap(), point());
+        match C::decode::<Point>(&payload[..1]) {
+            Err(LlsdError::InvalidPayload(_)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn codecs() {
+        round_trip::<JsonCodec>();
+        round_trip::<MsgPackCodec>();
+        round_trip::<BincodeCodec>();
+
+        let json = Json(point()).into_bytes().unwrap();
+        assert_eq!(json.as_ref(), &br#"{"x":3,"y":-4,"label":"a"}"#[..]);
+    }
+
+    #[test]
+    fn payload_wrappers() {
+        let decoded: Json<Point> = FromBytes::from(BytesMut::from(Json(point())
+                                                                       .into_bytes()
+                                                                       .unwrap()))
+                .unwrap();
+        assert_eq!(decoded.into_inner(), point());
+
+        let decoded: MsgPack<Point> = FromBytes::from(BytesMut::from(MsgPack(point())
+                                                                         .into_bytes()
+                                                                         .unwrap()))
+                .unwrap();
+        assert_eq!(decoded.into_inner(), point());
+
+        let decoded: Bincode<Point> = FromBytes::from(BytesMut::from(Bincode(point())
+                                                                         .into_bytes()
+                                                                         .unwrap()))
+                .unwrap();
+        assert_eq!(decoded.into_inner(), point());
+
+        // JSON object with non-string keys can't be written.
+        let mut map = BTreeMap::new();
+        map.insert(vec![1u8], 2u8);
+        match Json(map).into_bytes() {
+            Err(LlsdError::InvalidPayload(_)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(missing_docs)]
+use crate::llsd::frames::{AppError, ErrorCode, FrameKind};
 use std::io;
 use std::result::Result;
 
@@ -28,5 +29,49 @@ quick_error! {
         InvalidSessionState {}
         BadFrame {}
         ExpiredSession {}
+        SessionRejected {
+            description("Server doesn't accept this session anymore.")
+        }
+        ReplayedFrame {
+            description("Frame was replayed, reflected or is too old to be accepted.")
+        }
+        InvalidCookie {
+            description("Cookie is malformed, expired or made for another client.")
+        }
+        UnexpectedFrame(kind: FrameKind) {
+            description("Frame of this kind is not expected here.")
+            display("Unexpected {:?} frame", kind)
+        }
+        InvalidPayload(reason: String) {
+            description("Payload can't be encoded or decoded as expected type.")
+            display("Invalid payload: {}", reason)
+        }
+        Application(err: AppError) {
+            description("Request failed, server replied with an error.")
+            display("Request failed ({})", err)
+        }
+        Terminated(code: ErrorCode, reason: String) {
+            description("Other side terminated request with an error.")
+            display("Request terminated ({:?}): {}", code, reason)
+        }
+    }
+}
+
+impl LlsdError {
+    /// Error code to report this error to the other side with.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            LlsdError::Io(_) => ErrorCode::ServerFault,
+            LlsdError::HandshakeFailed |
+            LlsdError::InvalidHelloFrame |
+            LlsdError::InvalidInitiateFrame |
+            LlsdError::InvalidCookie => ErrorCode::HandshakeFailed,
+            LlsdError::InvalidSessionState => ErrorCode::UnknownSession,
+            LlsdError::ExpiredSession |
+            LlsdError::SessionRejected => ErrorCode::ExpiredSession,
+            LlsdError::Terminated(code, _) => code,
+            LlsdError::Application(ref err) => err.code,
+            _ => ErrorCode::BadFrame,
+        }
     }
 }
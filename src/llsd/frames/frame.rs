@@ -1,12 +1,16 @@
 use bytes::{BufMut, Bytes, BytesMut};
 
-use llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::errors::{LlsdError, LlsdResult};
 use nom::{IResult, rest};
 use sodiumoxide::crypto::box_::{Nonce, PublicKey};
 
 /// Header size in bytes. Used to pre-allocate vector of correct size.
 pub const HEADER_SIZE: usize = 57;
 
+/// Frames bigger than this are treated as garbage. Prevents peer from making
+/// us allocate whatever length it sent.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
 
 /// Frame type.
 #[derive(Debug, Clone, PartialEq, Copy, Eq, Hash)]
@@ -129,7 +133,7 @@ named!(parse_frame < &[u8], Frame >,
 mod test {
     use super::*;
 
-    use llsd::errors::LlsdError;
+    use crate::llsd::errors::LlsdError;
     use sodiumoxide::crypto::box_::{gen_keypair, gen_nonce};
 
     #[test]
@@ -1,3 +1,7 @@
 mod frame;
+mod termination;
+mod reply;
 
-pub use self::frame::{Frame, FrameKind};
+pub use self::frame::{Frame, FrameKind, MAX_FRAME_LENGTH};
+pub use self::termination::{ErrorCode, Termination};
+pub use self::reply::{AppError, REPLY_ERROR, REPLY_OK, ok_reply, read_reply};
@@ -0,0 +1,158 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::frames::ErrorCode;
+use std::fmt;
+use std::str;
+
+/// First byte of reply payload when request succeeded. Rest is the response.
+pub const REPLY_OK: u8 = 0;
+/// First byte of reply payload when request failed. Rest is `AppError`.
+pub const REPLY_ERROR: u8 = 1;
+
+/// Error request ended with, sent back in reply Message instead of response.
+/// Unlike Termination it says nothing about session: session is fine and
+/// the next request can go right away.
+///
+/// Packed as error code (u16 BigEndian), length of the message (u16
+/// BigEndian), message in UTF-8 and detail bytes till the end of payload.
+/// Empty detail is the same as no detail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppError {
+    /// What went wrong. Application codes go into `ErrorCode::Other`.
+    pub code: ErrorCode,
+    /// Details for humans.
+    pub message: String,
+    /// Anything else application wants the client to know, in whatever
+    /// format it likes.
+    pub detail: Option<Bytes>,
+}
+
+impl AppError {
+    /// Create error with given code and message and no detail.
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> AppError {
+        AppError {
+            code: code,
+            message: message.into(),
+            detail: None,
+        }
+    }
+
+    /// Attach detail.
+    pub fn with_detail<D: Into<Bytes>>(mut self, detail: D) -> AppError {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Pack into reply payload.
+    pub fn to_reply(&self) -> Bytes {
+        // Message can't be longer than its length field allows.
+        let mut end = self.message.len().min(u16::MAX as usize);
+        while !self.message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let message = &self.message.as_bytes()[..end];
+        let detail = self.detail.as_ref().map_or(&[][..], |detail| detail.as_ref());
+
+        let mut buf = BytesMut::with_capacity(5 + message.len() + detail.len());
+        buf.put_u8(REPLY_ERROR);
+        buf.put_u16::<BigEndian>(self.code.as_u16());
+        buf.put_u16::<BigEndian>(message.len() as u16);
+        buf.extend_from_slice(message);
+        buf.extend_from_slice(detail);
+        buf.freeze()
+    }
+
+    // Parse what's after status byte.
+    fn from_slice(payload: &[u8]) -> LlsdResult<AppError> {
+        if payload.len() < 4 {
+            return Err(LlsdError::IncompleteFrame);
+        }
+        let code = ErrorCode::from_u16(BigEndian::read_u16(&payload[..2]));
+        let len = BigEndian::read_u16(&payload[2..4]) as usize;
+        if payload.len() < 4 + len {
+            return Err(LlsdError::IncompleteFrame);
+        }
+        let message = match str::from_utf8(&payload[4..4 + len]) {
+            Ok(message) => message.to_owned(),
+            Err(_) => return Err(LlsdError::BadFrame),
+        };
+        let detail = &payload[4 + len..];
+        Ok(AppError {
+               code: code,
+               message: message,
+               detail: if detail.is_empty() {
+                   None
+               } else {
+                   Some(Bytes::from(detail))
+               },
+           })
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+/// Pack successful response into reply payload.
+pub fn ok_reply(response: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + response.len());
+    buf.put_u8(REPLY_OK);
+    buf.extend_from_slice(response);
+    buf.freeze()
+}
+
+/// Unpack reply payload: response if request succeeded,
+/// `LlsdError::Application` if it didn't.
+pub fn read_reply(mut payload: BytesMut) -> LlsdResult<BytesMut> {
+    if payload.is_empty() {
+        return Err(LlsdError::IncompleteFrame);
+    }
+    let status = payload.split_to(1)[0];
+    match status {
+        REPLY_OK => Ok(payload),
+        REPLY_ERROR => Err(LlsdError::Application(AppError::from_slice(&payload)?)),
+        _ => Err(LlsdError::BadFrame),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ok() {
+        let reply = ok_reply(b"pong");
+        assert_eq!(reply.as_ref(), b"\x00pong");
+        assert_eq!(read_reply(BytesMut::from(reply)).unwrap(), b"pong".to_vec());
+        assert!(read_reply(BytesMut::from(ok_reply(b""))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn error() {
+        let plain = AppError::new(ErrorCode::NotImplemented, "nope");
+        let detailed = AppError::new(ErrorCode::Other(1000), "out of stock")
+            .with_detail(&b"sku-42"[..]);
+        for err in &[plain, detailed] {
+            match read_reply(BytesMut::from(err.to_reply())) {
+                Err(LlsdError::Application(ref got)) if got == err => {}
+                other => panic!("Expected application error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: &[(&[u8], &str)] = &[(b"", "empty"),
+                                        (b"\x01\x00\x07", "no length"),
+                                        (b"\x01\x00\x07\x00\x05abc", "short message"),
+                                        (b"\x01\x00\x07\x00\x02\xff\xfe", "not utf-8"),
+                                        (b"\x02pong", "unknown status")];
+        for &(payload, case) in cases {
+            assert!(read_reply(BytesMut::from(payload)).is_err(), "{}", case);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::frames::{Frame, FrameKind};
+use sodiumoxide::crypto::box_::{PublicKey, gen_nonce};
+use std::str;
+
+/// Size of error code in Termination payload.
+pub const CODE_SIZE: usize = 2;
+
+/// Why request was terminated. Sent as u16 BigEndian, so both sides agree on
+/// it no matter what the rest of the error looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// Something went wrong on the other side and it's not caller's fault.
+    ServerFault,
+    /// Session is unknown or was dropped. New handshake is required.
+    UnknownSession,
+    /// Session has expired. New handshake is required.
+    ExpiredSession,
+    /// Handshake was rejected.
+    HandshakeFailed,
+    /// Frame was malformed, replayed or couldn't be decrypted.
+    BadFrame,
+    /// Nothing is registered for requested route.
+    InvalidRoute,
+    /// Requested action is not implemented.
+    NotImplemented,
+    /// Server reached its limit of sessions.
+    TooManySessions,
+    /// Session was closed by Termination frame from the client.
+    SessionClosed,
+    /// Client is not allowed to do what it asked for.
+    PermissionDenied,
+    /// Request is bigger than the other side accepts.
+    PayloadTooLarge,
+    /// Code this side doesn't know about.
+    Other(u16),
+}
+
+impl ErrorCode {
+    /// Decode error code.
+    pub fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::ServerFault,
+            2 => ErrorCode::UnknownSession,
+            3 => ErrorCode::ExpiredSession,
+            4 => ErrorCode::HandshakeFailed,
+            5 => ErrorCode::BadFrame,
+            6 => ErrorCode::InvalidRoute,
+            7 => ErrorCode::NotImplemented,
+            8 => ErrorCode::TooManySessions,
+            9 => ErrorCode::SessionClosed,
+            10 => ErrorCode::PermissionDenied,
+            11 => ErrorCode::PayloadTooLarge,
+            other => ErrorCode::Other(other),
+        }
+    }
+
+    /// Encode error code.
+    pub fn as_u16(&self) -> u16 {
+        match *self {
+            ErrorCode::ServerFault => 1,
+            ErrorCode::UnknownSession => 2,
+            ErrorCode::ExpiredSession => 3,
+            ErrorCode::HandshakeFailed => 4,
+            ErrorCode::BadFrame => 5,
+            ErrorCode::InvalidRoute => 6,
+            ErrorCode::NotImplemented => 7,
+            ErrorCode::TooManySessions => 8,
+            ErrorCode::SessionClosed => 9,
+            ErrorCode::PermissionDenied => 10,
+            ErrorCode::PayloadTooLarge => 11,
+            ErrorCode::Other(other) => other,
+        }
+    }
+}
+
+/// Payload of Termination frame: error code followed by human readable
+/// reason in UTF-8.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Termination {
+    /// What went wrong.
+    pub code: ErrorCode,
+    /// Details for humans. Never used to make decisions.
+    pub reason: String,
+}
+
+impl Termination {
+    /// Create termination with given code and reason.
+    pub fn new<R: Into<String>>(code: ErrorCode, reason: R) -> Termination {
+        Termination {
+            code: code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Pack into payload.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(CODE_SIZE + self.reason.len());
+        buf.put_u16::<BigEndian>(self.code.as_u16());
+        buf.extend_from_slice(self.reason.as_bytes());
+        buf.freeze()
+    }
+
+    /// Parse payload.
+    pub fn from_slice(payload: &[u8]) -> LlsdResult<Termination> {
+        if payload.len() < CODE_SIZE {
+            return Err(LlsdError::IncompleteFrame);
+        }
+        let code = ErrorCode::from_u16(BigEndian::read_u16(&payload[..CODE_SIZE]));
+        let reason = match str::from_utf8(&payload[CODE_SIZE..]) {
+            Ok(reason) => reason.to_owned(),
+            Err(_) => return Err(LlsdError::BadFrame),
+        };
+        Ok(Termination::new(code, reason))
+    }
+
+    /// Termination frame that isn't encrypted. Used when there is no session
+    /// to encrypt it with.
+    pub fn to_plain_frame(&self, id: PublicKey) -> Frame {
+        Frame {
+            id: id,
+            nonce: gen_nonce(),
+            kind: FrameKind::Termination,
+            payload: self.to_bytes(),
+        }
+    }
+
+    /// Error client should see. Codes that mean session is gone turn into
+    /// `SessionRejected`, so engines know to do a new handshake.
+    pub fn into_error(self) -> LlsdError {
+        match self.code {
+            ErrorCode::UnknownSession |
+            ErrorCode::ExpiredSession |
+            ErrorCode::SessionClosed => LlsdError::SessionRejected,
+            code => LlsdError::Terminated(code, self.reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pack_and_unpack() {
+        let termination = Termination::new(ErrorCode::InvalidRoute, "no such route");
+        let packed = termination.to_bytes();
+        assert_eq!(packed.len(), 15);
+        assert_eq!(Termination::from_slice(&packed).unwrap(), termination);
+
+        let unknown = Termination::new(ErrorCode::Other(1000), "");
+        assert_eq!(Termination::from_slice(&unknown.to_bytes()).unwrap(), unknown);
+    }
+
+    #[test]
+    fn malformed_payload() {
+        match Termination::from_slice(&[0]) {
+            Err(LlsdError::IncompleteFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match Termination::from_slice(&[0, 1, 0xff, 0xfe]) {
+            Err(LlsdError::BadFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn into_error() {
+        match Termination::new(ErrorCode::ExpiredSession, "").into_error() {
+            LlsdError::SessionRejected => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match Termination::new(ErrorCode::TooManySessions, "busy").into_error() {
+            LlsdError::Terminated(ErrorCode::TooManySessions, ref reason) if reason == "busy" => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
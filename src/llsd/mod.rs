@@ -1,4 +1,9 @@
 #![deny(missing_docs)]
+
+/// Message to panic with when a lock was poisoned by a panic in another
+/// thread. Shared by everything that keeps state behind locks.
+pub const POISONED_LOCK_MSG: &'static str = "Lock was poisoned";
+
 /// This should be an example of how to use session module, but oh well.
 pub mod session;
 /// This module should only return errors from this sub-module.
@@ -8,8 +13,17 @@ pub mod frames;
 /// Things related to running either client or server on top of tokio.
 #[cfg(feature = "system-on-tokio")]
 pub mod tokio;
+/// Same framing as in `tokio` module, but for std futures and tokio 1.x.
+#[cfg(feature = "async-runtime")]
+pub mod runtime;
 /// This should be a separate crate in the future. Things related to building a
 /// client to `AngelSystem`.
 pub mod client;
+/// Protocol Buffers messages as request and response payloads.
+#[cfg(feature = "protobuf")]
+pub mod proto;
+/// JSON, MessagePack and bincode payloads for anything serde can handle.
+#[cfg(feature = "serde")]
+pub mod codec;
 /// Aid in creation of routes for router.
 pub mod route;
@@ -0,0 +1,97 @@
+use bytes::{Buf, Bytes, BytesMut, IntoBuf};
+use crate::llsd::client::{FromBytes, IntoBytes};
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use prost::Message;
+
+/// Encode message into payload.
+pub fn encode<M: Message>(msg: &M) -> Bytes {
+    let mut buf = BytesMut::with_capacity(msg.encoded_len());
+    msg.encode_raw(&mut buf);
+    buf.freeze()
+}
+
+/// Decode message from payload. Fields missing in the payload get their
+/// default values.
+pub fn decode<M: Message + Default>(payload: &[u8]) -> LlsdResult<M> {
+    let mut msg = M::default();
+    let len = payload.len();
+    msg.merge(&mut payload.into_buf().take(len))
+        .map_err(|e| LlsdError::InvalidPayload(e.to_string()))?;
+    Ok(msg)
+}
+
+/// Protobuf message as request or response of `EngineSugar::request`.
+///
+/// Can't implement `IntoBytes` and `FromBytes` for every `Message` directly:
+/// prost is free to implement `Message` for `Bytes` one day and then it
+/// would clash with raw payload impls. So message goes in a wrapper:
+///
+/// ```ignore
+/// let sum: Proto<Sum> = client.request(Some(route), Proto(add)).await?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Proto<M>(pub M);
+
+impl<M> Proto<M> {
+    /// Unwrap the message.
+    pub fn into_inner(self) -> M {
+        self.0
+    }
+}
+
+impl<M> From<M> for Proto<M> {
+    fn from(msg: M) -> Proto<M> {
+        Proto(msg)
+    }
+}
+
+impl<M: Message> IntoBytes for Proto<M> {
+    fn into_bytes(self) -> LlsdResult<Bytes> {
+        Ok(encode(&self.0))
+    }
+}
+
+impl<M: Message + Default> FromBytes for Proto<M> {
+    fn from(bytes: BytesMut) -> LlsdResult<Proto<M>> {
+        decode(&bytes).map(Proto)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Debug, Message)]
+    struct Add {
+        #[prost(int64, required, tag = "1")]
+        left: i64,
+        #[prost(int64, required, tag = "2")]
+        right: i64,
+    }
+
+    #[test]
+    fn round_trip() {
+        let add = Add {
+            left: 40,
+            right: -2,
+        };
+        let payload = Proto(add.clone()).into_bytes().unwrap();
+        assert_eq!(payload.len(), add.encoded_len());
+
+        let decoded: Proto<Add> = FromBytes::from(BytesMut::from(payload)).unwrap();
+        assert_eq!(decoded.into_inner(), add);
+
+        // Nothing is set, everything is default.
+        let empty: Add = decode(&[]).unwrap();
+        assert_eq!(empty, Add::default());
+    }
+
+    #[test]
+    fn garbage() {
+        // Field 1 says it's varint, but varint never ends.
+        match decode::<Add>(&[0x08, 0xff, 0xff]) {
+            Err(LlsdError::InvalidPayload(_)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
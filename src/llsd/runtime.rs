@@ -0,0 +1,157 @@
+use byteorder::{BigEndian, ByteOrder};
+use crate::frames::Frame;
+use crate::llsd::errors::LlsdError;
+use std::io;
+use tokio1::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Id used to match responses with requests in multiplexed transport.
+pub type RequestId = u64;
+
+/// Request id reserved for frames server sends on its own (pushes). Clients
+/// never use it for requests and server drops connections that do. tokio-proto
+/// client numbers requests from 0, so the reserved id is the last one.
+pub const PUSH_REQUEST_ID: RequestId = ::std::u64::MAX;
+
+/// How responses are matched with requests on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Responses are sent back in the same order requests came in.
+    Pipelined,
+    /// Every frame is tagged with `RequestId`, so responses can come back in
+    /// any order.
+    Multiplexed,
+}
+
+pub use crate::frames::MAX_FRAME_LENGTH;
+
+/// Read one length prefixed frame. Uses the same framing as `FrameCodec`: 4
+/// bytes of length followed by the frame. Returns `None` if connection was
+/// closed before next frame started.
+pub async fn read_frame<R: AsyncRead + Unpin>(io: &mut R) -> io::Result<Option<Frame>> {
+    let mut len_buf = [0u8; 4];
+    match io.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let frame_len = BigEndian::read_u32(&len_buf) as usize;
+    if frame_len > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, LlsdError::BadFrame));
+    }
+    let mut data = vec![0u8; frame_len];
+    io.read_exact(&mut data).await?;
+    Frame::from_slice(&data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write one length prefixed frame and flush it.
+pub async fn write_frame<W: AsyncWrite + Unpin>(io: &mut W, frame: &Frame) -> io::Result<()> {
+    let mut len_buf = [0u8; 4];
+    BigEndian::write_u32(&mut len_buf, frame.length() as u32);
+    io.write_all(&len_buf).await?;
+    io.write_all(&frame.pack()).await?;
+    io.flush().await
+}
+
+/// Same as `read_frame`, but frame is expected to be tagged with request id.
+/// Id goes right after length prefix. Length prefix doesn't include it.
+pub async fn read_tagged_frame<R: AsyncRead + Unpin>(io: &mut R)
+                                                     -> io::Result<Option<(RequestId, Frame)>> {
+    let mut header = [0u8; 12];
+    match io.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let frame_len = BigEndian::read_u32(&header[0..4]) as usize;
+    let request_id = BigEndian::read_u64(&header[4..12]);
+    if frame_len > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, LlsdError::BadFrame));
+    }
+    let mut data = vec![0u8; frame_len];
+    io.read_exact(&mut data).await?;
+    Frame::from_slice(&data)
+        .map(|frame| Some((request_id, frame)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Same as `write_frame`, but tags frame with request id.
+pub async fn write_tagged_frame<W: AsyncWrite + Unpin>(io: &mut W,
+                                                       request_id: RequestId,
+                                                       frame: &Frame)
+                                                       -> io::Result<()> {
+    let mut header = [0u8; 12];
+    BigEndian::write_u32(&mut header[0..4], frame.length() as u32);
+    BigEndian::write_u64(&mut header[4..12], request_id);
+    io.write_all(&header).await?;
+    io.write_all(&frame.pack()).await?;
+    io.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frames::FrameKind;
+    use sodiumoxide::crypto::box_::{gen_keypair, gen_nonce};
+    use tokio1::runtime::Builder;
+
+    fn make_frame() -> Frame {
+        let (pk, _) = gen_keypair();
+        let payload = vec![0, 0, 0];
+        let nonce = gen_nonce();
+
+        Frame {
+            id: pk,
+            nonce: nonce,
+            kind: FrameKind::Hello,
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn write_and_read() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let frame = make_frame();
+            let mut buf = Vec::new();
+            write_frame(&mut buf, &frame).await.unwrap();
+            assert_eq!(buf.len(), 4 + frame.length());
+
+            let mut reader = &buf[..];
+            let read = read_frame(&mut reader).await.unwrap();
+            assert_eq!(read, Some(frame));
+            // Nothing left - connection closed.
+            let eof = read_frame(&mut reader).await.unwrap();
+            assert!(eof.is_none());
+        });
+    }
+
+    #[test]
+    fn too_long() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let buf = vec![255u8, 255, 255, 255, 0];
+            let mut reader = &buf[..];
+            assert!(read_frame(&mut reader).await.is_err());
+        });
+    }
+
+    #[test]
+    fn tagged_write_and_read() {
+        let rt = Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let frame = make_frame();
+            let mut buf = Vec::new();
+            write_tagged_frame(&mut buf, 42, &frame).await.unwrap();
+            write_tagged_frame(&mut buf, 7, &frame).await.unwrap();
+            assert_eq!(buf.len(), 2 * (12 + frame.length()));
+
+            let mut reader = &buf[..];
+            assert_eq!(read_tagged_frame(&mut reader).await.unwrap(),
+                       Some((42, frame.clone())));
+            assert_eq!(read_tagged_frame(&mut reader).await.unwrap(), Some((7, frame)));
+            assert!(read_tagged_frame(&mut reader).await.unwrap().is_none());
+        });
+    }
+}
@@ -1,13 +1,18 @@
 
 
-use super::{KeyPair, NULL_BYTES, Sendable, SessionState};
+use super::{Activity, INITIATE_CONTENT_SIZE, INITIATE_PAYLOAD_SIZE, KeyPair, NULL_BYTES, Sendable,
+            SessionConfig, SessionState, VOUCH_SIZE, WELCOME_CONTENT_SIZE};
+use super::keyring::ServerKeys;
+use super::nonce::{CLIENT_MSG_PREFIX, Nonces, SERVER_MSG_PREFIX};
 use bytes::{Bytes, BytesMut};
-use chrono::{DateTime, Duration};
+use chrono::DateTime;
 use chrono::offset::Utc;
-use llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::errors::{LlsdError, LlsdResult};
 
-use llsd::frames::{Frame, FrameKind};
-use sodiumoxide::crypto::box_::{Nonce, PublicKey, gen_keypair, gen_nonce, open, seal};
+use crate::llsd::frames::{ErrorCode, Frame, FrameKind, Termination, read_reply};
+use sodiumoxide::crypto::box_::{Nonce, PUBLICKEYBYTES, PrecomputedKey, PublicKey, gen_keypair,
+                                 gen_nonce, open, open_precomputed, precompute, seal,
+                                 seal_precomputed};
 const READY_PAYLOAD: &'static [u8; 16] = b"My body is ready";
 
 
@@ -20,24 +25,141 @@ pub struct Session {
     our_pair: KeyPair,
     state: SessionState,
     server_pk: Option<PublicKey>,
+    server_keys: ServerKeys,
+    /// Which of server keys this session uses.
+    key_index: usize,
     server_lt_pk: PublicKey,
+    /// Shared key for messages, computed once server short-term key is known.
+    shared: Option<PrecomputedKey>,
+    nonces: Nonces,
+    config: SessionConfig,
+    activity: Activity,
 }
 
 impl Session {
     /// Create new client session. Requires client long-term key-pair and
     /// server long-term public
+    /// key. Takes a set of `ServerKeys` as well, while server is rotating its
     /// key.
-    pub fn new(server_lt_pk: PublicKey, our_pair: KeyPair) -> Session {
+    pub fn new<K: Into<ServerKeys>>(server_keys: K, our_pair: KeyPair) -> Session {
+        Session::with_config(server_keys, our_pair, SessionConfig::default())
+    }
+
+    /// Same as `new`, but with custom lifetimes. Should match what server is
+    /// configured with.
+    pub fn with_config<K: Into<ServerKeys>>(server_keys: K,
+                                            our_pair: KeyPair,
+                                            config: SessionConfig)
+                                            -> Session {
+        Session::with_key_index(server_keys.into(), 0, our_pair, config)
+    }
+
+    fn with_key_index(server_keys: ServerKeys,
+                      key_index: usize,
+                      our_pair: KeyPair,
+                      config: SessionConfig)
+                      -> Session {
+        let server_lt_pk = server_keys.get(key_index).unwrap_or_else(|| server_keys.preferred());
         Session {
-            expire_at: Utc::now() + Duration::minutes(34),
+            expire_at: Utc::now() + config.ttl,
             created_at: Utc::now(),
             st: gen_keypair(),
             our_pair: our_pair,
             state: SessionState::Fresh,
             server_pk: None,
+            server_keys: server_keys,
+            key_index: key_index,
             server_lt_pk: server_lt_pk,
+            shared: None,
+            nonces: Nonces::new(),
+            config: config,
+            activity: Activity::new(),
         }
     }
+
+    /// Time after which session can't be used anymore. Server might drop it
+    /// earlier if it's idle for too long.
+    pub fn expire_at(&self) -> DateTime<Utc> {
+        self.expire_at
+    }
+
+    /// Current state of the session.
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    /// Lifetimes this session was created with.
+    pub fn config(&self) -> SessionConfig {
+        self.config
+    }
+
+    /// Check if it's time to replace this session with a new one: either it
+    /// can't be used right now or it's about to expire.
+    pub fn needs_renewal(&self) -> bool {
+        !self.can_send() || self.expire_at - self.config.renew_before <= Utc::now()
+    }
+
+    /// Server long-term key this session trusts.
+    pub fn server_public_key(&self) -> PublicKey {
+        self.server_lt_pk
+    }
+
+    /// Brand new session for the same server, keys and config. Handshake
+    /// has to be done again. Server key this session ended up with becomes
+    /// the preferred one, so renewal doesn't have to fall back all over
+    /// again.
+    pub fn renewed(&self) -> Session {
+        Session::with_config(self.server_keys.preferring(self.key_index),
+                             self.our_pair.clone(),
+                             self.config)
+    }
+
+    /// Brand new session that trusts the next server key. Used when server
+    /// couldn't open Hello sealed to the current one. `None` once every key
+    /// was tried.
+    pub fn fallback(&self) -> Option<Session> {
+        let next = self.key_index + 1;
+        self.server_keys.get(next).map(|_| {
+            Session::with_key_index(self.server_keys.clone(),
+                                    next,
+                                    self.our_pair.clone(),
+                                    self.config)
+        })
+    }
+
+    /// Read reply Message sent by server: response if request succeeded,
+    /// `LlsdError::Application` with the error it failed with otherwise.
+    pub fn read_reply(&self, frame: &Frame) -> LlsdResult<BytesMut> {
+        read_reply(self.read_msg(frame)?)
+    }
+
+    /// Read Termination frame sent by server. Until handshake is complete
+    /// server has nothing to encrypt it with, so it comes in plain text. Once
+    /// session is ready Termination is sealed with the session, unless
+    /// server doesn't have the session anymore (it was reaped or server was
+    /// restarted). So plain `UnknownSession` and `ExpiredSession` are
+    /// accepted too, but anybody on the way could have sent them: reason is
+    /// dropped and they are only good for doing a new handshake. Any other
+    /// plain Termination is rejected with `LlsdError::BadFrame`.
+    pub fn read_termination(&self, frame: &Frame) -> LlsdResult<Termination> {
+        if frame.kind != FrameKind::Termination {
+            return Err(LlsdError::BadFrame);
+        }
+        if self.state != SessionState::Ready {
+            return Termination::from_slice(&frame.payload);
+        }
+        if let Ok(payload) = self.read_msg(frame) {
+            return Termination::from_slice(&payload);
+        }
+        match Termination::from_slice(&frame.payload) {
+            Ok(ref termination) if termination.code == ErrorCode::UnknownSession ||
+                                   termination.code == ErrorCode::ExpiredSession => {
+                Ok(Termination::new(termination.code, ""))
+            }
+            _ => Err(LlsdError::BadFrame),
+        }
+    }
+
     /// Helper to make Hello frame. Client workflow.
     pub fn make_hello(&self) -> Frame {
         let nonce = gen_nonce();
@@ -55,23 +177,29 @@ impl Session {
         if self.state != SessionState::Fresh || welcome.kind != FrameKind::Welcome {
             return Err(LlsdError::InvalidSessionState);
         }
-        // Try to obtain server short public key from the box.
-        if let Ok(server_pk) = open(&welcome.payload,
-                                 &welcome.nonce,
-                                 &self.server_lt_pk,
-                                 &self.st.1)
+        // Try to obtain server short public key and cookie from the box.
+        if let Ok(welcome_content) = open(&welcome.payload,
+                                          &welcome.nonce,
+                                          &self.server_lt_pk,
+                                          &self.st.1)
         {
-            if let Some(key) = PublicKey::from_slice(&server_pk) {
+            let key = if welcome_content.len() == WELCOME_CONTENT_SIZE {
+                PublicKey::from_slice(&welcome_content[..PUBLICKEYBYTES])
+            } else {
+                None
+            };
+            if let Some(key) = key {
                 self.server_pk = Some(key);
-                let mut initiate_box = Vec::with_capacity(104);
+                self.shared = Some(precompute(&key, &self.st.1));
+                let mut initiate_box = Vec::with_capacity(INITIATE_CONTENT_SIZE);
                 let our_pk = &self.our_pair.0;
                 initiate_box.extend_from_slice(&our_pk.0);
-                initiate_box.extend(self.vouch());
+                initiate_box.extend(self.vouch(&key));
                 let nonce = gen_nonce();
-                let payload = seal(&initiate_box,
-                                   &nonce,
-                                   &self.server_pk.expect("Shit is on fire yo"),
-                                   &self.st.1);
+                // Cookie goes back to the server as is.
+                let mut payload = Vec::with_capacity(INITIATE_PAYLOAD_SIZE);
+                payload.extend_from_slice(&welcome_content[PUBLICKEYBYTES..]);
+                payload.extend(seal(&initiate_box, &nonce, &key, &self.st.1));
                 let frame = Frame {
                     id: welcome.id,
                     nonce: nonce,
@@ -96,7 +224,7 @@ impl Session {
         if self.state != SessionState::Fresh || ready.kind != FrameKind::Ready {
             return Err(LlsdError::InvalidSessionState);
         }
-        let msg = try!(self.read_msg(ready));
+        let msg = self.read_msg(ready)?;
         if msg.as_ref() == READY_PAYLOAD {
             self.state = SessionState::Ready;
             Ok(())
@@ -105,17 +233,16 @@ impl Session {
         }
     }
 
-    // Helper to make a vouch
-    fn vouch(&self) -> Vec<u8> {
+    // Helper to make a vouch: our short-term public key sealed with our
+    // long-term secret key. Proves that whoever owns the long-term key owns
+    // this session.
+    fn vouch(&self, server_pk: &PublicKey) -> Vec<u8> {
         let nonce = gen_nonce();
         let our_sk = &self.our_pair.1;
-        let pk = &self.st.1;
-        let vouch_box = seal(&pk.0,
-                             &nonce,
-                             &self.server_pk.expect("Shit is on fire yo"),
-                             our_sk);
+        let pk = &self.st.0;
+        let vouch_box = seal(&pk.0, &nonce, server_pk, our_sk);
 
-        let mut vouch = Vec::with_capacity(72);
+        let mut vouch = Vec::with_capacity(VOUCH_SIZE);
         vouch.extend_from_slice(&nonce.0);
         vouch.extend(vouch_box);
         vouch
@@ -129,21 +256,27 @@ impl Sendable for Session {
     }
 
     fn can_send(&self) -> bool {
-        self.state == SessionState::Ready && self.expire_at > Utc::now()
+        self.state == SessionState::Ready && self.expire_at > Utc::now() &&
+            !self.activity.idle_for_too_long(self.config.max_idle)
     }
 
     fn seal_msg(&self, data: &[u8]) -> (Nonce, Bytes) {
-        let nonce = gen_nonce();
-        let payload = seal(data, &nonce, &self.server_pk.unwrap(), &self.st.1);
+        let nonce = self.nonces.next(CLIENT_MSG_PREFIX);
+        let key = self.shared.as_ref().expect("Shit is on fire yo");
+        let payload = seal_precomputed(data, &nonce, key);
         (nonce, payload.into())
     }
 
     fn read_msg(&self, frame: &Frame) -> LlsdResult<BytesMut> {
-        if let Ok(msg) = open(&frame.payload,
-                           &frame.nonce,
-                           &self.server_pk.expect("Shit is on fire yo!"),
-                           &self.st.1)
-        {
+        let key = match self.shared {
+            Some(ref key) => key,
+            None => return Err(LlsdError::InvalidSessionState),
+        };
+        let counter = Nonces::counter(SERVER_MSG_PREFIX, &frame.nonce)?;
+        if let Ok(msg) = open_precomputed(&frame.payload, &frame.nonce, key) {
+            // Only authentic frames are allowed to move the window.
+            self.nonces.accept(counter)?;
+            self.activity.touch();
             Ok(msg.into())
         } else {
             Err(LlsdError::DecryptionFailed)
@@ -0,0 +1,286 @@
+use super::KeyPair;
+use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, Duration};
+use chrono::offset::Utc;
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use sodiumoxide::crypto::box_::{PUBLICKEYBYTES, PublicKey, SECRETKEYBYTES, SecretKey};
+use sodiumoxide::crypto::secretbox;
+use std::collections::HashSet;
+
+/// Size of time cookie was issued at: milliseconds since epoch, i64
+/// BigEndian.
+const ISSUED_AT_SIZE: usize = 8;
+
+/// Size of what goes into cookie: client's short-term public key, server's
+/// short-term key pair and time cookie was issued at.
+const COOKIE_CONTENT_SIZE: usize = PUBLICKEYBYTES * 2 + SECRETKEYBYTES + ISSUED_AT_SIZE;
+
+/// Size of cookie as it's sent in Welcome and echoed back in Initiate.
+pub const COOKIE_SIZE: usize = secretbox::NONCEBYTES + secretbox::MACBYTES + COOKIE_CONTENT_SIZE;
+
+/// Key that only server knows. Cookie sealed with it can only be opened by
+/// the server, so server doesn't have to remember anything between Hello and
+/// Initiate.
+#[derive(Clone)]
+pub struct CookieKey(secretbox::Key);
+
+impl CookieKey {
+    /// Generate brand new key.
+    pub fn generate() -> CookieKey {
+        CookieKey(secretbox::gen_key())
+    }
+
+    /// Pack short-term keys of both sides into a cookie. Cookie remembers
+    /// when it was issued, so server knows how long handshake is taking.
+    pub fn seal(&self, client_pk: &PublicKey, server_st: &KeyPair) -> Vec<u8> {
+        self.seal_issued(client_pk, server_st, Utc::now())
+    }
+
+    /// Same as `seal`, but cookie pretends to be issued at `issued_at`.
+    #[cfg(test)]
+    pub fn seal_at(&self,
+                   client_pk: &PublicKey,
+                   server_st: &KeyPair,
+                   issued_at: DateTime<Utc>)
+                   -> Vec<u8> {
+        self.seal_issued(client_pk, server_st, issued_at)
+    }
+
+    fn seal_issued(&self,
+                   client_pk: &PublicKey,
+                   server_st: &KeyPair,
+                   issued_at: DateTime<Utc>)
+                   -> Vec<u8> {
+        let mut issued_at_buf = [0u8; ISSUED_AT_SIZE];
+        BigEndian::write_i64(&mut issued_at_buf, issued_at.timestamp_millis());
+        let mut content = Vec::with_capacity(COOKIE_CONTENT_SIZE);
+        content.extend_from_slice(&client_pk.0);
+        content.extend_from_slice(&(server_st.0).0);
+        content.extend_from_slice(&(server_st.1).0);
+        content.extend_from_slice(&issued_at_buf);
+        let nonce = secretbox::gen_nonce();
+        let mut cookie = Vec::with_capacity(COOKIE_SIZE);
+        cookie.extend_from_slice(&nonce.0);
+        cookie.extend(secretbox::seal(&content, &nonce, &self.0));
+        cookie
+    }
+
+    /// Get server's short-term key pair and time cookie was issued at out of
+    /// the cookie. Cookie has to be made for this very client.
+    pub fn open(&self,
+                cookie: &[u8],
+                client_pk: &PublicKey)
+                -> LlsdResult<(KeyPair, DateTime<Utc>)> {
+        if cookie.len() != COOKIE_SIZE {
+            return Err(LlsdError::InvalidCookie);
+        }
+        let (nonce, sealed) = cookie.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or(LlsdError::InvalidCookie)?;
+        let content = match secretbox::open(sealed, &nonce, &self.0) {
+            Ok(content) => content,
+            Err(_) => return Err(LlsdError::InvalidCookie),
+        };
+        let (cookie_client_pk, server_st) = content.split_at(PUBLICKEYBYTES);
+        let (server_pk, rest) = server_st.split_at(PUBLICKEYBYTES);
+        let (server_sk, issued_at) = rest.split_at(SECRETKEYBYTES);
+        if cookie_client_pk != &client_pk.0[..] {
+            return Err(LlsdError::InvalidCookie);
+        }
+        let server_pk = PublicKey::from_slice(server_pk).ok_or(LlsdError::InvalidCookie)?;
+        let server_sk = SecretKey::from_slice(server_sk).ok_or(LlsdError::InvalidCookie)?;
+        let issued_at = DateTime::from_timestamp_millis(BigEndian::read_i64(issued_at))
+            .ok_or(LlsdError::InvalidCookie)?;
+        Ok(((server_pk, server_sk), issued_at))
+    }
+}
+
+// Cookie key and clients whose cookies sealed with it were already spent.
+#[derive(Clone)]
+struct Batch {
+    key: CookieKey,
+    spent: HashSet<PublicKey>,
+}
+
+impl Batch {
+    fn generate() -> Batch {
+        Batch {
+            key: CookieKey::generate(),
+            spent: HashSet::new(),
+        }
+    }
+}
+
+/// Current cookie key and the one before it. Keys are rotated, so a cookie
+/// can only be used for a limited time: until the key it was sealed with is
+/// rotated out twice. Within that time cookie can be spent only once.
+#[derive(Clone)]
+pub struct CookieJar {
+    current: Batch,
+    previous: Option<Batch>,
+    rotated_at: DateTime<Utc>,
+}
+
+impl CookieJar {
+    /// Jar with a single fresh key.
+    pub fn new() -> CookieJar {
+        CookieJar {
+            current: Batch::generate(),
+            previous: None,
+            rotated_at: Utc::now(),
+        }
+    }
+
+    /// Key new cookies are sealed with.
+    pub fn current(&self) -> &CookieKey {
+        &self.current.key
+    }
+
+    /// Open cookie with either of the keys. Cookie that was already spent is
+    /// rejected with `LlsdError::ReplayedFrame`.
+    pub fn open(&self,
+                cookie: &[u8],
+                client_pk: &PublicKey)
+                -> LlsdResult<(KeyPair, DateTime<Utc>)> {
+        for batch in Some(&self.current).into_iter().chain(self.previous.as_ref()) {
+            match batch.key.open(cookie, client_pk) {
+                Ok(_) if batch.spent.contains(client_pk) => return Err(LlsdError::ReplayedFrame),
+                Ok(opened) => return Ok(opened),
+                Err(_) => {}
+            }
+        }
+        Err(LlsdError::InvalidCookie)
+    }
+
+    /// Remember that cookie was used for a handshake, so it can't be used
+    /// again. Spent cookies are remembered until the key that sealed them is
+    /// rotated out: after that they can't be opened anyway. Fails with
+    /// `LlsdError::ReplayedFrame` if cookie was already spent.
+    pub fn spend(&mut self, cookie: &[u8], client_pk: &PublicKey) -> LlsdResult<()> {
+        for batch in Some(&mut self.current).into_iter().chain(self.previous.as_mut()) {
+            if batch.key.open(cookie, client_pk).is_ok() {
+                return if batch.spent.insert(*client_pk) {
+                           Ok(())
+                       } else {
+                           Err(LlsdError::ReplayedFrame)
+                       };
+            }
+        }
+        Err(LlsdError::InvalidCookie)
+    }
+
+    /// Check if current key was used for at least `period`.
+    pub fn is_older_than(&self, period: Duration) -> bool {
+        Utc::now().signed_duration_since(self.rotated_at) >= period
+    }
+
+    /// Make a new current key if current one was used for at least `period`.
+    /// If it was used for twice as long, previous key is too old to be kept
+    /// either. Returns true if keys were rotated.
+    pub fn rotate(&mut self, period: Duration) -> bool {
+        if !self.is_older_than(period) {
+            return false;
+        }
+        let current = ::std::mem::replace(&mut self.current, Batch::generate());
+        self.previous = if self.is_older_than(period * 2) {
+            None
+        } else {
+            Some(current)
+        };
+        self.rotated_at = Utc::now();
+        true
+    }
+
+    /// Pretend keys were rotated `by` earlier than they were.
+    #[cfg(test)]
+    pub fn age_by(&mut self, by: Duration) {
+        self.rotated_at = self.rotated_at - by;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto::box_::gen_keypair;
+
+    #[test]
+    fn seal_and_open() {
+        let key = CookieKey::generate();
+        let (client_pk, _) = gen_keypair();
+        let server_st = gen_keypair();
+        let cookie = key.seal(&client_pk, &server_st);
+        assert_eq!(cookie.len(), COOKIE_SIZE);
+        assert_eq!(key.open(&cookie, &client_pk).unwrap().0, server_st);
+
+        // Issue time is kept to the millisecond.
+        let issued_at = Utc::now() - Duration::minutes(5);
+        let cookie = key.seal_at(&client_pk, &server_st, issued_at);
+        let (_, opened_at) = key.open(&cookie, &client_pk).unwrap();
+        assert_eq!(opened_at.timestamp_millis(), issued_at.timestamp_millis());
+
+        // Cookie is bound to the client.
+        let (other_pk, _) = gen_keypair();
+        assert!(key.open(&cookie, &other_pk).is_err());
+        // And to the key.
+        assert!(CookieKey::generate().open(&cookie, &client_pk).is_err());
+        assert!(key.open(&cookie[1..], &client_pk).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let period = Duration::minutes(1);
+        let mut jar = CookieJar::new();
+        let (client_pk, _) = gen_keypair();
+        let cookie = jar.current().seal(&client_pk, &gen_keypair());
+
+        assert!(!jar.rotate(period));
+        assert!(jar.open(&cookie, &client_pk).is_ok());
+
+        // Previous key is still good.
+        jar.age_by(period);
+        assert!(jar.rotate(period));
+        assert!(jar.open(&cookie, &client_pk).is_ok());
+
+        jar.age_by(period);
+        assert!(jar.rotate(period));
+        assert!(jar.open(&cookie, &client_pk).is_err());
+
+        // Jar that sat around for too long forgets both keys at once.
+        let mut jar = CookieJar::new();
+        let cookie = jar.current().seal(&client_pk, &gen_keypair());
+        jar.age_by(period * 2);
+        assert!(jar.rotate(period));
+        assert!(jar.open(&cookie, &client_pk).is_err());
+    }
+
+    #[test]
+    fn spend() {
+        let period = Duration::minutes(1);
+        let mut jar = CookieJar::new();
+        let (client_pk, _) = gen_keypair();
+        let cookie = jar.current().seal(&client_pk, &gen_keypair());
+        let (other_pk, _) = gen_keypair();
+        let other = jar.current().seal(&other_pk, &gen_keypair());
+
+        jar.spend(&cookie, &client_pk).unwrap();
+        match jar.open(&cookie, &client_pk) {
+            Err(LlsdError::ReplayedFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match jar.spend(&cookie, &client_pk) {
+            Err(LlsdError::ReplayedFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        assert!(jar.open(&other, &other_pk).is_ok());
+
+        // Spent cookie stays spent while its key is kept as previous one.
+        jar.age_by(period);
+        assert!(jar.rotate(period));
+        assert!(jar.open(&cookie, &client_pk).is_err());
+        jar.spend(&other, &other_pk).unwrap();
+        assert!(jar.open(&other, &other_pk).is_err());
+        match jar.spend(&cookie[1..], &client_pk) {
+            Err(LlsdError::InvalidCookie) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
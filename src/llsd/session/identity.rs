@@ -0,0 +1,47 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Who is on the other side of the session. Server gets it from the
+/// authenticator during handshake and keeps it with the session, so handlers
+/// can make authorization decisions.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Identity {
+    /// Application's id of the client. `None` for anonymous clients that are
+    /// only known by key.
+    pub user_id: Option<String>,
+    /// Roles client has.
+    pub roles: BTreeSet<String>,
+    /// Anything else authenticator wants handlers to know.
+    pub claims: BTreeMap<String, String>,
+}
+
+impl Identity {
+    /// Identity with user id and nothing else.
+    pub fn new<U: Into<String>>(user_id: U) -> Identity {
+        Identity {
+            user_id: Some(user_id.into()),
+            ..Identity::default()
+        }
+    }
+
+    /// Add a role.
+    pub fn with_role<R: Into<String>>(mut self, role: R) -> Identity {
+        self.roles.insert(role.into());
+        self
+    }
+
+    /// Add a claim. Claim with the same name is replaced.
+    pub fn with_claim<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Identity {
+        self.claims.insert(name.into(), value.into());
+        self
+    }
+
+    /// Check if client has a role.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Value of a claim, if there is one.
+    pub fn claim(&self, name: &str) -> Option<&str> {
+        self.claims.get(name).map(|value| value.as_str())
+    }
+}
@@ -0,0 +1,155 @@
+use super::KeyPair;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::slice::Iter;
+
+/// Server's long-term key pairs. First one is current, the rest are retiring:
+/// they are only there for clients that don't know about current one yet.
+/// Lets server rotate its key without a flag day.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Vec<KeyPair>,
+}
+
+impl KeyRing {
+    /// Ring with a single key.
+    pub fn new(current: KeyPair) -> KeyRing {
+        KeyRing { keys: vec![current] }
+    }
+
+    /// Key that clients should be using.
+    pub fn current(&self) -> &KeyPair {
+        &self.keys[0]
+    }
+
+    /// Public keys of every key in the ring, current first.
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys.iter().map(|&(pk, _)| pk).collect()
+    }
+
+    /// Every key in the ring, current first.
+    pub fn iter(&self) -> Iter<KeyPair> {
+        self.keys.iter()
+    }
+
+    /// Make `next` current key. Key that was current until now keeps working
+    /// until it's retired.
+    pub fn rotate(&mut self, next: KeyPair) {
+        self.keys.retain(|&(pk, _)| pk != next.0);
+        self.keys.insert(0, next);
+    }
+
+    /// Forget retiring key. Current key can't be retired, rotate it first.
+    /// Returns true if key was in the ring.
+    pub fn retire(&mut self, pk: &PublicKey) -> bool {
+        match self.keys.iter().skip(1).position(|&(ref key, _)| key == pk) {
+            Some(index) => {
+                self.keys.remove(index + 1);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl From<KeyPair> for KeyRing {
+    fn from(current: KeyPair) -> KeyRing {
+        KeyRing::new(current)
+    }
+}
+
+/// Server long-term public keys client trusts, most preferred first. Client
+/// seals Hello to preferred key and falls back to the rest if server doesn't
+/// know it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl ServerKeys {
+    /// Trust a single key.
+    pub fn new(preferred: PublicKey) -> ServerKeys {
+        ServerKeys { keys: vec![preferred] }
+    }
+
+    /// Trust one more key, less preferred than those added before.
+    pub fn with_fallback(mut self, pk: PublicKey) -> ServerKeys {
+        if !self.keys.contains(&pk) {
+            self.keys.push(pk);
+        }
+        self
+    }
+
+    /// Key client should try first.
+    pub fn preferred(&self) -> PublicKey {
+        self.keys[0]
+    }
+
+    /// Key at given position, if there are that many.
+    pub fn get(&self, index: usize) -> Option<PublicKey> {
+        self.keys.get(index).cloned()
+    }
+
+    /// Same keys, but the one at given position is preferred. Rest keep
+    /// their order and are still there to fall back to.
+    pub fn preferring(&self, index: usize) -> ServerKeys {
+        let mut keys = self.keys.clone();
+        if index < keys.len() {
+            let preferred = keys.remove(index);
+            keys.insert(0, preferred);
+        }
+        ServerKeys { keys: keys }
+    }
+}
+
+impl From<PublicKey> for ServerKeys {
+    fn from(preferred: PublicKey) -> ServerKeys {
+        ServerKeys::new(preferred)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto::box_::gen_keypair;
+
+    #[test]
+    fn rotate_and_retire() {
+        let old = gen_keypair();
+        let new = gen_keypair();
+        let mut ring = KeyRing::new(old.clone());
+
+        ring.rotate(new.clone());
+        assert_eq!(ring.current(), &new);
+        assert_eq!(ring.public_keys(), vec![new.0, old.0]);
+
+        // Current key stays no matter what.
+        assert!(!ring.retire(&new.0));
+        assert!(ring.retire(&old.0));
+        assert!(!ring.retire(&old.0));
+        assert_eq!(ring.public_keys(), vec![new.0]);
+
+        // Rotating back to a known key doesn't duplicate it.
+        ring.rotate(old.clone());
+        ring.rotate(new.clone());
+        assert_eq!(ring.public_keys(), vec![new.0, old.0]);
+    }
+
+    #[test]
+    fn server_keys() {
+        let (first, _) = gen_keypair();
+        let (second, _) = gen_keypair();
+        let keys = ServerKeys::new(first)
+            .with_fallback(second)
+            .with_fallback(first);
+        assert_eq!(keys.preferred(), first);
+        assert_eq!(keys.get(1), Some(second));
+        assert_eq!(keys.get(2), None);
+
+        let (third, _) = gen_keypair();
+        let keys = keys.with_fallback(third).preferring(1);
+        assert_eq!(keys.preferred(), second);
+        assert_eq!(keys.get(1), Some(first));
+        assert_eq!(keys.get(2), Some(third));
+        assert_eq!(keys.preferring(3), keys);
+    }
+}
@@ -0,0 +1,40 @@
+use super::KeyPair;
+use super::client::Session as ClientSession;
+use super::cookie::CookieJar;
+use super::server::{Session as ServerSession, make_welcome};
+use crate::llsd::errors::LlsdResult;
+use crate::llsd::frames::Frame;
+
+/// Server side of the handshake up to the session made from the cookie.
+/// Answers `hello` with Welcome, lets `initiate` turn Welcome into Initiate
+/// and returns server session together with that Initiate. Initiate is not
+/// validated.
+pub fn cookie_exchange<F>(server_lt: &KeyPair,
+                          hello: &Frame,
+                          initiate: F)
+                          -> LlsdResult<(ServerSession, Frame)>
+where
+    F: FnOnce(&Frame) -> LlsdResult<Frame>,
+{
+    let cookies = CookieJar::new();
+    let welcome = make_welcome(hello, &server_lt.clone().into(), &cookies)?;
+    let initiate = initiate(&welcome)?;
+    let session = ServerSession::from_cookie(&initiate, &cookies, Default::default())?;
+    Ok((session, initiate))
+}
+
+/// Play whole handshake between `client` and server with `server_lt` keys.
+/// Leaves `client` ready and returns server side of the session with the
+/// Ready frame it sent.
+pub fn handshake(client: &mut ClientSession,
+                 server_lt: &KeyPair)
+                 -> LlsdResult<(ServerSession, Frame)> {
+    let hello = client.make_hello();
+    let (mut server, initiate) = cookie_exchange(server_lt, &hello, |welcome| {
+        client.make_initiate(welcome)
+    })?;
+    let client_lt_pk = server.validate_initiate(&initiate)?;
+    let ready = server.make_ready(&initiate, &client_lt_pk)?;
+    client.read_ready(&ready)?;
+    Ok((server, ready))
+}
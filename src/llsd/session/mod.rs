@@ -1,21 +1,130 @@
 
 
 use bytes::{Bytes, BytesMut};
-use llsd::errors::{LlsdError, LlsdResult};
-use llsd::frames::{Frame, FrameKind};
-use sodiumoxide::crypto::box_::{Nonce, PublicKey, SecretKey};
+use chrono::{DateTime, Duration};
+use chrono::offset::Utc;
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::frames::{Frame, FrameKind, Termination};
+use sodiumoxide::crypto::box_::{MACBYTES, NONCEBYTES, Nonce, PUBLICKEYBYTES, PublicKey, SecretKey};
+use self::cookie::COOKIE_SIZE;
+use std::fmt;
+use std::sync::Mutex;
 /// Things that are required to build a client.
 pub mod client;
 /// Things that are required to build a server.
 pub mod server;
+/// Counter based nonces and replay protection for messages.
+pub mod nonce;
+/// Cookies that let server keep no state until handshake is complete.
+pub mod cookie;
+/// Long-term keys of the server: ones it has and ones client trusts.
+pub mod keyring;
+/// Who the client is, as far as server is concerned.
+pub mod identity;
+/// Both sides of the handshake played in memory, for tests and benches.
+pub mod loopback;
 /// Just an alias...
 pub type KeyPair = (PublicKey, SecretKey);
 
 /// Array of null bytes used in Hello package. Needs to be bigger than Welcome
-/// frame to prevent
+/// frame (short-term key and cookie) to prevent
 /// amplification attacks.
 pub static NULL_BYTES: [u8; 256] = [b'\x00'; 256];
 
+/// Size of vouch: nonce and a box with client's short-term public key.
+pub const VOUCH_SIZE: usize = NONCEBYTES + MACBYTES + PUBLICKEYBYTES;
+
+/// Size of what client puts in Initiate box: its long-term public key and
+/// vouch.
+pub const INITIATE_CONTENT_SIZE: usize = PUBLICKEYBYTES + VOUCH_SIZE;
+
+/// Size of Initiate payload: cookie followed by the box.
+pub const INITIATE_PAYLOAD_SIZE: usize = COOKIE_SIZE + MACBYTES + INITIATE_CONTENT_SIZE;
+
+/// Size of what server puts in Welcome box: its short-term public key and
+/// cookie.
+pub const WELCOME_CONTENT_SIZE: usize = PUBLICKEYBYTES + COOKIE_SIZE;
+
+/// Lifetimes of a session. Same thing is used by both client and server, but
+/// each side enforces it on its own, so they better match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionConfig {
+    /// How long session lives after it was created, no matter how active it
+    /// is.
+    pub ttl: Duration,
+    /// How long client has to complete the handshake after Hello.
+    pub handshake_timeout: Duration,
+    /// How long session can go without receiving a message before it is
+    /// considered dead. `None` means it can idle until it expires.
+    pub max_idle: Option<Duration>,
+    /// Client starts a new session this long before current one expires, so
+    /// requests never race with expiration. Server doesn't use it.
+    pub renew_before: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            ttl: Duration::minutes(34),
+            handshake_timeout: Duration::minutes(3),
+            max_idle: None,
+            renew_before: Duration::minutes(1),
+        }
+    }
+}
+
+// Time of the last message session received. Updated from `read_msg`, which
+// only has `&self`.
+struct Activity {
+    at: Mutex<DateTime<Utc>>,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity { at: Mutex::new(Utc::now()) }
+    }
+
+    fn touch(&self) {
+        *self.at.lock().expect("Lock was poisoned") = Utc::now();
+    }
+
+    fn last(&self) -> DateTime<Utc> {
+        *self.at.lock().expect("Lock was poisoned")
+    }
+
+    // Check if session was idle for longer than allowed.
+    fn idle_for_too_long(&self, max_idle: Option<Duration>) -> bool {
+        match max_idle {
+            Some(max_idle) => Utc::now().signed_duration_since(self.last()) > max_idle,
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    fn age_by(&self, by: Duration) {
+        let mut at = self.at.lock().expect("Lock was poisoned");
+        *at = *at - by;
+    }
+}
+
+impl Clone for Activity {
+    fn clone(&self) -> Activity {
+        Activity { at: Mutex::new(self.last()) }
+    }
+}
+
+impl PartialEq for Activity {
+    fn eq(&self, other: &Activity) -> bool {
+        self.last() == other.last()
+    }
+}
+
+impl fmt::Debug for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Activity({})", self.last())
+    }
+}
+
 /// Session has three states. Each state means different thing on client and
 /// server. For example,
 /// on client Fresh state means that client has send Hello frame to server. On
@@ -62,14 +171,33 @@ pub trait Sendable {
         };
         Ok(frame)
     }
+
+    /// Helper to send a Termination Frame. Encrypted if session is ready,
+    /// plain text otherwise.
+    fn make_termination(&self, termination: &Termination) -> Frame {
+        if !self.can_send() {
+            return termination.to_plain_frame(self.id());
+        }
+        let (nonce, payload) = self.seal_msg(&termination.to_bytes());
+        Frame {
+            id: self.id(),
+            nonce: nonce,
+            kind: FrameKind::Termination,
+            payload: payload,
+        }
+    }
 }
 #[cfg(test)]
 mod test {
-    use super::Sendable;
+    use super::{Sendable, SessionConfig};
     use super::client::Session as ClientSession;
-    use super::server::Session as ServerSession;
+    use super::cookie::{COOKIE_SIZE, CookieJar};
+    use super::loopback::handshake;
+    use super::server::{ReapReason, Session as ServerSession, make_welcome};
+    use chrono::Duration;
+    use chrono::offset::Utc;
 
-    use llsd::frames::{Frame, FrameKind};
+    use crate::llsd::errors::LlsdError;
     use sodiumoxide::crypto::box_::gen_keypair;
 
     #[test]
@@ -83,21 +211,44 @@ mod test {
         assert!(err.is_err());
     }
     #[test]
+    fn test_cant_read_if_not_ready() {
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let client_session = ClientSession::new(server_lt.0.clone(), client_lt.clone());
+        let server_session = ServerSession::new(client_session.id());
+        let hello_frame = client_session.make_hello();
+
+        match server_session.read_msg(&hello_frame) {
+            Err(LlsdError::InvalidSessionState) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match client_session.read_msg(&hello_frame) {
+            Err(LlsdError::InvalidSessionState) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+    #[test]
     fn test_successful_hashshake() {
         let client_lt = gen_keypair();
         let server_lt = gen_keypair();
 
         let mut client_session = ClientSession::new(server_lt.0.clone(), client_lt.clone());
-        let mut server_session = ServerSession::new(client_session.id());
+        let cookies = CookieJar::new();
 
         let hello_frame = client_session.make_hello();
 
-        let welcome_frame = server_session
-            .make_welcome(&hello_frame, &server_lt.1).unwrap();
+        let welcome_frame = make_welcome(&hello_frame, &server_lt.clone().into(), &cookies)
+            .unwrap();
 
         let initiate_frame = client_session
             .make_initiate(&welcome_frame).unwrap();
 
+        // Server knows nothing about the session until now.
+        let mut server_session =
+            ServerSession::from_cookie(&initiate_frame, &cookies, SessionConfig::default())
+                .unwrap();
+
         assert!(!client_session.can_send());
         assert!(!server_session.can_send());
         let client_lt_pk = server_session
@@ -126,4 +277,96 @@ mod test {
         let from_server_to_client_read = client_session.read_msg(&from_server_to_client).unwrap();
         assert_eq!(&from_server_to_client_read.as_ref(), b"I'm the hyper star");
     }
+
+    #[test]
+    fn test_replay_and_reflection() {
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let mut client_session = ClientSession::new(server_lt.0.clone(), client_lt.clone());
+        let (server_session, ready_frame) = handshake(&mut client_session, &server_lt).unwrap();
+
+        // Replaying Ready frame is not allowed either
+        match client_session.read_msg(&ready_frame) {
+            Err(LlsdError::ReplayedFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        let first = client_session.make_message(b"first").unwrap();
+        let second = client_session.make_message(b"second").unwrap();
+        assert!(first.nonce != second.nonce);
+
+        // Out of order delivery is fine
+        assert!(server_session.read_msg(&second).is_ok());
+        assert!(server_session.read_msg(&first).is_ok());
+        match server_session.read_msg(&first) {
+            Err(LlsdError::ReplayedFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        // Server's own frame can't be bounced back to it
+        let from_server = server_session.make_message(b"echo").unwrap();
+        match server_session.read_msg(&from_server) {
+            Err(LlsdError::BadFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        assert!(client_session.read_msg(&from_server).is_ok());
+    }
+
+    #[test]
+    fn test_stale_cookie() {
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let mut client_session = ClientSession::new(server_lt.0.clone(), client_lt.clone());
+        let cookies = CookieJar::new();
+        let hello_frame = client_session.make_hello();
+        let welcome_frame = make_welcome(&hello_frame, &server_lt.clone().into(), &cookies)
+            .unwrap();
+        let mut initiate_frame = client_session
+            .make_initiate(&welcome_frame).unwrap();
+
+        // Same cookie, but issued longer ago than handshake may take.
+        let (server_st, _) = cookies.open(&initiate_frame.payload[..COOKIE_SIZE],
+                                          &initiate_frame.id)
+            .unwrap();
+        let stale = cookies.current()
+            .seal_at(&initiate_frame.id,
+                     &server_st,
+                     Utc::now() - Duration::minutes(5));
+        let mut payload = stale;
+        payload.extend_from_slice(&initiate_frame.payload[COOKIE_SIZE..]);
+        initiate_frame.payload = payload.into();
+
+        match ServerSession::from_cookie(&initiate_frame, &cookies, SessionConfig::default()) {
+            Err(LlsdError::ExpiredSession) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn test_session_config() {
+        let (client_pk, _) = gen_keypair();
+        let config = SessionConfig {
+            ttl: Duration::minutes(10),
+            handshake_timeout: Duration::seconds(30),
+            max_idle: Some(Duration::minutes(1)),
+            renew_before: Duration::seconds(30),
+        };
+
+        let mut abandoned = ServerSession::with_config(client_pk, config);
+        assert!(abandoned.is_valid());
+        assert_eq!(abandoned.reap_reason(), None);
+        abandoned.age_by(Duration::seconds(45));
+        assert_eq!(abandoned.reap_reason(), Some(ReapReason::Abandoned));
+
+        let mut idle = ServerSession::with_config(client_pk, config);
+        idle.age_by(Duration::minutes(2));
+        assert!(!idle.is_valid());
+        assert_eq!(idle.reap_reason(), Some(ReapReason::Idle));
+
+        let mut expired = ServerSession::with_config(client_pk, config);
+        expired.age_by(Duration::minutes(11));
+        assert_eq!(expired.reap_reason(), Some(ReapReason::Expired));
+    }
 }
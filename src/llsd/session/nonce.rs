@@ -0,0 +1,156 @@
+use crate::llsd::POISONED_LOCK_MSG;
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use byteorder::{BigEndian, ByteOrder};
+use sodiumoxide::crypto::box_::Nonce;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Prefix of nonces used for messages sent by client. Different prefix for
+/// each direction means frame can't be reflected back to its sender.
+pub const CLIENT_MSG_PREFIX: &'static [u8; 16] = b"CurveCP-client-M";
+/// Prefix of nonces used for messages sent by server.
+pub const SERVER_MSG_PREFIX: &'static [u8; 16] = b"CurveCP-server-M";
+/// How far behind the highest seen counter a frame can be and still be
+/// accepted. Frames can arrive out of order with multiplexed transport.
+pub const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    /// Counter for the next frame we send.
+    next: u64,
+    /// Highest counter we've accepted from the other side.
+    highest: u64,
+    /// Bit N is set if `highest - N` was accepted.
+    seen: u64,
+}
+
+/// Per session nonce bookkeeping. Nonce is 16 bytes of direction prefix
+/// followed by 8 bytes of counter (big endian). Sending side increments
+/// counter, receiving side keeps sliding window of counters it has seen.
+pub struct Nonces {
+    state: Mutex<State>,
+}
+
+impl Nonces {
+    /// Fresh state. First frame is sent with counter 1.
+    pub fn new() -> Nonces {
+        Nonces {
+            state: Mutex::new(State {
+                                  next: 1,
+                                  highest: 0,
+                                  seen: 0,
+                              }),
+        }
+    }
+
+    /// Make nonce for the next outgoing frame.
+    pub fn next(&self, prefix: &[u8; 16]) -> Nonce {
+        let mut state = self.state.lock().expect(POISONED_LOCK_MSG);
+        let counter = state.next;
+        state.next += 1;
+        let mut nonce = [0u8; 24];
+        nonce[0..16].copy_from_slice(prefix);
+        BigEndian::write_u64(&mut nonce[16..24], counter);
+        Nonce(nonce)
+    }
+
+    /// Extract counter from incoming nonce. Doesn't check the window, so it's
+    /// safe to call before frame is authenticated. Nonce made for the other
+    /// direction is rejected with `LlsdError::BadFrame`.
+    pub fn counter(prefix: &[u8; 16], nonce: &Nonce) -> LlsdResult<u64> {
+        if &nonce.0[0..16] != &prefix[..] {
+            return Err(LlsdError::BadFrame);
+        }
+        Ok(BigEndian::read_u64(&nonce.0[16..24]))
+    }
+
+    /// Record counter of successfully decrypted frame. Fails if frame with
+    /// this counter was already accepted or if it's too old to tell.
+    pub fn accept(&self, counter: u64) -> LlsdResult<()> {
+        let mut state = self.state.lock().expect(POISONED_LOCK_MSG);
+        if counter > state.highest {
+            let shift = counter - state.highest;
+            state.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                state.seen << shift
+            };
+            state.seen |= 1;
+            state.highest = counter;
+            return Ok(());
+        }
+        let age = state.highest - counter;
+        if age >= REPLAY_WINDOW || counter == 0 {
+            return Err(LlsdError::ReplayedFrame);
+        }
+        let bit = 1u64 << age;
+        if state.seen & bit != 0 {
+            return Err(LlsdError::ReplayedFrame);
+        }
+        state.seen |= bit;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> State {
+        self.state.lock().expect(POISONED_LOCK_MSG).clone()
+    }
+}
+
+impl Clone for Nonces {
+    fn clone(&self) -> Nonces {
+        Nonces { state: Mutex::new(self.snapshot()) }
+    }
+}
+
+impl PartialEq for Nonces {
+    fn eq(&self, other: &Nonces) -> bool {
+        self.snapshot() == other.snapshot()
+    }
+}
+
+impl fmt::Debug for Nonces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.snapshot();
+        f.debug_struct("Nonces")
+            .field("next", &state.next)
+            .field("highest", &state.highest)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counter_increments() {
+        let nonces = Nonces::new();
+        let first = nonces.next(CLIENT_MSG_PREFIX);
+        let second = nonces.next(CLIENT_MSG_PREFIX);
+
+        assert_eq!(Nonces::counter(CLIENT_MSG_PREFIX, &first).unwrap(), 1);
+        assert_eq!(Nonces::counter(CLIENT_MSG_PREFIX, &second).unwrap(), 2);
+        match Nonces::counter(SERVER_MSG_PREFIX, &first) {
+            Err(LlsdError::BadFrame) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn window() {
+        let nonces = Nonces::new();
+
+        assert!(nonces.accept(1).is_ok());
+        assert!(nonces.accept(1).is_err());
+        // Out of order is fine as long as it's inside the window
+        assert!(nonces.accept(5).is_ok());
+        assert!(nonces.accept(3).is_ok());
+        assert!(nonces.accept(3).is_err());
+        assert!(nonces.accept(5).is_err());
+
+        assert!(nonces.accept(5 + REPLAY_WINDOW).is_ok());
+        assert!(nonces.accept(5).is_err());
+        assert!(nonces.accept(6).is_ok());
+        assert!(nonces.accept(0).is_err());
+    }
+}
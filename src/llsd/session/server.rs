@@ -1,15 +1,80 @@
 
 
-use super::{Sendable, SessionState};
+use super::{Activity, INITIATE_CONTENT_SIZE, INITIATE_PAYLOAD_SIZE, KeyPair, NULL_BYTES, Sendable,
+            SessionConfig, SessionState, WELCOME_CONTENT_SIZE};
+use super::cookie::{COOKIE_SIZE, CookieJar};
+use super::identity::Identity;
+use super::keyring::KeyRing;
+use super::nonce::{CLIENT_MSG_PREFIX, Nonces, SERVER_MSG_PREFIX};
 use bytes::{Bytes, BytesMut};
-use chrono::{DateTime, Duration};
+use chrono::DateTime;
 use chrono::offset::Utc;
-use llsd::errors::{LlsdError, LlsdResult};
-use llsd::frames::{Frame, FrameKind};
-use sodiumoxide::crypto::box_::{Nonce, PublicKey, SecretKey, gen_keypair, gen_nonce, open, seal};
+use crate::llsd::errors::{LlsdError, LlsdResult};
+use crate::llsd::frames::{AppError, Frame, FrameKind, Termination, ok_reply};
+use sodiumoxide::crypto::box_::{NONCEBYTES, Nonce, PUBLICKEYBYTES, PrecomputedKey, PublicKey,
+                                 SECRETKEYBYTES, SecretKey, gen_keypair, gen_nonce, open,
+                                 open_precomputed, precompute, seal, seal_precomputed};
 
 const READY_PAYLOAD: &'static [u8; 16] = b"My body is ready";
 
+/// Reason session is no longer useful and should be erased.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReapReason {
+    /// Session lived past its expiration time.
+    Expired,
+    /// Session didn't receive any messages for too long.
+    Idle,
+    /// Session ended up in Error state.
+    Errored,
+    /// Session never completed the handshake in time. `AngelSystem` only
+    /// stores sessions once Initiate is validated, so it never reaps these
+    /// itself.
+    Abandoned,
+}
+
+
+/// Helper to make a Welcome frame, a reply to Hello frame. Server worflow.
+/// Server's short-term keys are generated here and travel inside of the
+/// cookie, so nothing has to be stored until client sends Initiate. Hello is
+/// opened with whichever key from the ring client sealed it to, and Welcome
+/// is sealed with the same one.
+pub fn make_welcome(hello: &Frame, keys: &KeyRing, cookies: &CookieJar) -> LlsdResult<Frame> {
+    if hello.kind != FrameKind::Hello {
+        return Err(LlsdError::InvalidHelloFrame);
+    }
+    // Verify content of the box
+    let opened = keys.iter()
+        .filter_map(|&(_, ref sk)| {
+                        open(&hello.payload, &hello.nonce, &hello.id, sk)
+                            .ok()
+                            .map(|payload| (sk, payload))
+                    })
+        .next();
+    // Client either sent garbage or trusts a key we don't have.
+    let (our_sk, payload) = match opened {
+        Some(opened) => opened,
+        None => return Err(LlsdError::HandshakeFailed),
+    };
+    // We're not going to verify that box content itself, but will verify it's
+    // length since that is what matters the most.
+    if payload.len() != NULL_BYTES.len() {
+        return Err(LlsdError::InvalidHelloFrame);
+    }
+    let st = gen_keypair();
+    let mut welcome_content = Vec::with_capacity(WELCOME_CONTENT_SIZE);
+    welcome_content.extend_from_slice(&(st.0).0);
+    welcome_content.extend(cookies.current().seal(&hello.id, &st));
+
+    let nonce = gen_nonce();
+    let welcome_box = seal(&welcome_content, &nonce, &hello.id, our_sk);
+    Ok(Frame {
+           // Server uses client id in reply.
+           id: hello.id,
+           nonce: nonce,
+           kind: FrameKind::Welcome,
+           payload: welcome_box.into(),
+       })
+}
 
 #[derive(Debug, Clone, PartialEq)]
 /// Server side session.
@@ -24,7 +89,15 @@ pub struct Session {
     /// This key should be know once session transitions to Ready state.
     client_pk: PublicKey,
     client_lt_pk: Option<PublicKey>,
+    /// What authenticator said about the client.
+    identity: Option<Identity>,
     state: SessionState,
+    /// Shared key for messages. Computed once, when session becomes ready.
+    shared: Option<PrecomputedKey>,
+    /// Message nonces for both directions.
+    nonces: Nonces,
+    config: SessionConfig,
+    activity: Activity,
 }
 
 impl Session {
@@ -32,79 +105,174 @@ impl Session {
     /// key. Server
     /// long-term pair stored in session manager or else where.
     pub fn new(client_pk: PublicKey) -> Session {
+        Session::with_config(client_pk, SessionConfig::default())
+    }
+
+    /// Same as `new`, but with custom lifetimes.
+    pub fn with_config(client_pk: PublicKey, config: SessionConfig) -> Session {
+        Session::with_keys(client_pk, gen_keypair(), config)
+    }
+
+    /// Recreate session from the cookie client echoed back in Initiate frame.
+    /// Session is not ready until Initiate is validated and Ready is sent.
+    /// Handshake is counted from the moment cookie was issued, so cookie
+    /// older than handshake timeout is rejected with
+    /// `LlsdError::ExpiredSession`.
+    pub fn from_cookie(initiate: &Frame,
+                       cookies: &CookieJar,
+                       config: SessionConfig)
+                       -> LlsdResult<Session> {
+        if initiate.kind != FrameKind::Initiate || initiate.payload.len() != INITIATE_PAYLOAD_SIZE {
+            return Err(LlsdError::InvalidInitiateFrame);
+        }
+        let (st, issued_at) = cookies.open(&initiate.payload[..COOKIE_SIZE], &initiate.id)?;
+        let mut session = Session::with_keys(initiate.id, st, config);
+        session.created_at = issued_at;
+        if session.handshake_expired() {
+            return Err(LlsdError::ExpiredSession);
+        }
+        Ok(session)
+    }
+
+    fn with_keys(client_pk: PublicKey, st: KeyPair, config: SessionConfig) -> Session {
         Session {
-            expire_at: Utc::now() + Duration::minutes(34),
+            expire_at: Utc::now() + config.ttl,
             created_at: Utc::now(),
             state: SessionState::Fresh,
-            st: gen_keypair(),
+            st: st,
             client_pk: client_pk,
             client_lt_pk: None,
+            identity: None,
+            shared: None,
+            nonces: Nonces::new(),
+            config: config,
+            activity: Activity::new(),
         }
     }
     /// Verify that session is not expired
     pub fn is_valid(&self) -> bool {
-        self.expire_at > Utc::now()
+        self.expire_at > Utc::now() && !self.is_idle()
     }
 
-    /// Helper to make a Welcome frame, a reply to Hello frame. Server worflow.
-    pub fn make_welcome(&mut self, hello: &Frame, our_sk: &SecretKey) -> LlsdResult<Frame> {
-        if self.state != SessionState::Fresh || hello.kind != FrameKind::Hello {
-            return Err(LlsdError::InvalidSessionState);
-        }
-        // Verify content of the box
-        if let Ok(payload) = open(&hello.payload, &hello.nonce, &hello.id, our_sk) {
-            // We're not going to verify that box content itself, but will verify it's
-            // length since
-            // that is what matters the most.
-            if payload.len() != 256 {
-                self.state = SessionState::Error;
-                return Err(LlsdError::InvalidHelloFrame);
-            }
-            let nonce = gen_nonce();
-            let welcome_box = seal(self.st.0.as_ref(), &nonce, &hello.id, our_sk);
-
-            let welcome_frame = Frame {
-                // Server uses client id in reply.
-                id: hello.id,
-                nonce: nonce,
-                kind: FrameKind::Welcome,
-                payload: welcome_box.into(),
-            };
-            Ok(welcome_frame)
+    /// Check if session went without messages for longer than allowed.
+    pub fn is_idle(&self) -> bool {
+        self.activity.idle_for_too_long(self.config.max_idle)
+    }
+
+    /// Current state of the session.
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    /// Client's long-term public key. Known once Initiate is accepted.
+    pub fn client_public_key(&self) -> Option<PublicKey> {
+        self.client_lt_pk
+    }
+
+    /// Who the client is, according to authenticator. Known once handshake
+    /// is complete.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Remember who the client is. Done by server right after client is
+    /// authenticated.
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = Some(identity);
+    }
+
+    /// Tell if session should be erased and why. `None` means session is
+    /// still in use.
+    pub fn reap_reason(&self) -> Option<ReapReason> {
+        if self.state == SessionState::Error {
+            Some(ReapReason::Errored)
+        } else if self.expire_at <= Utc::now() {
+            Some(ReapReason::Expired)
+        } else if self.is_idle() {
+            Some(ReapReason::Idle)
+        } else if self.state == SessionState::Fresh && self.handshake_expired() {
+            Some(ReapReason::Abandoned)
         } else {
-            self.state = SessionState::Error;
-            Err(LlsdError::DecryptionFailed)
+            None
         }
     }
+
+    /// Erase short-term secret and shared keys and put session into Error
+    /// state. Anyone still holding on to this session won't be able to use
+    /// it. Keys are zeroed when they are dropped.
+    pub fn destroy(&mut self) {
+        self.state = SessionState::Error;
+        self.shared = None;
+        self.st.1 = SecretKey([0; SECRETKEYBYTES]);
+    }
+
+    /// Pretend session was created `by` earlier than it was. Lets tests see
+    /// expired sessions without waiting.
+    #[cfg(test)]
+    pub fn age_by(&mut self, by: ::chrono::Duration) {
+        self.created_at = self.created_at - by;
+        self.expire_at = self.expire_at - by;
+        self.activity.age_by(by);
+    }
+
+    fn handshake_expired(&self) -> bool {
+        let duration_since = Utc::now().signed_duration_since(self.created_at);
+        duration_since > self.config.handshake_timeout
+    }
+
     /// A helper to extract client's permamanet public key from initiate frame
     /// in order to
     /// authenticate client. Authentication happens in another place.
     pub fn validate_initiate(&self, initiate: &Frame) -> LlsdResult<PublicKey> {
-        if let Ok(initiate_payload) =
-            open(&initiate.payload,
-                 &initiate.nonce,
-                 &self.client_pk,
-                 &self.st.1)
-        {
-            // TODO: change to != with proper size
-            if initiate_payload.len() < 60 {
-                return Err(LlsdError::InvalidInitiateFrame);
-            }
-            // unwrapping here because they only panic when input is shorter than needed.
-            let pk = PublicKey::from_slice(&initiate_payload[0..32])
-                .expect("Failed to slice pk from payload");
-            let v_nonce = Nonce::from_slice(&initiate_payload[32..56])
-                .expect("Failed to slice nonce from payload");
-            let v_box = &initiate_payload[56..initiate_payload.len()];
-
-            if let Ok(vouch_payload) = open(v_box, &v_nonce, &pk, &self.st.1) {
-                let v_pk = PublicKey::from_slice(&vouch_payload).expect("Wrong Size Key!!!");
-                if vouch_payload.len() == 32 || v_pk == self.client_pk {
-                    return Ok(pk);
-                }
-            }
+        if initiate.kind != FrameKind::Initiate || initiate.payload.len() != INITIATE_PAYLOAD_SIZE {
+            return Err(LlsdError::InvalidInitiateFrame);
         }
-        Err(LlsdError::InvalidInitiateFrame)
+        let initiate_payload = match open(&initiate.payload[COOKIE_SIZE..],
+                                          &initiate.nonce,
+                                          &self.client_pk,
+                                          &self.st.1) {
+            Ok(payload) => payload,
+            Err(_) => return Err(LlsdError::DecryptionFailed),
+        };
+        if initiate_payload.len() != INITIATE_CONTENT_SIZE {
+            return Err(LlsdError::InvalidInitiateFrame);
+        }
+        let (pk, vouch) = initiate_payload.split_at(PUBLICKEYBYTES);
+        let (v_nonce, v_box) = vouch.split_at(NONCEBYTES);
+        let pk = PublicKey::from_slice(pk).ok_or(LlsdError::InvalidInitiateFrame)?;
+        let v_nonce = Nonce::from_slice(v_nonce).ok_or(LlsdError::InvalidInitiateFrame)?;
+
+        let vouch_payload = match open(v_box, &v_nonce, &pk, &self.st.1) {
+            Ok(payload) => payload,
+            Err(_) => return Err(LlsdError::InvalidInitiateFrame),
+        };
+        // Vouch has to be made by owner of the long-term key for this very
+        // session, otherwise anybody who knows the long-term public key could
+        // pretend to be its owner.
+        match PublicKey::from_slice(&vouch_payload) {
+            Some(v_pk) if v_pk == self.client_pk => Ok(pk),
+            _ => Err(LlsdError::InvalidInitiateFrame),
+        }
+    }
+
+    /// Read Termination frame sent by client. Only accepted if it's
+    /// encrypted with this session, so nobody else can close it.
+    pub fn read_termination(&self, frame: &Frame) -> LlsdResult<Termination> {
+        if frame.kind != FrameKind::Termination {
+            return Err(LlsdError::BadFrame);
+        }
+        let payload = self.read_msg(frame)?;
+        Termination::from_slice(&payload)
+    }
+
+    /// Helper to make a reply Message frame: response if request succeeded,
+    /// error it failed with otherwise. Server workflow.
+    pub fn make_reply(&self, reply: Result<&[u8], &AppError>) -> LlsdResult<Frame> {
+        let payload = match reply {
+            Ok(response) => ok_reply(response),
+            Err(err) => err.to_reply(),
+        };
+        self.make_message(&payload)
     }
 
     /// Helper to make a Ready frame, a reply to Initiate frame. Server
@@ -114,13 +282,13 @@ impl Session {
             return Err(LlsdError::InvalidSessionState);
         }
 
-        // If client spend more than 3 minutes to come up with initiate - fuck him.
-        let duration_since = Utc::now().signed_duration_since(self.created_at);
-        if duration_since > Duration::minutes(3) {
+        // If client spend too much time to come up with initiate - fuck him.
+        if self.handshake_expired() {
             return Err(LlsdError::ExpiredSession);
         }
         self.state = SessionState::Ready;
         self.client_lt_pk = Some(*client_lt_pk);
+        self.shared = Some(precompute(&self.client_pk, &self.st.1));
         let (nonce, payload) = self.seal_msg(READY_PAYLOAD);
         let frame = Frame {
             id: initiate.id,
@@ -141,13 +309,22 @@ impl Sendable for Session {
     }
 
     fn seal_msg(&self, data: &[u8]) -> (Nonce, Bytes) {
-        let nonce = gen_nonce();
-        let payload = seal(data, &nonce, &self.client_pk, &self.st.1);
+        let nonce = self.nonces.next(SERVER_MSG_PREFIX);
+        let key = self.shared.as_ref().expect("Session is not ready");
+        let payload = seal_precomputed(data, &nonce, key);
         (nonce, payload.into())
     }
 
     fn read_msg(&self, frame: &Frame) -> LlsdResult<BytesMut> {
-        if let Ok(msg) = open(&frame.payload, &frame.nonce, &self.client_pk, &self.st.1) {
+        let key = match self.shared {
+            Some(ref key) => key,
+            None => return Err(LlsdError::InvalidSessionState),
+        };
+        let counter = Nonces::counter(CLIENT_MSG_PREFIX, &frame.nonce)?;
+        if let Ok(msg) = open_precomputed(&frame.payload, &frame.nonce, key) {
+            // Only authentic frames are allowed to move the window.
+            self.nonces.accept(counter)?;
+            self.activity.touch();
             Ok(msg.into())
         } else {
             Err(LlsdError::DecryptionFailed)
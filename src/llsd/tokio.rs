@@ -1,11 +1,13 @@
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
-use frames::Frame;
-use llsd::errors::LlsdError;
+use crate::frames::{Frame, MAX_FRAME_LENGTH};
+use crate::llsd::errors::LlsdError;
 use std::io;
 use std::result::Result;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_proto::multiplex;
+use tokio_proto::multiplex::RequestId;
 use tokio_proto::pipeline::{ClientProto, ServerProto};
 
 /// Tokio style codec for both client and server. It uses 4 bytes to prefix
@@ -22,20 +24,18 @@ impl Decoder for FrameCodec {
         }
         // Check that if we have the whole payload
         let payload_len = BigEndian::read_u32(&buf[0..4]) as usize;
+        if payload_len > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LlsdError::BadFrame));
+        }
         if buf.len() < 4 + payload_len {
             return Ok(None);
         }
-        // We have a whole frame. Consume those bytes form the buffer.
+        // We have a whole frame. Consume those bytes form the buffer. They are
+        // gone, so frame that doesn't parse can't be waited for.
         let data = buf.split_to(4 + payload_len);
-        match Frame::from_slice(&data[4..]) {
-            Ok(frame) => Ok(Some(frame)),
-            Err(e) => {
-                match e {
-                    LlsdError::IncompleteFrame => Ok(None),
-                    _ => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-                }
-            }
-        }
+        Frame::from_slice(&data[4..])
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 }
 
@@ -74,10 +74,74 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for WhisperPipelinedPro
     }
 }
 
+/// Same as `FrameCodec`, but every frame is followed by 8 bytes of request id
+/// right after length prefix. Length prefix doesn't include request id.
+pub struct MultiplexCodec;
+
+impl Decoder for MultiplexCodec {
+    type Item = (RequestId, Frame);
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<(RequestId, Frame)>> {
+        // Check that if we have at least length and request id to read
+        if buf.len() < 12 {
+            return Ok(None);
+        }
+        let payload_len = BigEndian::read_u32(&buf[0..4]) as usize;
+        if payload_len > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LlsdError::BadFrame));
+        }
+        if buf.len() < 12 + payload_len {
+            return Ok(None);
+        }
+        let data = buf.split_to(12 + payload_len);
+        let request_id = BigEndian::read_u64(&data[4..12]);
+        Frame::from_slice(&data[12..])
+            .map(|frame| Some((request_id, frame)))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Encoder for MultiplexCodec {
+    type Item = (RequestId, Frame);
+    type Error = io::Error;
+    fn encode(&mut self, msg: (RequestId, Frame), buf: &mut BytesMut) -> io::Result<()> {
+        let (request_id, frame) = msg;
+        if buf.remaining_mut() < 12 {
+            buf.reserve(12);
+        }
+        buf.put_u32::<BigEndian>(frame.length() as u32);
+        buf.put_u64::<BigEndian>(request_id);
+        frame.pack_to_buf(buf);
+        Ok(())
+    }
+}
+
+/// Tokio Protocol for both clients and servers. This is Multiplexed version of
+/// it. Responses can be sent back in any order.
+pub struct WhisperMultiplexedProtocol;
+impl<T: AsyncRead + AsyncWrite + 'static> multiplex::ServerProto<T> for WhisperMultiplexedProtocol {
+    type Request = Frame;
+    type Response = Frame;
+    type Transport = Framed<T, MultiplexCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(MultiplexCodec))
+    }
+}
+impl<T: AsyncRead + AsyncWrite + 'static> multiplex::ClientProto<T> for WhisperMultiplexedProtocol {
+    type Request = Frame;
+    type Response = Frame;
+    type Transport = Framed<T, MultiplexCodec>;
+    type BindTransport = Result<Self::Transport, io::Error>;
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(MultiplexCodec))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use frames::FrameKind;
+    use crate::frames::FrameKind;
     use sodiumoxide::crypto::box_::{gen_keypair, gen_nonce};
 
     fn make_frame() -> Frame {
@@ -150,4 +214,54 @@ mod test {
         let payload_len = BigEndian::read_u32(&buf[0..4]) as usize;
         assert_eq!(frame.length(), payload_len);
     }
+
+    #[test]
+    fn test_multiplex_decode_and_encode() {
+        let frame = make_frame();
+        let mut buf = BytesMut::with_capacity(0);
+        let mut codec = MultiplexCodec {};
+
+        codec.encode((42, frame.clone()), &mut buf).unwrap();
+        codec.encode((7, frame.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), 2 * (12 + frame.length()));
+
+        // Message is partial
+        let mut buf_partial = BytesMut::from(&buf[0..30]);
+        let result = codec.decode(&mut buf_partial);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+
+        // Ids are preserved and frames come out in order they were written
+        let (first_id, first) = codec.decode(&mut buf).unwrap().unwrap();
+        let (second_id, second) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(first_id, 42);
+        assert_eq!(second_id, 7);
+        assert_eq!(first, frame);
+        assert_eq!(second, frame);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn test_multiplex_decode_garbage() {
+        let mut codec = MultiplexCodec {};
+
+        // Length nobody should allocate for
+        let mut buf = BytesMut::with_capacity(12);
+        buf.put_u32::<BigEndian>(MAX_FRAME_LENGTH as u32 + 1);
+        buf.put_u64::<BigEndian>(1);
+        match codec.decode(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        // Whole frame arrived, but it's too short to be one
+        let mut buf = BytesMut::with_capacity(15);
+        buf.put_u32::<BigEndian>(3);
+        buf.put_u64::<BigEndian>(1);
+        buf.put_slice(&[1, 2, 3]);
+        match codec.decode(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
 }
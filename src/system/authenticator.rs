@@ -1,11 +1,25 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use crate::errors::{KeyFileError, KeyFileResult};
+use crate::keyfile::parse_public_key;
+pub use crate::llsd::session::identity::Identity;
 use sodiumoxide::crypto::box_::PublicKey;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 /// Used to authenticate user by his long term public key. This way its easy to
 /// test.
 pub trait Authenticator: Clone + Send + Sync {
+    /// Tell who the client is. `None` means client is not allowed in.
+    /// Identity is kept with the session and handlers can see it.
+    fn authenticate(&self, key: &PublicKey) -> Option<Identity>;
+
     /// Well...
-    fn is_valid(&self, key: &PublicKey) -> bool;
+    fn is_valid(&self, key: &PublicKey) -> bool {
+        self.authenticate(key).is_some()
+    }
 }
 
 /// Authenticator example that is very dumb, but great for testing
@@ -26,14 +40,151 @@ impl DumbAuthenticator {
 }
 
 impl Authenticator for DumbAuthenticator {
-    fn is_valid(&self, key: &PublicKey) -> bool {
-        self.white_list.contains(key)
+    fn authenticate(&self, key: &PublicKey) -> Option<Identity> {
+        if self.white_list.contains(key) {
+            Some(Identity::default())
+        } else {
+            None
+        }
+    }
+}
+
+/// What allow-list knows about a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedKey {
+    /// Who this key belongs to. Only for humans.
+    pub label: Option<String>,
+    /// Key is rejected after this moment. `None` means never.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AllowedKey {
+    /// Check if key is still good.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= Utc::now())
+    }
+}
+
+type AllowList = HashMap<PublicKey, AllowedKey>;
+
+/// Authenticator that reads allowed client keys from a file. One key per
+/// line, in the format of `keyfile`, optionally followed by label and expiry:
+///
+/// ```text
+/// # Comments and empty lines are ignored.
+/// hex:5f0e...
+/// hex:8a3c... label=billing
+/// base64:Xw6... label=contractor expires=2027-01-01T00:00:00Z
+/// ```
+///
+/// File is read again on `reload`. Clones share allowed keys, so the copy
+/// `AngelSystem` holds sees reloaded ones too.
+pub struct FileAuthenticator {
+    path: Arc<PathBuf>,
+    keys: Arc<RwLock<AllowList>>,
+}
+
+impl Clone for FileAuthenticator {
+    fn clone(&self) -> FileAuthenticator {
+        FileAuthenticator {
+            path: self.path.clone(),
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+impl FileAuthenticator {
+    /// Read allow-list from file.
+    pub fn open<P: AsRef<Path>>(path: P) -> KeyFileResult<FileAuthenticator> {
+        let path = path.as_ref().to_path_buf();
+        let keys = read_allow_list(&path)?;
+        Ok(FileAuthenticator {
+               path: Arc::new(path),
+               keys: Arc::new(RwLock::new(keys)),
+           })
+    }
+
+    /// Read file again and replace allowed keys with what is in it. If file
+    /// can't be read or parsed, keys that were allowed before stay allowed.
+    /// Returns number of keys in the file.
+    pub fn reload(&self) -> KeyFileResult<usize> {
+        let keys = read_allow_list(&self.path)?;
+        let len = keys.len();
+        match self.keys.write() {
+            Ok(mut current) => *current = keys,
+            // Nobody can read poisoned list anyway, so just replace it.
+            Err(poisoned) => *poisoned.into_inner() = keys,
+        }
+        Ok(len)
+    }
+
+    /// Label and expiry of the key, if it's in the list. Expired keys are
+    /// returned too.
+    pub fn get(&self, key: &PublicKey) -> Option<AllowedKey> {
+        match self.keys.read() {
+            Ok(keys) => keys.get(key).cloned(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    /// Label becomes user id.
+    fn authenticate(&self, key: &PublicKey) -> Option<Identity> {
+        match self.get(key) {
+            Some(ref allowed) if !allowed.is_expired() => {
+                Some(Identity {
+                         user_id: allowed.label.clone(),
+                         ..Identity::default()
+                     })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn read_allow_list(path: &Path) -> KeyFileResult<AllowList> {
+    let content = fs::read_to_string(path)?;
+    parse_allow_list(&content)
+}
+
+fn parse_allow_list(content: &str) -> KeyFileResult<AllowList> {
+    let mut keys = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, allowed) = parse_entry(line).ok_or(KeyFileError::InvalidEntry(index + 1))?;
+        keys.insert(key, allowed);
     }
+    Ok(keys)
+}
+
+fn parse_entry(line: &str) -> Option<(PublicKey, AllowedKey)> {
+    let mut fields = line.split_whitespace();
+    let key = parse_public_key(fields.next()?).ok()?;
+    let mut allowed = AllowedKey {
+        label: None,
+        expires_at: None,
+    };
+    for field in fields {
+        if let Some(label) = field.strip_prefix("label=") {
+            allowed.label = Some(label.to_owned());
+        } else if let Some(expires_at) = field.strip_prefix("expires=") {
+            let expires_at = DateTime::parse_from_rfc3339(expires_at).ok()?;
+            allowed.expires_at = Some(expires_at.with_timezone(&Utc));
+        } else {
+            return None;
+        }
+    }
+    Some((key, allowed))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::keyfile::{Encoding, public_key_to_string};
     use sodiumoxide::crypto::box_;
 
     #[test]
@@ -46,4 +197,75 @@ mod test {
         assert_eq!(dumb.is_valid(&pk), true);
         assert_eq!(dumb.is_valid(&pk2), false);
     }
+
+    fn allow_list_file(name: &str, content: &str) -> PathBuf {
+        let path = ::std::env::temp_dir()
+            .join(format!("angel-whisper-{}-{}", ::std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_authenticator() {
+        let (plain, _) = box_::gen_keypair();
+        let (labeled, _) = box_::gen_keypair();
+        let (expired, _) = box_::gen_keypair();
+        let (stranger, _) = box_::gen_keypair();
+        let content = format!("# clients\n\n{}\n{} label=billing expires=2999-01-01T00:00:00Z\n\
+                               {} label=gone expires=2000-01-01T00:00:00+02:00\n",
+                              public_key_to_string(&plain, Encoding::Hex),
+                              public_key_to_string(&labeled, Encoding::Base64),
+                              public_key_to_string(&expired, Encoding::Hex));
+        let path = allow_list_file("file_authenticator", &content);
+        let auth = FileAuthenticator::open(&path).unwrap();
+
+        assert!(auth.is_valid(&plain));
+        assert!(auth.is_valid(&labeled));
+        assert!(!auth.is_valid(&expired));
+        assert!(!auth.is_valid(&stranger));
+        assert_eq!(auth.get(&labeled).unwrap().label, Some("billing".to_owned()));
+        assert_eq!(auth.get(&plain).unwrap().label, None);
+        assert_eq!(auth.authenticate(&labeled), Some(Identity::new("billing")));
+        assert_eq!(auth.authenticate(&plain), Some(Identity::default()));
+        assert_eq!(auth.authenticate(&expired), None);
+        assert!(auth.get(&expired).unwrap().is_expired());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn file_authenticator_reload() {
+        let (old, _) = box_::gen_keypair();
+        let (new, _) = box_::gen_keypair();
+        let path = allow_list_file("file_authenticator_reload",
+                                   &public_key_to_string(&old, Encoding::Hex));
+        let auth = FileAuthenticator::open(&path).unwrap();
+        // Clone is what AngelSystem would hold.
+        let system_copy = auth.clone();
+        assert!(system_copy.is_valid(&old));
+
+        fs::write(&path, public_key_to_string(&new, Encoding::Hex)).unwrap();
+        assert_eq!(auth.reload().unwrap(), 1);
+        assert!(!system_copy.is_valid(&old));
+        assert!(system_copy.is_valid(&new));
+
+        // Broken file doesn't lock everybody out.
+        fs::write(&path, format!("{}\nhex:nope\n", public_key_to_string(&old, Encoding::Hex)))
+            .unwrap();
+        match auth.reload() {
+            Err(KeyFileError::InvalidEntry(2)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        assert!(system_copy.is_valid(&new));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let (pk, _) = box_::gen_keypair();
+        let key = public_key_to_string(&pk, Encoding::Hex);
+        assert!(parse_entry(&key).is_some());
+        assert!(parse_entry(&format!("{} owner=me", key)).is_none());
+        assert!(parse_entry(&format!("{} expires=tomorrow", key)).is_none());
+        assert!(parse_entry("hex:00ff").is_none());
+    }
 }
@@ -0,0 +1,157 @@
+use super::{ServiceHub, ShareSession};
+use super::router::RouteAction;
+use bytes::{Bytes, BytesMut};
+use crate::errors::AWResult;
+use crate::llsd::codec::{BincodeCodec, Codec, JsonCodec, MsgPackCodec};
+use crate::llsd::route::Route;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+/// Route action that takes and returns serde types. Payload is decoded into
+/// `Req` with codec `C` and `Resp` is encoded back with the same codec:
+///
+/// ```ignore
+/// router.register_route("geo::distance",
+///                       SerdeAction::json(|line: Line| Ok(line.length())))?;
+/// ```
+///
+/// Client has to use matching wrapper: `Json`, `MsgPack` or `Bincode`.
+/// Payload that can't be decoded is answered with
+/// `LlsdError::InvalidPayload`.
+pub struct SerdeAction<C, Req, Resp, F> {
+    action: F,
+    types: PhantomData<fn(C, Req) -> Resp>,
+}
+
+impl<C, Req, Resp, F> SerdeAction<C, Req, Resp, F>
+where
+    C: Codec,
+    Req: DeserializeOwned + 'static,
+    Resp: Serialize + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    /// Wrap a function, use codec `C`.
+    pub fn new(action: F) -> SerdeAction<C, Req, Resp, F> {
+        SerdeAction {
+            action: action,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp, F> SerdeAction<JsonCodec, Req, Resp, F>
+where
+    Req: DeserializeOwned + 'static,
+    Resp: Serialize + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    /// Wrap a function, talk JSON.
+    pub fn json(action: F) -> SerdeAction<JsonCodec, Req, Resp, F> {
+        SerdeAction::new(action)
+    }
+}
+
+impl<Req, Resp, F> SerdeAction<MsgPackCodec, Req, Resp, F>
+where
+    Req: DeserializeOwned + 'static,
+    Resp: Serialize + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    /// Wrap a function, talk MessagePack.
+    pub fn msgpack(action: F) -> SerdeAction<MsgPackCodec, Req, Resp, F> {
+        SerdeAction::new(action)
+    }
+}
+
+impl<Req, Resp, F> SerdeAction<BincodeCodec, Req, Resp, F>
+where
+    Req: DeserializeOwned + 'static,
+    Resp: Serialize + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    /// Wrap a function, talk bincode.
+    pub fn bincode(action: F) -> SerdeAction<BincodeCodec, Req, Resp, F> {
+        SerdeAction::new(action)
+    }
+}
+
+impl<C, Req, Resp, F> RouteAction for SerdeAction<C, Req, Resp, F>
+where
+    C: Codec,
+    Req: DeserializeOwned + 'static,
+    Resp: Serialize + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    fn process(&self,
+               _: &Route,
+               _: ServiceHub,
+               _: ShareSession,
+               msg: &mut BytesMut)
+               -> AWResult<Bytes> {
+        let req = C::decode(msg)?;
+        let resp = (self.action)(req)?;
+        Ok(C::encode(&resp)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::AWError;
+    use crate::llsd::client::{FromBytes, IntoBytes};
+    use crate::llsd::codec::{Bincode, Json, MsgPack};
+    use crate::llsd::errors::LlsdError;
+    use crate::llsd::session::server::Session;
+    use crate::system::Services;
+    use serde::Deserialize;
+    use std::sync::{Arc, RwLock};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Line {
+        from: (f64, f64),
+        to: (f64, f64),
+    }
+
+    fn length(line: Line) -> AWResult<f64> {
+        Ok(((line.to.0 - line.from.0).powi(2) + (line.to.1 - line.from.1).powi(2)).sqrt())
+    }
+
+    fn line() -> Line {
+        Line {
+            from: (0.0, 0.0),
+            to: (3.0, 4.0),
+        }
+    }
+
+    fn call<R: IntoBytes>(action: &dyn RouteAction, req: R) -> AWResult<BytesMut> {
+        let hub = Arc::new(Services::default());
+        let session = Arc::new(RwLock::new(Session::default()));
+        let mut payload = BytesMut::from(req.into_bytes()?);
+        action
+            .process(&Route::from("geo::length"), hub, session, &mut payload)
+            .map(BytesMut::from)
+    }
+
+    #[test]
+    fn serde_actions() {
+        let reply = call(&SerdeAction::json(length), Json(line())).unwrap();
+        assert_eq!(reply.as_ref(), b"5.0");
+        let reply: Json<f64> = FromBytes::from(reply).unwrap();
+        assert_eq!(reply.into_inner(), 5.0);
+
+        let reply = call(&SerdeAction::msgpack(length), MsgPack(line())).unwrap();
+        let reply: MsgPack<f64> = FromBytes::from(reply).unwrap();
+        assert_eq!(reply.into_inner(), 5.0);
+
+        let reply = call(&SerdeAction::bincode(length), Bincode(line())).unwrap();
+        let reply: Bincode<f64> = FromBytes::from(reply).unwrap();
+        assert_eq!(reply.into_inner(), 5.0);
+
+        // Codecs don't mix.
+        match call(&SerdeAction::json(length), MsgPack(line())) {
+            Err(AWError::LlsdError(LlsdError::InvalidPayload(_))) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
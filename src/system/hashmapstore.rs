@@ -1,16 +1,17 @@
 
 
-use super::sessionstore::SessionStore;
-use llsd::session::Sendable;
-use llsd::session::server::Session;
+use super::sessionstore::{ReapReason, ReapStats, SessionStore};
+use crate::errors::{AWError, AWResult};
+use crate::llsd::POISONED_LOCK_MSG;
+use crate::llsd::errors::LlsdError;
+use crate::llsd::session::Sendable;
+use crate::llsd::session::server::Session;
 
 use sodiumoxide::crypto::box_::PublicKey;
 use std::collections::HashMap;
 use std::default::Default;
 use std::sync::{Arc, RwLock};
 
-const POISONED_LOCK_MSG: &'static str = "Lock was poisoned";
-
 type Store = Arc<RwLock<HashMap<PublicKey, Arc<RwLock<Session>>>>>;
 pub struct HashMapStore {
     store: Store,
@@ -24,21 +25,32 @@ impl Clone for HashMapStore {
 
 impl SessionStore for HashMapStore {
     fn insert(&self, session: Session) -> Option<()> {
-        // Avoid write locks on map as hard as we can
-        if session.is_valid() &&
-            !self.store
-                 .read()
-                 .expect(POISONED_LOCK_MSG)
-                 .contains_key(&session.id())
-        {
-            self.store
-                .write()
-                .expect(POISONED_LOCK_MSG)
-                .insert(session.id(), Arc::new(RwLock::new(session)));
-            Some(())
-        } else {
-            None
+        if !session.is_valid() {
+            return None;
+        }
+        // Check and insert under one lock, so concurrent inserts of the same
+        // session can't both succeed.
+        let mut store = self.store.write().expect(POISONED_LOCK_MSG);
+        if store.contains_key(&session.id()) {
+            return None;
+        }
+        store.insert(session.id(), Arc::new(RwLock::new(session)));
+        Some(())
+    }
+
+    fn try_insert_bounded(&self, session: Session, limit: usize) -> AWResult<()> {
+        if !session.is_valid() {
+            return Err(LlsdError::InvalidSessionState.into());
+        }
+        let mut store = self.store.write().expect(POISONED_LOCK_MSG);
+        if store.contains_key(&session.id()) {
+            return Err(LlsdError::InvalidSessionState.into());
+        }
+        if store.len() >= limit {
+            return Err(AWError::TooManySessions);
         }
+        store.insert(session.id(), Arc::new(RwLock::new(session)));
+        Ok(())
     }
 
     fn find_by_pk(&self, key: &PublicKey) -> Option<Arc<RwLock<Session>>> {
@@ -54,6 +66,38 @@ impl SessionStore for HashMapStore {
     fn destroy(&self, key: &PublicKey) {
         self.store.write().expect(POISONED_LOCK_MSG).remove(key);
     }
+
+    fn len(&self) -> usize {
+        self.store.read().expect(POISONED_LOCK_MSG).len()
+    }
+
+    fn reap(&self) -> (ReapStats, Vec<PublicKey>) {
+        let mut stats = ReapStats::default();
+        let mut reaped = Vec::new();
+        self.store
+            .write()
+            .expect(POISONED_LOCK_MSG)
+            .retain(|session_id, session_lock| {
+                // Session that somebody panicked with is not to be trusted.
+                let (mut session, reason) = match session_lock.write() {
+                    Ok(session) => {
+                        let reason = session.reap_reason();
+                        (session, reason)
+                    }
+                    Err(poisoned) => (poisoned.into_inner(), Some(ReapReason::Errored)),
+                };
+                match reason {
+                    None => true,
+                    Some(reason) => {
+                        stats.record(reason);
+                        reaped.push(*session_id);
+                        session.destroy();
+                        false
+                    }
+                }
+            });
+        (stats, reaped)
+    }
 }
 
 impl Default for HashMapStore {
@@ -65,9 +109,10 @@ impl Default for HashMapStore {
 #[cfg(test)]
 mod test {
     use super::*;
-    use super::super::sessionstore::SessionStore;
-    use llsd::session::Sendable;
-    use llsd::session::server::Session;
+    use super::super::sessionstore::{ReapStats, SessionStore};
+    use chrono::Duration;
+    use crate::llsd::session::Sendable;
+    use crate::llsd::session::server::Session;
     use sodiumoxide::crypto::box_;
 
     fn make_store() -> HashMapStore {
@@ -109,15 +154,77 @@ mod test {
         assert_eq!(store.insert(session.clone()), None);
     }
 
+    #[test]
+    fn insert_bounded() {
+        let store = make_store();
+        let first = Session::new(key().0);
+        assert!(store.is_empty());
+
+        store.try_insert_bounded(first.clone(), 1).unwrap();
+        match store.try_insert_bounded(Session::new(key().0), 1) {
+            Err(AWError::TooManySessions) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        match store.try_insert_bounded(first, 2) {
+            Err(AWError::LlsdError(LlsdError::InvalidSessionState)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        assert_eq!(store.len(), 1);
+    }
+
     #[test]
     fn remove() {
         let store = make_store();
         let session = Session::new(key().0);
 
         assert_eq!(store.insert(session.clone()), Some(()));
+        assert_eq!(store.len(), 1);
         store.destroy(&session.id());
+        assert_eq!(store.len(), 0);
         let subject = store.find_by_pk(&session.id());
         assert!(subject.is_none());
     }
 
+    #[test]
+    fn reap() {
+        let store = make_store();
+        let alive = Session::new(key().0);
+        let mut errored = Session::new(key().0);
+        let mut abandoned = Session::new(key().0);
+        let expired = Session::new(key().0);
+        errored.destroy();
+        abandoned.age_by(Duration::minutes(5));
+
+        for session in vec![alive.clone(), errored.clone(), abandoned.clone(), expired.clone()] {
+            assert_eq!(store.insert(session), Some(()));
+        }
+        // Can't insert expired session, so age it in place.
+        store
+            .find_by_pk(&expired.id())
+            .unwrap()
+            .write()
+            .unwrap()
+            .age_by(Duration::minutes(60));
+        let expired_lock = store.find_by_pk(&expired.id()).unwrap();
+
+        let (stats, reaped) = store.reap();
+        assert_eq!(stats,
+                   ReapStats {
+                       expired: 1,
+                       idle: 0,
+                       errored: 1,
+                       abandoned: 1,
+                   });
+        assert_eq!(stats.total(), 3);
+        assert_eq!(reaped.len(), 3);
+        assert!(!reaped.contains(&alive.id()));
+        assert!(store.find_by_pk(&alive.id()).is_some());
+        assert!(store.find_by_pk(&errored.id()).is_none());
+        assert!(store.find_by_pk(&abandoned.id()).is_none());
+        assert!(store.find_by_pk(&expired.id()).is_none());
+        // Whoever still holds on to the session can't use it
+        assert!(!expired_lock.read().unwrap().can_send());
+
+        assert_eq!(store.reap().0.total(), 0);
+    }
 }
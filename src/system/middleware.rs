@@ -0,0 +1,401 @@
+use super::{Handler, ServiceHub, ShareSession};
+use super::policy::Policy;
+use super::router::RouteAction;
+use bytes::{Bytes, BytesMut};
+use crate::errors::{AWError, AWResult};
+use crate::llsd::route::Route;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::time::{Duration, Instant};
+
+type Inner<'a> = &'a (dyn Fn(ServiceHub, ShareSession, &mut BytesMut) -> AWResult<Bytes> + 'a);
+
+/// Rest of the chain: other middlewares and, eventually, wrapped handler or
+/// route action.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    route: Option<&'a Route>,
+    inner: Inner<'a>,
+}
+
+impl<'a> Next<'a> {
+    /// Route of the request. Only known when route action is wrapped: handler
+    /// (including `DynamicRouter`) gets payload with route still in it.
+    pub fn route(&self) -> Option<&'a Route> {
+        self.route
+    }
+
+    /// Pass request further down the chain.
+    pub fn run(self,
+               services: ServiceHub,
+               session: ShareSession,
+               msg: &mut BytesMut)
+               -> AWResult<Bytes> {
+        (self.inner)(services, session, msg)
+    }
+}
+
+/// Cross-cutting logic around `Handler` or `RouteAction`. Middleware decides
+/// whether to call the rest of the chain and can change both request and
+/// result.
+pub trait Middleware: Send + Sync + 'static {
+    /// Handle request, usually by calling `next.run` at some point.
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes>;
+}
+
+/// Handler or route action wrapped with middleware. Is a `Handler` if inner
+/// is a `Handler` and `RouteAction` if inner is a `RouteAction`.
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M: Middleware, H> Layered<M, H> {
+    /// Wrap `inner` with `middleware`.
+    pub fn new(middleware: M, inner: H) -> Layered<M, H> {
+        Layered {
+            middleware: middleware,
+            inner: inner,
+        }
+    }
+}
+
+impl<M: Middleware, H: Handler> Handler for Layered<M, H> {
+    fn handle(&self,
+              services: ServiceHub,
+              session: ShareSession,
+              msg: &mut BytesMut)
+              -> AWResult<Bytes> {
+        let inner = |services: ServiceHub, session: ShareSession, msg: &mut BytesMut| {
+            self.inner.handle(services, session, msg)
+        };
+        let next = Next {
+            route: None,
+            inner: &inner,
+        };
+        self.middleware.call(services, session, msg, next)
+    }
+}
+
+impl<M: Middleware, A: RouteAction> RouteAction for Layered<M, A> {
+    fn process(&self,
+               route: &Route,
+               services: ServiceHub,
+               session: ShareSession,
+               msg: &mut BytesMut)
+               -> AWResult<Bytes> {
+        let inner = |services: ServiceHub, session: ShareSession, msg: &mut BytesMut| {
+            self.inner.process(route, services, session, msg)
+        };
+        let next = Next {
+            route: Some(route),
+            inner: &inner,
+        };
+        self.middleware.call(services, session, msg, next)
+    }
+}
+
+/// Wrap handler or route action with middleware. Layers added later are
+/// outer ones: they see request first and result last.
+pub fn layer<M: Middleware, H>(inner: H, middleware: M) -> Layered<M, H> {
+    Layered::new(middleware, inner)
+}
+
+/// Calls a function with route, time it took to handle request and result.
+/// Good for metrics.
+pub struct Timing<F> {
+    report: F,
+}
+
+impl<F> Timing<F>
+where
+    F: Fn(Option<&Route>, Duration, &AWResult<Bytes>) + Send + Sync + 'static,
+{
+    /// Report every request to `report`.
+    pub fn new(report: F) -> Timing<F> {
+        Timing { report: report }
+    }
+}
+
+impl<F> Middleware for Timing<F>
+where
+    F: Fn(Option<&Route>, Duration, &AWResult<Bytes>) + Send + Sync + 'static,
+{
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes> {
+        let started = Instant::now();
+        let result = next.run(services, session, msg);
+        (self.report)(next.route(), started.elapsed(), &result);
+        result
+    }
+}
+
+/// Writes a line per request: who called, what route, how long it took and
+/// how it ended.
+pub struct Logging<F> {
+    write: F,
+}
+
+impl<F: Fn(&str) + Send + Sync + 'static> Logging<F> {
+    /// Send lines to `write`.
+    pub fn new(write: F) -> Logging<F> {
+        Logging { write: write }
+    }
+}
+
+impl Logging<fn(&str)> {
+    /// Write lines to stderr.
+    pub fn stderr() -> Logging<fn(&str)> {
+        fn write(line: &str) {
+            eprintln!("{}", line);
+        }
+        Logging::new(write as fn(&str))
+    }
+}
+
+impl<F: Fn(&str) + Send + Sync + 'static> Middleware for Logging<F> {
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes> {
+        let user = match super::identity(&session).and_then(|identity| identity.user_id) {
+            Some(user_id) => user_id,
+            None => "-".to_owned(),
+        };
+        let route = match next.route() {
+            Some(route) => format!("{:016x}", route.as_u64()),
+            None => "-".to_owned(),
+        };
+        let size = msg.len();
+        let started = Instant::now();
+        let result = next.run(services, session, msg);
+        let elapsed = started.elapsed();
+        let outcome = match result {
+            Ok(ref reply) => format!("ok {}", reply.len()),
+            Err(ref e) => format!("error {:?}", e.code()),
+        };
+        (self.write)(&format!("user={} route={} size={} time={}us {}",
+                              user,
+                              route,
+                              size,
+                              elapsed.as_micros(),
+                              outcome));
+        result
+    }
+}
+
+/// Lets request through only if session owner is allowed by the policy.
+/// Same check `DynamicRouter` does per route, but for anything.
+pub struct Authorize {
+    policy: Policy,
+}
+
+impl Authorize {
+    /// Require `policy`.
+    pub fn new(policy: Policy) -> Authorize {
+        Authorize { policy: policy }
+    }
+}
+
+impl Middleware for Authorize {
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes> {
+        let allowed = match session.read() {
+            Ok(session) => self.policy.allows(&session),
+            Err(_) => return Err(AWError::ServerFault),
+        };
+        if !allowed {
+            return Err(AWError::PermissionDenied);
+        }
+        next.run(services, session, msg)
+    }
+}
+
+/// Rejects requests with payload bigger than the limit.
+pub struct SizeLimit {
+    max: usize,
+}
+
+impl SizeLimit {
+    /// Allow at most `max` bytes of payload.
+    pub fn new(max: usize) -> SizeLimit {
+        SizeLimit { max: max }
+    }
+}
+
+impl Middleware for SizeLimit {
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes> {
+        if msg.len() > self.max {
+            return Err(AWError::PayloadTooLarge);
+        }
+        next.run(services, session, msg)
+    }
+}
+
+/// Turns panic in the rest of the chain into `AWError::ServerFault`, so one
+/// bad request doesn't take server thread down.
+#[derive(Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn call(&self,
+            services: ServiceHub,
+            session: ShareSession,
+            msg: &mut BytesMut,
+            next: Next)
+            -> AWResult<Bytes> {
+        match catch_unwind(AssertUnwindSafe(|| next.run(services, session, msg))) {
+            Ok(result) => result,
+            Err(_) => Err(AWError::ServerFault),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use crate::llsd::session::identity::Identity;
+    use crate::llsd::session::server::Session;
+    use crate::system::Services;
+    use crate::system::router::DynamicRouter;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    fn get_hub() -> ServiceHub {
+        Arc::new(Services::default())
+    }
+
+    fn get_session(identity: Identity) -> ShareSession {
+        let mut session = Session::default();
+        session.set_identity(identity);
+        Arc::new(RwLock::new(session))
+    }
+
+    struct Echo;
+    impl Handler for Echo {
+        fn handle(&self, _: ServiceHub, _: ShareSession, msg: &mut BytesMut) -> AWResult<Bytes> {
+            Ok(msg.clone().freeze())
+        }
+    }
+    impl RouteAction for Echo {
+        fn process(&self,
+                   _: &Route,
+                   _: ServiceHub,
+                   _: ShareSession,
+                   msg: &mut BytesMut)
+                   -> AWResult<Bytes> {
+            Ok(msg.clone().freeze())
+        }
+    }
+
+    struct Boom;
+    impl Handler for Boom {
+        fn handle(&self, _: ServiceHub, _: ShareSession, _: &mut BytesMut) -> AWResult<Bytes> {
+            panic!("boom")
+        }
+    }
+
+    // Appends its tag to the payload on the way in.
+    struct Tag(u8);
+    impl Middleware for Tag {
+        fn call(&self,
+                services: ServiceHub,
+                session: ShareSession,
+                msg: &mut BytesMut,
+                next: Next)
+                -> AWResult<Bytes> {
+            msg.extend_from_slice(&[self.0]);
+            next.run(services, session, msg)
+        }
+    }
+
+    #[test]
+    fn layers_order() {
+        let handler = layer(layer(Echo, Tag(1)), Tag(2));
+        let reply = handler
+            .handle(get_hub(), get_session(Identity::default()), &mut BytesMut::new())
+            .unwrap();
+        assert_eq!(reply.as_ref(), &[2, 1]);
+    }
+
+    #[test]
+    fn builtin_layers() {
+        let session = get_session(Identity::new("alice"));
+
+        let limited = layer(Echo, SizeLimit::new(2));
+        let fits = limited.handle(get_hub(), session.clone(), &mut BytesMut::from(&b"ok"[..]));
+        assert!(fits.is_ok());
+        match limited.handle(get_hub(), session.clone(), &mut BytesMut::from(&b"nope"[..])) {
+            Err(AWError::PayloadTooLarge) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        let admins_only = layer(Echo, Authorize::new(Policy::role("admin")));
+        match admins_only.handle(get_hub(), session.clone(), &mut BytesMut::new()) {
+            Err(AWError::PermissionDenied) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        let caught = layer(Boom, CatchPanic);
+        match caught.handle(get_hub(), session.clone(), &mut BytesMut::new()) {
+            Err(AWError::ServerFault) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn route_action_layers() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let timings = Arc::new(Mutex::new(Vec::new()));
+        let lines_copy = lines.clone();
+        let timings_copy = timings.clone();
+
+        let router = DynamicRouter::default();
+        let logged = layer(Echo,
+                           Logging::new(move |line: &str| {
+                                            lines_copy.lock().unwrap().push(line.to_owned())
+                                        }));
+        let action = layer(logged,
+                           Timing::new(move |route: Option<&Route>, _, result: &AWResult<Bytes>| {
+                                           timings_copy
+                                               .lock()
+                                               .unwrap()
+                                               .push((route.cloned(), result.is_ok()))
+                                       }));
+        router.register_route("echo", action).unwrap();
+
+        let mut req = Vec::new();
+        req.write_u64::<BigEndian>(Route::from("echo").as_u64()).unwrap();
+        req.extend_from_slice(b"hi");
+        let reply = router
+            .handle(get_hub(), get_session(Identity::new("alice")), &mut req.into())
+            .unwrap();
+        assert_eq!(reply.as_ref(), b"hi");
+
+        assert_eq!(*timings.lock().unwrap(), vec![(Some(Route::from("echo")), true)]);
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(&format!("user=alice route={:016x} size=2 ",
+                                              Route::from("echo").as_u64())));
+        assert!(lines[0].ends_with(" ok 2"));
+    }
+}
@@ -1,20 +1,43 @@
 
 
 use super::errors::AWResult;
+use super::llsd::session::identity::Identity;
 use super::llsd::session::server::Session;
 use bytes::{Bytes, BytesMut};
 
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::{Arc, RwLock};
-use typemap::ShareMap;
 
 pub mod router;
+pub mod policy;
+pub mod middleware;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "serde")]
+pub mod codec;
+pub mod service;
 pub mod authenticator;
 pub mod hashmapstore;
+pub mod push;
+pub mod services;
 pub mod sessionstore;
 
-pub type ServiceHub = Arc<RwLock<ShareMap>>;
+pub use self::services::Services;
+
+/// Services every handler gets. Read-only, so no locks are taken.
+pub type ServiceHub = Arc<Services>;
 pub type ShareSession = Arc<RwLock<Session>>;
 
+/// Identity `Authenticator` gave to the client that owns the session. Lets
+/// handlers and route actions decide what client is allowed to do.
+pub fn identity(session: &ShareSession) -> Option<Identity> {
+    match session.read() {
+        Ok(session) => session.identity().cloned(),
+        Err(_) => None,
+    }
+}
+
 pub trait Handler: Send + Sync + 'static {
     /// Handle incoming message.
     fn handle(&self,
@@ -23,3 +46,15 @@ pub trait Handler: Send + Sync + 'static {
               msg: &mut BytesMut)
               -> AWResult<Bytes>;
 }
+
+/// Future returned by `AsyncHandler`.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = AWResult<Bytes>> + Send + 'static>>;
+
+/// Same as `Handler`, but returns std future instead of blocking. Use it when
+/// handling a message requires waiting on something (database, other
+/// service, etc.).
+pub trait AsyncHandler: Send + Sync + 'static {
+    /// Handle incoming message. Message is passed by value, since returned
+    /// future can outlive the call.
+    fn handle(&self, services: ServiceHub, session: ShareSession, msg: BytesMut) -> HandlerFuture;
+}
@@ -0,0 +1,86 @@
+use crate::llsd::session::identity::Identity;
+use crate::llsd::session::server::Session;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::HashSet;
+
+/// Predicate for `Policy::Custom`.
+pub type IdentityCheck = Box<dyn Fn(&Identity) -> bool + Send + Sync>;
+
+/// Who is allowed to use a route. Checked by `DynamicRouter` before route
+/// action is called.
+pub enum Policy {
+    /// Anyone who completed the handshake.
+    Anyone,
+    /// Clients that have the role.
+    Role(String),
+    /// Clients with one of these long-term keys.
+    Keys(HashSet<PublicKey>),
+    /// Clients whose identity passes the check.
+    Custom(IdentityCheck),
+}
+
+impl Policy {
+    /// Clients that have the role.
+    pub fn role<R: Into<String>>(role: R) -> Policy {
+        Policy::Role(role.into())
+    }
+
+    /// Clients with one of these long-term keys.
+    pub fn keys<I: IntoIterator<Item = PublicKey>>(keys: I) -> Policy {
+        Policy::Keys(keys.into_iter().collect())
+    }
+
+    /// Clients whose identity passes the check.
+    pub fn custom<F: Fn(&Identity) -> bool + Send + Sync + 'static>(check: F) -> Policy {
+        Policy::Custom(Box::new(check))
+    }
+
+    /// Check if owner of the session can use the route. Sessions that were
+    /// never authenticated can't use anything, no matter the policy.
+    pub fn allows(&self, session: &Session) -> bool {
+        let identity = match session.identity() {
+            Some(identity) => identity,
+            None => return false,
+        };
+        match *self {
+            Policy::Anyone => true,
+            Policy::Role(ref role) => identity.has_role(role),
+            Policy::Keys(ref keys) => {
+                session
+                    .client_public_key()
+                    .map_or(false, |pk| keys.contains(&pk))
+            }
+            Policy::Custom(ref check) => check(identity),
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::Anyone
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sodiumoxide::crypto::box_::gen_keypair;
+
+    #[test]
+    fn policies() {
+        let mut session = Session::default();
+        assert!(!Policy::Anyone.allows(&session));
+
+        session.set_identity(Identity::new("alice").with_role("admin"));
+        assert!(Policy::Anyone.allows(&session));
+        assert!(Policy::role("admin").allows(&session));
+        assert!(!Policy::role("billing").allows(&session));
+        assert!(Policy::custom(|identity| identity.user_id == Some("alice".to_owned()))
+                    .allows(&session));
+        assert!(!Policy::custom(|identity| identity.claim("team").is_some()).allows(&session));
+
+        // Session without client key is not in any key set.
+        let (pk, _) = gen_keypair();
+        assert!(!Policy::keys(vec![pk]).allows(&session));
+    }
+}
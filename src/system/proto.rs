@@ -0,0 +1,113 @@
+use super::{ServiceHub, ShareSession};
+use super::router::RouteAction;
+use bytes::{Bytes, BytesMut};
+use crate::errors::AWResult;
+use crate::llsd::proto::{decode, encode};
+use crate::llsd::route::Route;
+use prost::Message;
+use std::marker::PhantomData;
+
+/// Route action that takes and returns protobuf messages. Payload is decoded
+/// into `Req` before calling the function and its `Resp` is encoded back, so
+/// route can be registered with concrete types:
+///
+/// ```ignore
+/// router.register_route("calc::add",
+///                       ProtoAction::new(|add: Add| Ok(Sum { sum: add.left + add.right })))?;
+/// ```
+///
+/// Payload that isn't a valid `Req` is answered with
+/// `LlsdError::InvalidPayload`.
+pub struct ProtoAction<Req, Resp, F> {
+    action: F,
+    types: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp, F> ProtoAction<Req, Resp, F>
+where
+    Req: Message + Default + 'static,
+    Resp: Message + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    /// Wrap a function.
+    pub fn new(action: F) -> ProtoAction<Req, Resp, F> {
+        ProtoAction {
+            action: action,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp, F> RouteAction for ProtoAction<Req, Resp, F>
+where
+    Req: Message + Default + 'static,
+    Resp: Message + 'static,
+    F: Fn(Req) -> AWResult<Resp> + Send + Sync + 'static,
+{
+    fn process(&self,
+               _: &Route,
+               _: ServiceHub,
+               _: ShareSession,
+               msg: &mut BytesMut)
+               -> AWResult<Bytes> {
+        let req = decode(msg)?;
+        let resp = (self.action)(req)?;
+        Ok(encode(&resp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::AWError;
+    use crate::llsd::errors::LlsdError;
+    use crate::llsd::session::server::Session;
+    use crate::system::Services;
+    use std::sync::{Arc, RwLock};
+
+    #[derive(Clone, PartialEq, Debug, Message)]
+    struct Add {
+        #[prost(int64, required, tag = "1")]
+        left: i64,
+        #[prost(int64, required, tag = "2")]
+        right: i64,
+    }
+
+    #[derive(Clone, PartialEq, Debug, Message)]
+    struct Sum {
+        #[prost(int64, required, tag = "1")]
+        sum: i64,
+    }
+
+    fn add() -> ProtoAction<Add, Sum, fn(Add) -> AWResult<Sum>> {
+        fn add(add: Add) -> AWResult<Sum> {
+            match add.left.checked_add(add.right) {
+                Some(sum) => Ok(Sum { sum: sum }),
+                None => Err(AWError::NotImplemented),
+            }
+        }
+        ProtoAction::new(add as fn(Add) -> AWResult<Sum>)
+    }
+
+    fn call(action: &dyn RouteAction, payload: &[u8]) -> AWResult<Bytes> {
+        let hub = Arc::new(Services::default());
+        let session = Arc::new(RwLock::new(Session::default()));
+        action.process(&Route::from("calc::add"), hub, session, &mut BytesMut::from(payload))
+    }
+
+    #[test]
+    fn typed_action() {
+        let reply = call(&add(), &encode(&Add { left: 2, right: 3 })).unwrap();
+        assert_eq!(decode::<Sum>(&reply).unwrap(), Sum { sum: 5 });
+
+        match call(&add(), &encode(&Add { left: i64::MAX, right: 1 })) {
+            Err(AWError::NotImplemented) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        match call(&add(), &[0x08, 0xff]) {
+            Err(AWError::LlsdError(LlsdError::InvalidPayload(_))) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
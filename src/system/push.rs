@@ -0,0 +1,155 @@
+use crate::errors::{AWError, AWResult};
+use crate::llsd::POISONED_LOCK_MSG;
+use crate::llsd::frames::Frame;
+use crate::llsd::session::Sendable;
+use crate::llsd::session::server::Session;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::collections::HashMap;
+use std::default::Default;
+use std::sync::{Arc, RwLock};
+
+/// Something that can deliver a frame to a connected client without client
+/// asking for it. Implemented by transports that support it.
+pub trait PushSink: Send + Sync + 'static {
+    /// Queue frame for delivery. Returns false if connection is gone or can't
+    /// take any more frames.
+    fn deliver(&self, frame: Frame) -> bool;
+}
+
+/// Keeps track of which session is reachable through which connection. Handlers
+/// can find it in `ServiceHub`.
+#[derive(Clone)]
+pub struct PushRegistry {
+    sinks: Arc<RwLock<HashMap<PublicKey, Arc<dyn PushSink>>>>,
+}
+
+impl PushRegistry {
+    /// Route pushes for the session through the sink.
+    pub fn register(&self, session_id: PublicKey, sink: Arc<dyn PushSink>) {
+        self.sinks
+            .write()
+            .expect(POISONED_LOCK_MSG)
+            .insert(session_id, sink);
+    }
+
+    /// Forget about session. Usually called when session is closed.
+    pub fn unregister(&self, session_id: &PublicKey) {
+        self.sinks
+            .write()
+            .expect(POISONED_LOCK_MSG)
+            .remove(session_id);
+    }
+
+    /// Forget about session, but only if its pushes still go through `sink`.
+    /// Called when connection is closed: session could have moved to another
+    /// connection already. Returns true if session was forgotten.
+    pub fn release(&self, session_id: &PublicKey, sink: &Arc<dyn PushSink>) -> bool {
+        let mut sinks = self.sinks.write().expect(POISONED_LOCK_MSG);
+        match sinks.get(session_id) {
+            Some(registered) if Arc::ptr_eq(registered, sink) => {}
+            _ => return false,
+        }
+        sinks.remove(session_id);
+        true
+    }
+
+    /// Check if session has a connection that can take pushes.
+    pub fn is_connected(&self, session_id: &PublicKey) -> bool {
+        self.sinks
+            .read()
+            .expect(POISONED_LOCK_MSG)
+            .contains_key(session_id)
+    }
+
+    /// Encrypt data with the session and send it to the client as Message
+    /// frame.
+    pub fn push(&self, session: &Session, data: &[u8]) -> AWResult<()> {
+        let frame = session.make_message(data)?;
+        match self.sinks
+                  .read()
+                  .expect(POISONED_LOCK_MSG)
+                  .get(&session.id()) {
+            Some(sink) if sink.deliver(frame) => Ok(()),
+            _ => Err(AWError::NotConnected),
+        }
+    }
+}
+
+impl Default for PushRegistry {
+    fn default() -> PushRegistry {
+        PushRegistry { sinks: Arc::new(RwLock::new(HashMap::new())) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::llsd::session::client::Session as ClientSession;
+    use crate::llsd::session::loopback::handshake;
+    use sodiumoxide::crypto::box_::gen_keypair;
+    use std::sync::Mutex;
+
+    struct VecSink(Mutex<Vec<Frame>>);
+    impl PushSink for VecSink {
+        fn deliver(&self, frame: Frame) -> bool {
+            self.0.lock().unwrap().push(frame);
+            true
+        }
+    }
+
+    fn ready_pair() -> (ClientSession, Session) {
+        let client_lt = gen_keypair();
+        let server_lt = gen_keypair();
+
+        let mut client_session = ClientSession::new(server_lt.0, client_lt);
+        let (server_session, _) = handshake(&mut client_session, &server_lt).unwrap();
+        (client_session, server_session)
+    }
+
+    #[test]
+    fn push_not_connected() {
+        let registry = PushRegistry::default();
+        let (_, server_session) = ready_pair();
+
+        match registry.push(&server_session, b"wat") {
+            Err(AWError::NotConnected) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn push_and_read() {
+        let registry = PushRegistry::default();
+        let (client_session, server_session) = ready_pair();
+        let sink = Arc::new(VecSink(Mutex::new(Vec::new())));
+
+        registry.register(server_session.id(), sink.clone());
+        assert!(registry.is_connected(&server_session.id()));
+        registry.push(&server_session, b"news").unwrap();
+
+        let frames = sink.0.lock().unwrap();
+        assert_eq!(frames.len(), 1);
+        let msg = client_session.read_msg(&frames[0]).unwrap();
+        assert_eq!(msg.as_ref(), b"news");
+
+        registry.unregister(&server_session.id());
+        assert!(!registry.is_connected(&server_session.id()));
+    }
+
+    #[test]
+    fn release_only_own_sink() {
+        let registry = PushRegistry::default();
+        let (_, server_session) = ready_pair();
+        let old: Arc<dyn PushSink> = Arc::new(VecSink(Mutex::new(Vec::new())));
+        let new: Arc<dyn PushSink> = Arc::new(VecSink(Mutex::new(Vec::new())));
+
+        registry.register(server_session.id(), old.clone());
+        registry.register(server_session.id(), new.clone());
+        // Session moved to another connection, old one can't take it away.
+        assert!(!registry.release(&server_session.id(), &old));
+        assert!(registry.is_connected(&server_session.id()));
+
+        assert!(registry.release(&server_session.id(), &new));
+        assert!(!registry.is_connected(&server_session.id()));
+    }
+}
@@ -1,16 +1,16 @@
 use super::{Handler, ServiceHub};
+use super::policy::Policy;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{Bytes, BytesMut};
-use errors::{AWError, AWResult};
-use llsd::route::Route;
-use llsd::session::server::Session;
+use crate::errors::{AWError, AWResult};
+use crate::llsd::POISONED_LOCK_MSG;
+use crate::llsd::route::Route;
+use crate::llsd::session::server::Session;
 use std::collections::HashMap;
 use std::convert::From;
 use std::default::Default;
 use std::sync::{Arc, RwLock};
 
-const POISONED_LOCK_MSG: &'static str = "Lock was poisoned";
-
 pub trait Router: Send + Sync + 'static {
     fn route_from_payload(&self, payload: &mut BytesMut) -> AWResult<Route> {
         if payload.len() < 8 {
@@ -49,15 +49,67 @@ pub trait RouteAction: Send + Sync + 'static {
                msg: &mut BytesMut)
                -> AWResult<Bytes>;
 }
+// Route action, who can call it and name it was registered under.
+struct Registered {
+    name: String,
+    policy: Policy,
+    action: Box<dyn RouteAction>,
+}
+
 pub struct DynamicRouter {
-    store: Arc<RwLock<HashMap<Route, Box<RouteAction>>>>,
+    store: Arc<RwLock<HashMap<Route, Registered>>>,
 }
 impl DynamicRouter {
-    pub fn register_route<R: Into<Route>, H: RouteAction>(&self, route: R, handler: H) {
+    /// Register route that anyone who completed the handshake can use.
+    /// Fails if route with this name is already registered or if name hashes
+    /// to the same route as another one.
+    pub fn register_route<N: Into<String>, H: RouteAction>(&self,
+                                                           name: N,
+                                                           handler: H)
+                                                           -> AWResult<()> {
+        self.register_route_with_policy(name, Policy::Anyone, handler)
+    }
+
+    /// Register route that only clients allowed by `policy` can use. Others
+    /// get `AWError::PermissionDenied`.
+    pub fn register_route_with_policy<N: Into<String>, H: RouteAction>(&self,
+                                                                       name: N,
+                                                                       policy: Policy,
+                                                                       handler: H)
+                                                                       -> AWResult<()> {
+        let name = name.into();
+        let route = Route::from(name.clone());
+        let mut store = self.store.write().expect(POISONED_LOCK_MSG);
+        if let Some(existing) = store.get(&route) {
+            return Err(AWError::RouteCollision(name, existing.name.clone()));
+        }
+        let registered = Registered {
+            name: name,
+            policy: policy,
+            action: Box::new(handler),
+        };
+        store.insert(route, registered);
+        Ok(())
+    }
+
+    /// Name route was registered under.
+    pub fn route_name(&self, route: &Route) -> Option<String> {
         self.store
-            .write()
+            .read()
             .expect(POISONED_LOCK_MSG)
-            .insert(route.into(), Box::new(handler));
+            .get(route)
+            .map(|registered| registered.name.clone())
+    }
+
+    /// Every registered route with its name, sorted by name.
+    pub fn routes(&self) -> Vec<(String, Route)> {
+        let store = self.store.read().expect(POISONED_LOCK_MSG);
+        let mut routes: Vec<(String, Route)> = store
+            .iter()
+            .map(|(route, registered)| (registered.name.clone(), route.clone()))
+            .collect();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+        routes
     }
 }
 
@@ -76,7 +128,16 @@ impl Router for DynamicRouter {
                -> AWResult<Bytes> {
         match self.store.read().expect(POISONED_LOCK_MSG).get(&route) {
             None => Err(AWError::NotImplemented),
-            Some(handler) => handler.process(&route, services, session, msg),
+            Some(registered) => {
+                let allowed = match session.read() {
+                    Ok(session) => registered.policy.allows(&session),
+                    Err(_) => return Err(AWError::ServerFault),
+                };
+                if !allowed {
+                    return Err(AWError::PermissionDenied);
+                }
+                registered.action.process(&route, services, session, msg)
+            }
         }
     }
 }
@@ -85,26 +146,30 @@ impl Router for DynamicRouter {
 mod test {
     use super::*;
     use byteorder::{BigEndian, WriteBytesExt};
-    use errors::AWResult;
-    use llsd::route::Route;
-    use llsd::session::server::Session;
+    use crate::errors::AWResult;
+    use crate::llsd::route::Route;
+    use crate::llsd::session::identity::Identity;
+    use crate::llsd::session::server::Session;
 
 
     use std::sync::{Arc, RwLock};
-    use system::{Handler, ServiceHub};
+    use crate::system::{Handler, ServiceHub, Services};
+
 
-    use typemap::TypeMap;
+    const ROUTE_NAME: &str = "system::test";
 
     fn get_route() -> Route {
-        Route::from("system::test")
+        Route::from(ROUTE_NAME)
     }
 
     fn get_hub() -> ServiceHub {
-        Arc::new(RwLock::new(TypeMap::custom()))
+        Arc::new(Services::default())
     }
 
     fn get_session() -> Arc<RwLock<Session>> {
-        Arc::new(RwLock::new(Session::default()))
+        let mut session = Session::default();
+        session.set_identity(Identity::new("tester"));
+        Arc::new(RwLock::new(session))
     }
 
     pub struct EchoAction;
@@ -203,7 +268,7 @@ mod test {
     #[test]
     fn dynamic_router() {
         let router = DynamicRouter::default();
-        router.register_route(get_route(), EchoAction::default());
+        router.register_route(ROUTE_NAME, EchoAction::default()).unwrap();
 
         let mut req_not_found = Vec::new();
         req_not_found
@@ -223,4 +288,74 @@ mod test {
         let pong = pong_res.unwrap();
         assert_eq!(pong.as_ref(), b"pong");
     }
+
+    #[test]
+    fn dynamic_router_policy() {
+        let router = DynamicRouter::default();
+        router
+            .register_route_with_policy("admin", Policy::role("admin"), EchoAction::default())
+            .unwrap();
+
+        let mut req = Vec::new();
+        req.write_u64::<BigEndian>(Route::from("admin").as_u64()).unwrap();
+        req.append(&mut b"ping".to_vec());
+
+        match router.handle(get_hub(), get_session(), &mut req.clone().into()) {
+            Err(AWError::PermissionDenied) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        let admin = get_session();
+        admin
+            .write()
+            .unwrap()
+            .set_identity(Identity::new("root").with_role("admin"));
+        let pong = router.handle(get_hub(), admin, &mut req.clone().into()).unwrap();
+        assert_eq!(pong.as_ref(), b"pong");
+
+        // Session that never got through authentication can't use even open
+        // routes.
+        router.register_route(ROUTE_NAME, EchoAction::default()).unwrap();
+        let mut req = Vec::new();
+        req.write_u64::<BigEndian>(get_route().as_u64()).unwrap();
+        let stranger = Arc::new(RwLock::new(Session::default()));
+        match router.handle(get_hub(), stranger, &mut req.into()) {
+            Err(AWError::PermissionDenied) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+
+    #[test]
+    fn route_names() {
+        let router = DynamicRouter::default();
+        router.register_route("zebra", EchoAction::default()).unwrap();
+        router.register_route(ROUTE_NAME, EchoAction::default()).unwrap();
+
+        match router.register_route(ROUTE_NAME, EchoAction::default()) {
+            Err(AWError::RouteCollision(ref name, ref existing)) if name == ROUTE_NAME &&
+                                                                  existing == ROUTE_NAME => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        // Two names hashing to one route. Real collision is hard to find, so
+        // plant "a" under the route of "b".
+        let planted = Registered {
+            name: "a".to_owned(),
+            policy: Policy::Anyone,
+            action: Box::new(EchoAction::default()),
+        };
+        router.store.write().unwrap().insert(Route::from("b"), planted);
+        match router.register_route("b", EchoAction::default()) {
+            Err(AWError::RouteCollision(ref name, ref existing)) if name == "b" &&
+                                                                  existing == "a" => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+        router.store.write().unwrap().remove(&Route::from("b"));
+
+        assert_eq!(router.route_name(&get_route()), Some(ROUTE_NAME.to_owned()));
+        assert_eq!(router.route_name(&Route::from("cnn")), None);
+        assert_eq!(router.routes(),
+                   vec![(ROUTE_NAME.to_owned(), get_route()),
+                        ("zebra".to_owned(), Route::from("zebra"))]);
+    }
 }
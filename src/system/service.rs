@@ -0,0 +1,222 @@
+use super::{ServiceHub, ShareSession};
+use super::router::RouteAction;
+use bytes::{Bytes, BytesMut};
+use crate::errors::AWResult;
+use crate::llsd::client::{FromBytes, IntoBytes};
+use crate::llsd::route::Route;
+use std::sync::Arc;
+
+/// Define an RPC service: trait for the server side and typed client stub
+/// over `Engine`. Route of every method is `"<Trait>::<method>"` on both
+/// sides, so they can't drift apart.
+///
+/// ```ignore
+/// service! {
+///     /// Calculator.
+///     pub trait Calc {
+///         /// Add two numbers.
+///         fn add(&self, session: &ShareSession, req: Proto<Add>) -> AWResult<Proto<Sum>>;
+///     }
+///     /// Client for `Calc`.
+///     pub client CalcClient;
+/// }
+///
+/// // Server.
+/// impl Calc for Calculator { ... }
+/// Calculator.register_routes(&router)?;
+///
+/// // Client.
+/// let mut calc = CalcClient::new(engine);
+/// let sum = core.run(calc.add(Proto(Add { left: 1, right: 2 })))?;
+/// ```
+///
+/// Every method takes the caller's session and one request and returns one
+/// response. Request has to be `FromBytes` and response `IntoBytes` for the
+/// server, the other way around for the client: `Bytes`, `Proto<M>` and
+/// serde wrappers like `Json<T>` are both. Generated trait also gets
+/// `register_routes` method, don't use that name for service methods.
+#[macro_export]
+macro_rules! service {
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $name:ident {
+            $(
+                $(#[$method_attr:meta])*
+                fn $method:ident(&self,
+                                 $session:ident: &ShareSession,
+                                 $req:ident: $req_ty:ty)
+                                 -> AWResult<$resp_ty:ty>;
+            )*
+        }
+        $(#[$client_attr:meta])*
+        $client_vis:vis client $client:ident;
+    ) => {
+        $(#[$attr])*
+        $vis trait $name: Send + Sync + 'static {
+            $(
+                $(#[$method_attr])*
+                fn $method(&self,
+                           $session: &$crate::system::ShareSession,
+                           $req: $req_ty)
+                           -> $crate::errors::AWResult<$resp_ty>;
+            )*
+
+            /// Register every method of the service in `router`.
+            fn register_routes(self,
+                               router: &$crate::system::router::DynamicRouter)
+                               -> $crate::errors::AWResult<()>
+            where
+                Self: Sized,
+            {
+                #[allow(unused_variables)]
+                let service = ::std::sync::Arc::new(self);
+                $(
+                    router.register_route(concat!(stringify!($name), "::", stringify!($method)),
+                                          $crate::system::service::Method::new(service.clone(),
+                                                                               Self::$method))?;
+                )*
+                Ok(())
+            }
+        }
+
+        $(#[$client_attr])*
+        $client_vis struct $client<E> {
+            engine: E,
+        }
+
+        impl<E: $crate::llsd::client::Engine> $client<E> {
+            /// Make calls through `engine`.
+            pub fn new(engine: E) -> $client<E> {
+                $client { engine: engine }
+            }
+
+            /// Engine calls go through.
+            pub fn engine(&mut self) -> &mut E {
+                &mut self.engine
+            }
+
+            /// Get engine back.
+            pub fn into_inner(self) -> E {
+                self.engine
+            }
+
+            $(
+                $(#[$method_attr])*
+                pub fn $method(&mut self,
+                               $req: $req_ty)
+                               -> $crate::llsd::client::RequestResult<$resp_ty> {
+                    let route = $crate::llsd::route::Route::from(concat!(stringify!($name),
+                                                                         "::",
+                                                                         stringify!($method)));
+                    $crate::llsd::client::EngineSugar::request(&mut self.engine, Some(route), $req)
+                }
+            )*
+        }
+    };
+}
+
+/// Route action for one method of a service defined with `service!`.
+pub struct Method<S, Req, Resp> {
+    service: Arc<S>,
+    call: fn(&S, &ShareSession, Req) -> AWResult<Resp>,
+}
+
+impl<S, Req, Resp> Method<S, Req, Resp> {
+    /// Call `call` on `service`.
+    pub fn new(service: Arc<S>,
+               call: fn(&S, &ShareSession, Req) -> AWResult<Resp>)
+               -> Method<S, Req, Resp> {
+        Method {
+            service: service,
+            call: call,
+        }
+    }
+}
+
+impl<S, Req, Resp> RouteAction for Method<S, Req, Resp>
+where
+    S: Send + Sync + 'static,
+    Req: FromBytes + 'static,
+    Resp: IntoBytes + 'static,
+{
+    fn process(&self,
+               _: &Route,
+               _: ServiceHub,
+               session: ShareSession,
+               msg: &mut BytesMut)
+               -> AWResult<Bytes> {
+        let req = Req::from(msg.take())?;
+        let resp = (self.call)(&self.service, &session, req)?;
+        Ok(resp.into_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // Client stub is only exercised in integration tests.
+    #![allow(dead_code)]
+    use byteorder::{BigEndian, WriteBytesExt};
+    use bytes::{Bytes, BytesMut};
+    use crate::errors::{AWError, AWResult};
+    use crate::llsd::route::Route;
+    use crate::llsd::session::identity::Identity;
+    use crate::llsd::session::server::Session;
+    use crate::system::{Handler, Services, ShareSession, identity};
+    use crate::system::router::DynamicRouter;
+    use std::sync::{Arc, RwLock};
+
+    service! {
+        /// Says things back.
+        pub trait Parrot {
+            /// Repeat after the caller.
+            fn repeat(&self, session: &ShareSession, req: Bytes) -> AWResult<Bytes>;
+            /// Name of the caller.
+            fn who(&self, session: &ShareSession, req: Bytes) -> AWResult<Bytes>;
+        }
+        /// Client for `Parrot`.
+        pub client ParrotClient;
+    }
+
+    struct Polly;
+    impl Parrot for Polly {
+        fn repeat(&self, _: &ShareSession, req: Bytes) -> AWResult<Bytes> {
+            Ok(req)
+        }
+
+        fn who(&self, session: &ShareSession, _: Bytes) -> AWResult<Bytes> {
+            identity(session)
+                .and_then(|identity| identity.user_id)
+                .map(Bytes::from)
+                .ok_or(AWError::PermissionDenied)
+        }
+    }
+
+    fn call(router: &DynamicRouter, name: &str, payload: &[u8]) -> AWResult<Bytes> {
+        let mut session = Session::default();
+        session.set_identity(Identity::new("alice"));
+        let mut req = Vec::new();
+        req.write_u64::<BigEndian>(Route::from(name).as_u64()).unwrap();
+        req.extend_from_slice(payload);
+        router.handle(Arc::new(Services::default()),
+                      Arc::new(RwLock::new(session)),
+                      &mut BytesMut::from(req))
+    }
+
+    #[test]
+    fn registered_routes() {
+        let router = DynamicRouter::default();
+        Polly.register_routes(&router).unwrap();
+
+        let names: Vec<String> = router.routes().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["Parrot::repeat", "Parrot::who"]);
+
+        assert_eq!(call(&router, "Parrot::repeat", b"hello").unwrap().as_ref(), b"hello");
+        assert_eq!(call(&router, "Parrot::who", b"").unwrap().as_ref(), b"alice");
+
+        // Same service can't be registered twice.
+        match Polly.register_routes(&router) {
+            Err(AWError::RouteCollision(..)) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+    }
+}
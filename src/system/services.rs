@@ -0,0 +1,96 @@
+use crate::errors::{AWError, AWResult};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Shared things handlers need: database pools, caches, configuration and
+/// so on. One service per type, looked up by that type.
+///
+/// Filled before system is started and never changed after that, so
+/// handlers read it without taking any locks. Service that has to change at
+/// runtime takes care of it on its own, like `PushRegistry` does.
+#[derive(Clone, Default)]
+pub struct Services {
+    services: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Services {
+    /// Registry without services.
+    pub fn new() -> Services {
+        Services::default()
+    }
+
+    /// Add a service. Service of the same type is replaced.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, service: T) {
+        self.services.insert(TypeId::of::<T>(), Arc::new(service));
+    }
+
+    /// Same as `insert`, for building registry in one expression.
+    pub fn with<T: Send + Sync + 'static>(mut self, service: T) -> Services {
+        self.insert(service);
+        self
+    }
+
+    /// Service of the type, if there is one.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.services
+            .get(&TypeId::of::<T>())
+            .and_then(|service| service.downcast_ref::<T>())
+    }
+
+    /// Same as `get`, but service can be kept after request is handled.
+    pub fn get_shared<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.services
+            .get(&TypeId::of::<T>())
+            .and_then(|service| service.clone().downcast::<T>().ok())
+    }
+
+    /// Service handler can't work without. Missing service is a server
+    /// misconfiguration, so it's reported to the client as
+    /// `AWError::ServerFault`.
+    pub fn require<T: Send + Sync + 'static>(&self) -> AWResult<&T> {
+        self.get::<T>().ok_or(AWError::ServerFault)
+    }
+
+    /// Check if service of the type is registered.
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.services.contains_key(&TypeId::of::<T>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq)]
+    struct Config {
+        name: &'static str,
+    }
+
+    #[derive(Default)]
+    struct Cache(Mutex<Vec<u32>>);
+
+    #[test]
+    fn typed_lookup() {
+        let services = Services::new()
+            .with(Config { name: "first" })
+            .with(Cache::default())
+            .with(Config { name: "second" });
+
+        assert_eq!(services.get::<Config>(), Some(&Config { name: "second" }));
+        assert!(services.contains::<Cache>());
+        assert!(!services.contains::<String>());
+        assert!(services.get::<String>().is_none());
+        match services.require::<String>() {
+            Err(AWError::ServerFault) => {}
+            _ => panic!("WRONG ERROR KIND"),
+        }
+
+        // Services are shared, not copied.
+        let cache = services.get_shared::<Cache>().unwrap();
+        cache.0.lock().unwrap().push(42);
+        let copy = services.clone();
+        assert_eq!(*copy.require::<Cache>().unwrap().0.lock().unwrap(), vec![42]);
+    }
+}
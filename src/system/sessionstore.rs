@@ -1,21 +1,79 @@
 
 
-pub use llsd::session::server::Session;
+use crate::errors::AWResult;
+pub use crate::llsd::session::server::{ReapReason, Session};
 use sodiumoxide::crypto::box_::PublicKey;
+use std::ops::AddAssign;
 use std::sync::{Arc, RwLock};
+
+/// How many sessions reaper removed, by reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReapStats {
+    /// Sessions that lived past their expiration time.
+    pub expired: usize,
+    /// Sessions that didn't receive any messages for too long.
+    pub idle: usize,
+    /// Sessions that ended up in Error state.
+    pub errored: usize,
+    /// Sessions that never completed the handshake.
+    pub abandoned: usize,
+}
+
+impl ReapStats {
+    /// Count one more removed session.
+    pub fn record(&mut self, reason: ReapReason) {
+        match reason {
+            ReapReason::Expired => self.expired += 1,
+            ReapReason::Idle => self.idle += 1,
+            ReapReason::Errored => self.errored += 1,
+            ReapReason::Abandoned => self.abandoned += 1,
+        }
+    }
+
+    /// Sessions removed for any reason.
+    pub fn total(&self) -> usize {
+        self.expired + self.idle + self.errored + self.abandoned
+    }
+}
+
+impl AddAssign for ReapStats {
+    fn add_assign(&mut self, other: ReapStats) {
+        self.expired += other.expired;
+        self.idle += other.idle;
+        self.errored += other.errored;
+        self.abandoned += other.abandoned;
+    }
+}
+
 /// This `Trait` defines session storage.
 pub trait SessionStore: Clone + Send + Sync {
     /// Look up session by its id
     fn find_by_pk(&self, key: &PublicKey) -> Option<Arc<RwLock<Session>>>;
 
-    /// Shortcut to lookup session from uuid in `&[u8]` representation
+    /// Shortcut to lookup session from uuid in `&[u8]` representation. Malformed
+    /// bytes never match any session.
     fn find(&self, bytes: &[u8]) -> Option<Arc<RwLock<Session>>> {
-        let key = PublicKey::from_slice(bytes).expect("Malformed bytes were passed as PublicKey");
-        self.find_by_pk(&key)
+        PublicKey::from_slice(bytes).and_then(|key| self.find_by_pk(&key))
     }
     /// Try to insert session in to the store. If session already exists in the
     /// store — return
     /// None, else return `()`
     fn insert(&self, session: Session) -> Option<()>;
+    /// Same as `insert`, but only if there are less than `limit` sessions in
+    /// the store. Check and insert happen atomically, so concurrent inserts
+    /// can't go over the limit. Fails with `AWError::TooManySessions` if store
+    /// is full and with `LlsdError::InvalidSessionState` if session can't be
+    /// inserted.
+    fn try_insert_bounded(&self, session: Session, limit: usize) -> AWResult<()>;
     fn destroy(&self, key: &PublicKey);
+    /// Number of sessions in the store, including ones waiting for reaper.
+    fn len(&self) -> usize;
+    /// Check if there are no sessions in the store.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Remove and destroy every session that is expired, errored or never
+    /// finished the handshake. Returns what was removed and ids of removed
+    /// sessions.
+    fn reap(&self) -> (ReapStats, Vec<PublicKey>);
 }
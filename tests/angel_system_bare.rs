@@ -5,15 +5,30 @@ extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_service;
 extern crate futures;
-use angel_whisper::{AngelSystem, ClientSession, Sendable};
+extern crate sodiumoxide;
+use angel_whisper::{AngelSystem, ClientSession, Sendable, SessionConfig};
+use angel_whisper::angel_system::Config;
 
-use angel_whisper::crypto::gen_keypair;
-use angel_whisper::frames::FrameKind;
-use angel_whisper::system::authenticator::DumbAuthenticator;
+use angel_whisper::crypto::{PublicKey, gen_keypair, gen_nonce};
+use angel_whisper::errors::{AWError, AWResult};
+use angel_whisper::frames::{AppError, ErrorCode, Frame, FrameKind, Termination};
+use angel_whisper::llsd::errors::LlsdError;
+use sodiumoxide::randombytes::randombytes;
+use angel_whisper::system::authenticator::{Authenticator, DumbAuthenticator, Identity};
 use angel_whisper::system::hashmapstore::HashMapStore;
+use angel_whisper::system::sessionstore::SessionStore;
+use angel_whisper::system::{Handler, ServiceHub, Services, ShareSession};
+use angel_whisper::system::push::{PushRegistry, PushSink};
+use angel_whisper::system::policy::Policy;
+use angel_whisper::system::router::{DynamicRouter, RouteAction};
+use angel_whisper::llsd::route::Route;
+use bytes::{Bytes, BytesMut};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 mod support;
-use support::service::EchoHandler;
+use support::service::{EchoHandler, WhoAmIHandler};
 
 #[test]
 fn handshake_and_ping_pong() {
@@ -56,7 +71,647 @@ fn handshake_and_ping_pong() {
     assert!(pong_result.is_ok());
 
     let message_frame = pong_result.unwrap();
-    let pong_payload = session.read_msg(&message_frame).unwrap();
+    let pong_payload = session.read_reply(&message_frame).unwrap();
     assert_eq!(pong_payload, b"pong".to_vec());
 
 }
+
+#[test]
+fn max_sessions() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let config = Config {
+        max_sessions: Some(1),
+        ..Config::default()
+    };
+
+    let system = AngelSystem::with_config(store,
+                                          authenticator,
+                                          server_pk,
+                                          server_sk,
+                                          EchoHandler::default(),
+                                          config);
+
+    let mut first = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+    let welcome = system.process(first.make_hello()).unwrap();
+    let ready = system.process(first.make_initiate(&welcome).unwrap()).unwrap();
+    assert!(first.read_ready(&ready).is_ok());
+
+    // Server keeps nothing for Hello, so the limit is only hit on Initiate.
+    let mut second = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(second.make_hello()).unwrap();
+    match system.process(second.make_initiate(&welcome).unwrap()) {
+        Err(AWError::TooManySessions) => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+}
+
+#[test]
+fn cookie_is_bound_to_session() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut first = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+    let welcome = system.process(first.make_hello()).unwrap();
+    let initiate = first.make_initiate(&welcome).unwrap();
+
+    // Same Initiate under another session id carries a cookie that isn't
+    // made for it.
+    let second = ClientSession::new(server_pk, (our_pk, our_sk));
+    let stolen = Frame { id: second.id(), ..initiate.clone() };
+    match system.process(stolen) {
+        Err(AWError::LlsdError(LlsdError::InvalidCookie)) => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+
+    assert!(system.process(initiate).is_ok());
+}
+
+#[test]
+fn termination_frames() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let initiate = session.make_initiate(&welcome).unwrap();
+    let ready = system.process(initiate).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    // Handler error comes back in reply, session stays as it was.
+    let reply = system
+        .process(session.make_message(b"wat").unwrap())
+        .unwrap();
+    assert_eq!(reply.kind, FrameKind::Message);
+    match session.read_reply(&reply) {
+        Err(LlsdError::Application(ref err)) if err.code == ErrorCode::NotImplemented => {}
+        other => panic!("Expected NotImplemented, got {:?}", other),
+    }
+
+    // Session is ready, so termination is encrypted.
+    let frame = system.terminate(&session.id(), &AWError::InvalidRoute);
+    assert_eq!(frame.kind, FrameKind::Termination);
+    let mut tampered = frame.clone();
+    let mut payload = tampered.payload.to_vec();
+    payload[0] ^= 1;
+    tampered.payload = payload.into();
+    assert!(session.read_termination(&tampered).is_err());
+    let termination = session.read_termination(&frame).unwrap();
+    assert_eq!(termination.code, ErrorCode::InvalidRoute);
+    // Plain one could come from anybody.
+    let forged = Termination::new(ErrorCode::InvalidRoute, "").to_plain_frame(session.id());
+    match session.read_termination(&forged) {
+        Err(LlsdError::BadFrame) => {}
+        other => panic!("Expected BadFrame, got {:?}", other),
+    }
+    // Unless it says session is gone: that's all server can say once it lost
+    // the session. Good only for a new handshake.
+    let gone = Termination::new(ErrorCode::ExpiredSession, "trust me").to_plain_frame(session.id());
+    assert_eq!(session.read_termination(&gone).unwrap(),
+               Termination::new(ErrorCode::ExpiredSession, ""));
+
+    // Server doesn't know this session, nothing to encrypt with.
+    let stranger = ClientSession::new(server_pk, (our_pk, our_sk));
+    let frame = system.terminate(&stranger.id(), &AWError::SessionNotFound);
+    assert_eq!(Termination::from_slice(&frame.payload).unwrap().code,
+               ErrorCode::UnknownSession);
+    let termination = stranger.read_termination(&frame).unwrap();
+    assert_eq!(termination.code, ErrorCode::UnknownSession);
+}
+
+#[test]
+fn termination_closes_session() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let initiate = session.make_initiate(&welcome).unwrap();
+    let ready = system.process(initiate).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    // Plain termination could come from anybody.
+    let forged = Termination::new(ErrorCode::SessionClosed, "").to_plain_frame(session.id());
+    assert!(system.process(forged).is_err());
+    assert!(system.process(session.make_message(b"ping").unwrap()).is_ok());
+
+    let bye = Termination::new(ErrorCode::SessionClosed, "bye");
+    let ack = system.process(session.make_termination(&bye)).unwrap();
+    assert_eq!(ack.kind, FrameKind::Termination);
+    assert_eq!(session.read_termination(&ack).unwrap().code, ErrorCode::SessionClosed);
+
+    match system.process(session.make_message(b"ping").unwrap()) {
+        Err(AWError::LlsdError(LlsdError::InvalidSessionState)) => {}
+        other => panic!("Expected InvalidSessionState, got {:?}", other),
+    }
+}
+
+#[test]
+fn initiate_replay_after_close() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = AngelSystem::new(HashMapStore::default(),
+                                  DumbAuthenticator::new(vec![our_pk]),
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let initiate = session.make_initiate(&welcome).unwrap();
+    let ready = system.process(initiate.clone()).unwrap();
+    session.read_ready(&ready).unwrap();
+    let ping = session.make_message(b"ping").unwrap();
+    assert!(system.process(ping.clone()).is_ok());
+
+    let bye = Termination::new(ErrorCode::SessionClosed, "bye");
+    system.process(session.make_termination(&bye)).unwrap();
+
+    // Cookie is still valid, but it was spent already. Otherwise recorded
+    // messages could be replayed to the brand new copy of the session.
+    match system.process(initiate) {
+        Err(AWError::LlsdError(LlsdError::ReplayedFrame)) => {}
+        other => panic!("Expected ReplayedFrame, got {:?}", other),
+    }
+    assert!(system.process(ping).is_err());
+}
+
+#[test]
+fn lost_session_asks_for_handshake() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let system = AngelSystem::new(store.clone(),
+                                  DumbAuthenticator::new(vec![our_pk]),
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    // Server forgot the session, so it can only answer in plain text.
+    store.destroy(&session.id());
+    let err = system
+        .process(session.make_message(b"ping").unwrap())
+        .unwrap_err();
+    let frame = system.terminate(&session.id(), &err);
+    match session.read_termination(&frame).unwrap().into_error() {
+        LlsdError::SessionRejected => {}
+        other => panic!("Expected SessionRejected, got {:?}", other),
+    }
+}
+
+#[test]
+fn server_frames_are_rejected() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    match system.process(welcome.clone()) {
+        Err(AWError::LlsdError(LlsdError::UnexpectedFrame(FrameKind::Welcome))) => {}
+        other => panic!("Expected UnexpectedFrame, got {:?}", other),
+    }
+    let initiate = session.make_initiate(&welcome).unwrap();
+    let ready = system.process(initiate).unwrap();
+    match system.process(ready.clone()) {
+        Err(AWError::LlsdError(LlsdError::UnexpectedFrame(FrameKind::Ready))) => {}
+        other => panic!("Expected UnexpectedFrame, got {:?}", other),
+    }
+    session.read_ready(&ready).unwrap();
+    assert!(system.process(session.make_message(b"ping").unwrap()).is_ok());
+}
+
+// Throw frames of every kind with random payloads at sessions in every state.
+// Nothing may panic, nothing but a proper handshake may succeed and the ready
+// session has to survive all of it.
+#[test]
+fn arbitrary_frames() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  server_pk,
+                                  server_sk,
+                                  EchoHandler::default());
+
+    let mut ready = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+    let welcome = system.process(ready.make_hello()).unwrap();
+    let initiate = ready.make_initiate(&welcome).unwrap();
+    let ready_frame = system.process(initiate).unwrap();
+    ready.read_ready(&ready_frame).unwrap();
+
+    let fresh = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+    system.process(fresh.make_hello()).unwrap();
+
+    let stranger = ClientSession::new(server_pk, (our_pk, our_sk));
+
+    let kinds = [FrameKind::Hello,
+                 FrameKind::Welcome,
+                 FrameKind::Initiate,
+                 FrameKind::Ready,
+                 FrameKind::Message,
+                 FrameKind::Termination];
+    let ids = [ready.id(), fresh.id(), stranger.id()];
+    for round in 0..100 {
+        for kind in kinds.iter() {
+            for id in ids.iter() {
+                let len = randombytes(1)[0] as usize * (round % 4);
+                let frame = Frame {
+                    id: *id,
+                    nonce: gen_nonce(),
+                    kind: *kind,
+                    payload: randombytes(len).into(),
+                };
+                assert!(system.process(frame).is_err());
+            }
+        }
+    }
+
+    let pong = system
+        .process(ready.make_message(b"ping").unwrap())
+        .unwrap();
+    assert_eq!(ready.read_reply(&pong).unwrap(), b"pong".to_vec());
+}
+
+#[test]
+fn key_rotation() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let old = gen_keypair();
+    let new = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = AngelSystem::new(store,
+                                  authenticator,
+                                  old.0,
+                                  old.1.clone(),
+                                  EchoHandler::default());
+
+    let handshake = |server_pk: PublicKey| -> AWResult<ClientSession> {
+        let mut session = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+        let welcome = system.process(session.make_hello())?;
+        let ready = system.process(session.make_initiate(&welcome)?)?;
+        session.read_ready(&ready)?;
+        Ok(session)
+    };
+
+    let established = handshake(old.0).unwrap();
+    assert!(handshake(new.0).is_err());
+
+    // Both keys work while old one is retiring.
+    system.rotate_key(new.clone()).unwrap();
+    assert_eq!(system.public_keys(), vec![new.0, old.0]);
+    assert!(handshake(old.0).is_ok());
+    assert!(handshake(new.0).is_ok());
+
+    assert!(!system.retire_key(&new.0).unwrap());
+    assert!(system.retire_key(&old.0).unwrap());
+    match handshake(old.0) {
+        Err(AWError::LlsdError(LlsdError::HandshakeFailed)) => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+    assert!(handshake(new.0).is_ok());
+
+    // Sessions made with retired key are still good.
+    let ping = established.make_message(b"ping").unwrap();
+    assert!(system.process(ping).is_ok());
+}
+
+#[derive(Clone)]
+struct AdminAuthenticator(PublicKey);
+impl Authenticator for AdminAuthenticator {
+    fn authenticate(&self, key: &PublicKey) -> Option<Identity> {
+        if *key == self.0 {
+            Some(Identity::new("alice").with_role("admin"))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn identity_reaches_handler() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let system = AngelSystem::new(store.clone(),
+                                  AdminAuthenticator(our_pk),
+                                  server_pk,
+                                  server_sk,
+                                  WhoAmIHandler);
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    let server_session = store.find_by_pk(&session.id()).unwrap();
+    {
+        let server_session = server_session.read().unwrap();
+        assert_eq!(server_session.client_public_key(), Some(our_pk));
+        assert!(server_session.identity().unwrap().has_role("admin"));
+    }
+
+    let reply = system
+        .process(session.make_message(b"who am i").unwrap())
+        .unwrap();
+    assert_eq!(session.read_reply(&reply).unwrap(), b"alice".to_vec());
+}
+
+struct Pong;
+impl RouteAction for Pong {
+    fn process(&self,
+               _: &Route,
+               _: ServiceHub,
+               _: ShareSession,
+               _: &mut BytesMut)
+               -> AWResult<Bytes> {
+        Ok(Bytes::from(&b"pong"[..]))
+    }
+}
+
+#[test]
+fn route_policies() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (other_pk, _) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let router = DynamicRouter::default();
+    router
+        .register_route_with_policy("mine", Policy::keys(vec![our_pk]), Pong)
+        .unwrap();
+    router
+        .register_route_with_policy("theirs", Policy::keys(vec![other_pk]), Pong)
+        .unwrap();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let system = AngelSystem::new(store, authenticator, server_pk, server_sk, router);
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    let routed = |route: &'static str| {
+        let mut payload = Route::from(route).as_u64().to_be_bytes().to_vec();
+        payload.extend_from_slice(b"ping");
+        session.make_message(&payload).unwrap()
+    };
+
+    let reply = system.process(routed("mine")).unwrap();
+    assert_eq!(session.read_reply(&reply).unwrap(), b"pong".to_vec());
+
+    // Client sees it as a distinct code and keeps the session.
+    let denied = system.process(routed("theirs")).unwrap();
+    match session.read_reply(&denied) {
+        Err(LlsdError::Application(ref err)) if err.code == ErrorCode::PermissionDenied => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+    assert!(system.process(routed("mine")).is_ok());
+}
+
+struct Shop;
+impl RouteAction for Shop {
+    fn process(&self,
+               route: &Route,
+               _: ServiceHub,
+               _: ShareSession,
+               _: &mut BytesMut)
+               -> AWResult<Bytes> {
+        if *route == Route::from("buy") {
+            let err = AppError::new(ErrorCode::Other(1000), "Out of stock")
+                .with_detail(&b"sku-42"[..]);
+            return Err(err.into());
+        }
+        Err(AWError::ServerFault)
+    }
+}
+
+#[test]
+fn application_errors() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let router = DynamicRouter::default();
+    router.register_route("buy", Shop).unwrap();
+    router.register_route("crash", Shop).unwrap();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let system = AngelSystem::new(store, authenticator, server_pk, server_sk, router);
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+
+    let call = |route: &'static str| {
+        let mut payload = Route::from(route).as_u64().to_be_bytes().to_vec();
+        payload.extend_from_slice(b"please");
+        let reply = system.process(session.make_message(&payload).unwrap()).unwrap();
+        session.read_reply(&reply)
+    };
+
+    // Application error goes to the client as is.
+    match call("buy") {
+        Err(LlsdError::Application(ref err)) => {
+            assert_eq!(*err,
+                       AppError::new(ErrorCode::Other(1000), "Out of stock")
+                           .with_detail(&b"sku-42"[..]));
+        }
+        other => panic!("Expected application error, got {:?}", other),
+    }
+
+    // Server faults don't tell anything.
+    match call("crash") {
+        Err(LlsdError::Application(ref err)) => {
+            assert_eq!(*err, AppError::new(ErrorCode::ServerFault, "Server fault"));
+        }
+        other => panic!("Expected application error, got {:?}", other),
+    }
+}
+
+struct Greeting(&'static str);
+
+struct GreetingHandler;
+impl Handler for GreetingHandler {
+    fn handle(&self, services: ServiceHub, _: ShareSession, _: &mut BytesMut) -> AWResult<Bytes> {
+        let greeting = services.require::<Greeting>()?;
+        Ok(Bytes::from(greeting.0))
+    }
+}
+
+#[test]
+fn services_reach_handler() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let start = |system: AngelSystem<HashMapStore, DumbAuthenticator, GreetingHandler>| {
+        let mut session = ClientSession::new(server_pk, (our_pk, our_sk.clone()));
+        let welcome = system.process(session.make_hello()).unwrap();
+        let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+        session.read_ready(&ready).unwrap();
+        let reply = system.process(session.make_message(b"hi").unwrap()).unwrap();
+        session.read_reply(&reply)
+    };
+
+    let system = AngelSystem::builder(HashMapStore::default(),
+                                      DumbAuthenticator::new(vec![our_pk]),
+                                      (server_pk, server_sk.clone()),
+                                      GreetingHandler)
+        .services(Services::new().with(Greeting("hello")))
+        .build();
+    assert!(system.services().contains::<PushRegistry>());
+    assert_eq!(start(system).unwrap(), b"hello".to_vec());
+
+    // Nothing registered: server is misconfigured.
+    let system = AngelSystem::new(HashMapStore::default(),
+                                  DumbAuthenticator::new(vec![our_pk]),
+                                  server_pk,
+                                  server_sk,
+                                  GreetingHandler);
+    match start(system) {
+        Err(LlsdError::Application(ref err)) if err.code == ErrorCode::ServerFault => {}
+        other => panic!("Expected ServerFault, got {:?}", other),
+    }
+}
+
+struct NullSink;
+impl PushSink for NullSink {
+    fn deliver(&self, _: Frame) -> bool {
+        true
+    }
+}
+
+#[test]
+fn reap_forgets_pushes() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let config = Config {
+        session: SessionConfig {
+            max_idle: Some(chrono::Duration::milliseconds(10)),
+            ..SessionConfig::default()
+        },
+        ..Config::default()
+    };
+    let system = AngelSystem::with_config(HashMapStore::default(),
+                                          DumbAuthenticator::new(vec![our_pk]),
+                                          server_pk,
+                                          server_sk,
+                                          EchoHandler::default(),
+                                          config);
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+    let pushes = system.push_registry();
+    pushes.register(session.id(), Arc::new(NullSink));
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(system.reap().idle, 1);
+    assert!(!pushes.is_connected(&session.id()));
+}
+
+#[test]
+fn expired_session_is_refused_before_reap() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let config = Config {
+        session: SessionConfig {
+            max_idle: Some(chrono::Duration::milliseconds(100)),
+            ..SessionConfig::default()
+        },
+        ..Config::default()
+    };
+    let system = AngelSystem::with_config(HashMapStore::default(),
+                                          DumbAuthenticator::new(vec![our_pk]),
+                                          server_pk,
+                                          server_sk,
+                                          EchoHandler::default(),
+                                          config);
+
+    let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+    let welcome = system.process(session.make_hello()).unwrap();
+    let ready = system.process(session.make_initiate(&welcome).unwrap()).unwrap();
+    session.read_ready(&ready).unwrap();
+    assert!(system.process(session.make_message(b"ping").unwrap()).is_ok());
+
+    thread::sleep(Duration::from_millis(200));
+    let err = match system.process(session.make_message(b"ping").unwrap()) {
+        Err(err @ AWError::LlsdError(LlsdError::ExpiredSession)) => err,
+        other => panic!("Expected ExpiredSession, got {:?}", other),
+    };
+    // Session is still stored, so client gets termination it can trust.
+    let frame = system.terminate(&session.id(), &err);
+    assert_eq!(session.read_termination(&frame).unwrap().code, ErrorCode::ExpiredSession);
+}
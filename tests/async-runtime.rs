@@ -0,0 +1,828 @@
+#![cfg(feature = "async-runtime")]
+
+extern crate angel_whisper;
+extern crate bytes;
+extern crate chrono;
+extern crate tokio1;
+
+use angel_whisper::{AngelSystem, ClientSession, Sendable, SessionConfig};
+use angel_whisper::angel_system::Config;
+use angel_whisper::angel_system::runtime::{Blocking, Reaper, Server};
+use angel_whisper::llsd::runtime::{PUSH_REQUEST_ID, Transport, read_tagged_frame,
+                                   write_tagged_frame};
+
+use angel_whisper::crypto::{PublicKey, gen_keypair, gen_nonce};
+use angel_whisper::frames::{ErrorCode, Frame, FrameKind};
+use angel_whisper::llsd::errors::LlsdError;
+use angel_whisper::llsd::client::ConnectionState;
+use angel_whisper::llsd::client::runtime::{TcpEngine, TcpMultiplexEngine};
+use angel_whisper::errors::AWResult;
+use angel_whisper::system::{AsyncHandler, Handler, HandlerFuture, ServiceHub, ShareSession};
+use angel_whisper::system::push::PushRegistry;
+use angel_whisper::system::authenticator::DumbAuthenticator;
+use angel_whisper::system::hashmapstore::HashMapStore;
+use bytes::{Bytes, BytesMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio1::net::{TcpListener, TcpStream};
+use tokio1::runtime::Builder;
+use tokio1::sync::Notify;
+
+mod support;
+use support::service::EchoHandler;
+
+#[test]
+fn test_async_ping_pong() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = Arc::new(AngelSystem::new(store,
+                                           authenticator,
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::new(system);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let mut client = TcpEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        assert!(client.connection_state() == ConnectionState::NotReady);
+
+        client.authenticate().await.expect("handshake failed");
+        assert!(client.connection_state() == ConnectionState::Ready);
+
+        let pong: BytesMut = client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+    });
+}
+
+#[test]
+fn test_error_reply_keeps_connection() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::new(system);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let mut client = TcpEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+
+        let res: Result<BytesMut, LlsdError> = client.request(None, Bytes::from(&b"wat"[..])).await;
+        match res {
+            Err(LlsdError::Application(ref err)) if err.code == ErrorCode::NotImplemented => {}
+            other => panic!("Expected NotImplemented, got {:?}", other),
+        }
+
+        let pong: BytesMut = client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+    });
+}
+
+#[test]
+fn test_request_handshakes_on_demand() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        assert!(client.connection_state() == ConnectionState::NotReady);
+
+        let pong: BytesMut = client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+        assert!(client.connection_state() == ConnectionState::Ready);
+    });
+}
+
+#[test]
+fn test_engine_session_config() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::new(system);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        // Session that is always about to expire.
+        let config = SessionConfig {
+            renew_before: chrono::Duration::days(1),
+            ..SessionConfig::default()
+        };
+        let mut client = TcpEngine::connect_with_config(&addr, (our_pk, our_sk), server_pk, config)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        assert_eq!(client.session().unwrap().config(), config);
+
+        let old_id = client.session().unwrap().id();
+        let pong: BytesMut = client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+        assert!(client.session().unwrap().id() != old_id);
+        assert_eq!(client.session().unwrap().config(), config);
+    });
+}
+
+#[test]
+fn test_request_renews_lost_session() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let config = Config {
+        session: SessionConfig {
+            max_idle: Some(chrono::Duration::milliseconds(100)),
+            ..SessionConfig::default()
+        },
+        ..Config::default()
+    };
+    let system = Arc::new(AngelSystem::with_config(HashMapStore::default(),
+                                                   DumbAuthenticator::new(vec![our_pk]),
+                                                   server_pk,
+                                                   server_sk,
+                                                   Blocking::new(EchoHandler::default()),
+                                                   config));
+    let pipelined = Server::new(system.clone());
+    let multiplexed = Server::with_transport(system.clone(), Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pipelined_addr = listener.local_addr().unwrap();
+        tokio1::spawn(pipelined.serve(listener));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let multiplexed_addr = listener.local_addr().unwrap();
+        tokio1::spawn(multiplexed.serve(listener));
+
+        let mut client = TcpEngine::connect(&pipelined_addr, (our_pk, our_sk.clone()), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let old_id = client.session().unwrap().id();
+        let multiplexed_client =
+            TcpMultiplexEngine::connect(&multiplexed_addr, (our_pk, our_sk), server_pk)
+                .await
+                .expect("failed to connect");
+        multiplexed_client.authenticate().await.expect("handshake failed");
+
+        // Server reaps both sessions and can only answer in plain text.
+        tokio1::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(system.reap().idle, 2);
+
+        let pong: BytesMut = client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+        assert!(client.session().unwrap().id() != old_id);
+        let pong: BytesMut = multiplexed_client
+            .request(None, Bytes::from(&b"ping"[..]))
+            .await
+            .unwrap();
+        assert_eq!(pong, b"pong".to_vec());
+    });
+}
+
+// Echoes everything back, but holds "slow" until test opens the gate.
+struct GateHandler {
+    entered: Arc<Notify>,
+    gate: Arc<Notify>,
+}
+
+impl AsyncHandler for GateHandler {
+    fn handle(&self, _: ServiceHub, _: ShareSession, msg: BytesMut) -> HandlerFuture {
+        let entered = self.entered.clone();
+        let gate = self.gate.clone();
+        Box::pin(async move {
+            if msg.as_ref() == b"slow" {
+                entered.notify_one();
+                gate.notified().await;
+            }
+            Ok(msg.freeze())
+        })
+    }
+}
+
+#[test]
+fn test_multiplexed_out_of_order() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let entered = Arc::new(Notify::new());
+    let gate = Arc::new(Notify::new());
+
+    let system = Arc::new(AngelSystem::new(store,
+                                           authenticator,
+                                           server_pk,
+                                           server_sk,
+                                           GateHandler {
+                                               entered: entered.clone(),
+                                               gate: gate.clone(),
+                                           }));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let client = Arc::new(client);
+
+        let slow_client = client.clone();
+        let slow = tokio1::spawn(async move {
+            slow_client
+                .request::<_, BytesMut>(None, Bytes::from(&b"slow"[..]))
+                .await
+        });
+
+        // Slow request is stuck in the handler, but doesn't block this one.
+        entered.notified().await;
+        let fast: BytesMut = client
+            .request(None, Bytes::from(&b"fast"[..]))
+            .await
+            .unwrap();
+        assert_eq!(fast, b"fast".to_vec());
+
+        gate.notify_one();
+        let slow = slow.await.unwrap().unwrap();
+        assert_eq!(slow, b"slow".to_vec());
+    });
+}
+
+#[test]
+fn test_multiplexed_reply_after_renewal() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let entered = Arc::new(Notify::new());
+    let gate = Arc::new(Notify::new());
+
+    let system = Arc::new(AngelSystem::new(store,
+                                           authenticator,
+                                           server_pk,
+                                           server_sk,
+                                           GateHandler {
+                                               entered: entered.clone(),
+                                               gate: gate.clone(),
+                                           }));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let client = Arc::new(client);
+
+        let slow_client = client.clone();
+        let slow = tokio1::spawn(async move {
+            slow_client
+                .request::<_, BytesMut>(None, Bytes::from(&b"slow"[..]))
+                .await
+        });
+        entered.notified().await;
+
+        // Session is replaced while slow request is in flight. Its reply is
+        // still sealed with the old one.
+        client.authenticate().await.expect("handshake failed");
+        gate.notify_one();
+        assert_eq!(slow.await.unwrap().unwrap(), b"slow".to_vec());
+
+        let fast: BytesMut = client
+            .request(None, Bytes::from(&b"fast"[..]))
+            .await
+            .unwrap();
+        assert_eq!(fast, b"fast".to_vec());
+    });
+}
+
+#[test]
+fn test_multiplexed_in_flight_limit() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let entered = Arc::new(Notify::new());
+    let gate = Arc::new(Notify::new());
+    let config = Config {
+        max_in_flight: 1,
+        ..Config::default()
+    };
+
+    let system = Arc::new(AngelSystem::with_config(store,
+                                                   authenticator,
+                                                   server_pk,
+                                                   server_sk,
+                                                   GateHandler {
+                                                       entered: entered.clone(),
+                                                       gate: gate.clone(),
+                                                   },
+                                                   config));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let client = Arc::new(client);
+
+        let slow_client = client.clone();
+        let slow = tokio1::spawn(async move {
+            slow_client
+                .request::<_, BytesMut>(None, Bytes::from(&b"slow"[..]))
+                .await
+        });
+        entered.notified().await;
+
+        // Slow request takes the only slot, so this one waits for it.
+        let fast_client = client.clone();
+        let mut fast = tokio1::spawn(async move {
+            fast_client
+                .request::<_, BytesMut>(None, Bytes::from(&b"fast"[..]))
+                .await
+        });
+        assert!(tokio1::time::timeout(Duration::from_millis(100), &mut fast)
+                    .await
+                    .is_err());
+
+        gate.notify_one();
+        assert_eq!(slow.await.unwrap().unwrap(), b"slow".to_vec());
+        assert_eq!(fast.await.unwrap().unwrap(), b"fast".to_vec());
+    });
+}
+
+#[test]
+fn test_dropped_call() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let entered = Arc::new(Notify::new());
+    let gate = Arc::new(Notify::new());
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           GateHandler {
+                                               entered: entered.clone(),
+                                               gate: gate.clone(),
+                                           }));
+    let pipelined = Server::new(system.clone());
+    let multiplexed = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(pipelined.serve(listener));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let multiplexed_addr = listener.local_addr().unwrap();
+        tokio1::spawn(multiplexed.serve(listener));
+
+        // Reply to the dropped request would be read as reply to the next
+        // one, so engine gives up on the connection.
+        let mut client = TcpEngine::connect(&addr, (our_pk, our_sk.clone()), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let slow = client.request::<_, BytesMut>(None, Bytes::from(&b"slow"[..]));
+        assert!(tokio1::time::timeout(Duration::from_millis(100), slow).await.is_err());
+        gate.notify_one();
+        match client.request::<_, BytesMut>(None, Bytes::from(&b"fast"[..])).await {
+            Err(LlsdError::Io(_)) => {}
+            other => panic!("Expected Io error, got {:?}", other),
+        }
+
+        // Multiplexed engine just throws the late reply away.
+        let client = TcpMultiplexEngine::connect(&multiplexed_addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let slow = client.request::<_, BytesMut>(None, Bytes::from(&b"slow"[..]));
+        assert!(tokio1::time::timeout(Duration::from_millis(100), slow).await.is_err());
+        gate.notify_one();
+        let fast: BytesMut = client
+            .request(None, Bytes::from(&b"fast"[..]))
+            .await
+            .unwrap();
+        assert_eq!(fast, b"fast".to_vec());
+    });
+}
+
+// Pushes "news" before replying to any message.
+struct NewsHandler;
+
+impl Handler for NewsHandler {
+    fn handle(&self,
+              services: ServiceHub,
+              session: ShareSession,
+              _: &mut BytesMut)
+              -> AWResult<Bytes> {
+        let pushes = services.require::<PushRegistry>()?;
+        pushes.push(&session.read().unwrap(), b"news")?;
+        Ok(Bytes::from(&b"ok"[..]))
+    }
+}
+
+#[test]
+fn test_multiplexed_push() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+
+    let system = Arc::new(AngelSystem::new(store,
+                                           authenticator,
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(NewsHandler)));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let mut subscription = client.subscribe();
+
+        let ok: BytesMut = client
+            .request(None, Bytes::from(&b"anything"[..]))
+            .await
+            .unwrap();
+        assert_eq!(ok, b"ok".to_vec());
+
+        let news = subscription.next().await.unwrap();
+        assert_eq!(news, b"news".to_vec());
+    });
+}
+
+#[test]
+fn test_multiplexed_push_read_late() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(NewsHandler)));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let mut subscription = client.subscribe();
+
+        // Way more replies than nonce window holds arrive before first push
+        // is read.
+        for _ in 0..100 {
+            let _: BytesMut = client.request(None, Bytes::from(&b"hi"[..])).await.unwrap();
+        }
+        for _ in 0..100 {
+            assert_eq!(subscription.next().await.unwrap(), b"news".to_vec());
+        }
+    });
+}
+
+// Pushes "news" before replying to any message, remembers who asked.
+struct WitnessHandler {
+    sessions: Arc<Mutex<Vec<PublicKey>>>,
+}
+
+impl Handler for WitnessHandler {
+    fn handle(&self,
+              services: ServiceHub,
+              session: ShareSession,
+              _: &mut BytesMut)
+              -> AWResult<Bytes> {
+        let session = session.read().unwrap();
+        self.sessions.lock().unwrap().push(session.id());
+        services.require::<PushRegistry>()?.push(&session, b"news")?;
+        Ok(Bytes::from(&b"ok"[..]))
+    }
+}
+
+#[test]
+fn test_multiplexed_push_not_hijacked() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let sessions = Arc::new(Mutex::new(Vec::new()));
+
+    let system = Arc::new(AngelSystem::new(store,
+                                           authenticator,
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(WitnessHandler {
+                                                             sessions: sessions.clone(),
+                                                         })));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let client = TcpMultiplexEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+        client.authenticate().await.expect("handshake failed");
+        let mut subscription = client.subscribe();
+        let _: BytesMut = client.request(None, Bytes::from(&b"hi"[..])).await.unwrap();
+        assert_eq!(subscription.next().await.unwrap(), b"news".to_vec());
+        let victim = sessions.lock().unwrap()[0];
+
+        // Somebody else sends junk on behalf of the session and hangs up.
+        let mut attacker = TcpStream::connect(&addr).await.unwrap();
+        let junk = Frame {
+            id: victim,
+            nonce: gen_nonce(),
+            kind: FrameKind::Message,
+            payload: vec![0; 64].into(),
+        };
+        write_tagged_frame(&mut attacker, 1, &junk).await.unwrap();
+        let (_, reply) = read_tagged_frame(&mut attacker).await.unwrap().unwrap();
+        assert_eq!(reply.kind, FrameKind::Termination);
+        drop(attacker);
+        tokio1::time::sleep(Duration::from_millis(50)).await;
+
+        // Pushes still go to the owner.
+        let ok: BytesMut = client.request(None, Bytes::from(&b"hi"[..])).await.unwrap();
+        assert_eq!(ok, b"ok".to_vec());
+        assert_eq!(subscription.next().await.unwrap(), b"news".to_vec());
+    });
+}
+
+#[test]
+fn test_multiplexed_rejects_push_id() {
+    let (our_pk, _) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        // Reply to this one would look like a push, so server hangs up.
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        let hello = ClientSession::new(server_pk, gen_keypair()).make_hello();
+        write_tagged_frame(&mut client, PUSH_REQUEST_ID, &hello).await.unwrap();
+        match read_tagged_frame(&mut client).await {
+            Ok(None) | Err(_) => {}
+            Ok(Some(_)) => panic!("Request with push id was answered"),
+        }
+    });
+}
+
+// Old tokio-proto client numbers requests from 0, server must not mistake
+// them for anything else.
+#[cfg(feature = "system-on-tokio")]
+#[test]
+fn test_multiplexed_tokio_proto_client() {
+    use angel_whisper::llsd::client::{Engine, EngineSugar};
+    use angel_whisper::llsd::client::tokio::TcpMultiplexEngine as ProtoMultiplexEngine;
+    use angel_whisper::tokio::Core;
+    use std::thread;
+
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(EchoHandler::default())));
+    let server = Server::with_transport(system, Transport::Multiplexed);
+
+    // Server gets a runtime of its own, client runs on tokio-core reactor.
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .expect("Failed to create runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+            let _ = server.serve(listener).await;
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let mut core = Core::new().expect("Failed to create reactor [thread]");
+    let client_future =
+        ProtoMultiplexEngine::connect(&addr, core.handle(), (our_pk, our_sk), server_pk);
+    let mut client = core.run(client_future).expect("failed to connect");
+
+    core.run(client.authenticate()).expect("handshake failed");
+    assert!(client.connection_state() == ConnectionState::Ready);
+
+    let ping = client.request::<_, BytesMut>(None, Bytes::from(&b"ping"[..]));
+    assert_eq!(core.run(ping).unwrap(), b"pong".to_vec());
+}
+
+#[test]
+fn test_reaper() {
+    let (our_pk, our_sk) = gen_keypair();
+
+    let (server_pk, server_sk) = gen_keypair();
+
+    let store = HashMapStore::default();
+    let authenticator = DumbAuthenticator::new(vec![our_pk]);
+    let config = Config {
+        session: SessionConfig {
+            max_idle: Some(chrono::Duration::milliseconds(10)),
+            ..SessionConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let system = Arc::new(AngelSystem::with_config(store,
+                                                   authenticator,
+                                                   server_pk,
+                                                   server_sk,
+                                                   Blocking::new(EchoHandler::default()),
+                                                   config));
+
+    let rt = Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        // Client completes the handshake and then goes quiet
+        let mut session = ClientSession::new(server_pk, (our_pk, our_sk));
+        let welcome = system.process_async(session.make_hello()).await.unwrap();
+        let initiate = session.make_initiate(&welcome).unwrap();
+        let ready = system.process_async(initiate).await.unwrap();
+        session.read_ready(&ready).unwrap();
+
+        let reaper = Reaper::spawn(system.clone(), Duration::from_millis(5));
+        tokio1::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(reaper.runs() > 0);
+        assert_eq!(reaper.removed().idle, 1);
+        assert_eq!(reaper.removed().total(), 1);
+
+        // Session is gone, so client's messages bounce
+        let msg = session.make_message(b"still there?").unwrap();
+        assert!(system.process_async(msg).await.is_err());
+    });
+}
@@ -0,0 +1,154 @@
+extern crate angel_whisper;
+extern crate quickcheck;
+
+use angel_whisper::{ClientSession, ServerSession};
+use angel_whisper::crypto::{PublicKey, gen_keypair, gen_nonce};
+use angel_whisper::frames::{Frame, FrameKind, Termination};
+use angel_whisper::llsd::errors::LlsdError;
+use angel_whisper::llsd::session::INITIATE_CONTENT_SIZE;
+use angel_whisper::llsd::session::cookie::CookieJar;
+use angel_whisper::llsd::session::server::make_welcome;
+use angel_whisper::system::hashmapstore::HashMapStore;
+use angel_whisper::system::sessionstore::SessionStore;
+use quickcheck::{QuickCheck, TestResult};
+
+mod support;
+use support::handshake::{forged_initiate, vouched_content};
+
+const TESTS: u64 = 500;
+
+fn frame(kind: FrameKind, id: PublicKey, payload: Vec<u8>) -> Frame {
+    Frame {
+        id: id,
+        nonce: gen_nonce(),
+        kind: kind,
+        payload: payload.into(),
+    }
+}
+
+// Garbage in Initiate is rejected, never accepted and never panics.
+#[test]
+fn initiate_garbage_is_rejected() {
+    fn prop(payload: Vec<u8>) -> bool {
+        let server_lt = gen_keypair();
+        let cookies = CookieJar::new();
+        let client = ClientSession::new(server_lt.0, gen_keypair());
+        make_welcome(&client.make_hello(), &server_lt.into(), &cookies).unwrap();
+        let initiate = frame(FrameKind::Initiate, client.id(), payload);
+        ServerSession::from_cookie(&initiate, &cookies, Default::default())
+            .and_then(|mut server| server.validate_initiate(&initiate))
+            .is_err()
+    }
+    QuickCheck::new()
+        .tests(TESTS)
+        .quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+// Even if box is authentic, its content has to be exactly what client is
+// supposed to put there: right length and a vouch that opens.
+#[test]
+fn initiate_content_is_verified() {
+    fn prop(content: Vec<u8>, full_size: bool) -> TestResult {
+        let mut content = content;
+        if full_size {
+            content.resize(INITIATE_CONTENT_SIZE, 0);
+        }
+        let (server, initiate) = forged_initiate(&gen_keypair(), |_, _| content);
+        match server.validate_initiate(&initiate) {
+            Err(LlsdError::InvalidInitiateFrame) => TestResult::passed(),
+            _ => TestResult::failed(),
+        }
+    }
+    QuickCheck::new()
+        .tests(TESTS)
+        .quickcheck(prop as fn(Vec<u8>, bool) -> TestResult);
+}
+
+// Whatever server replies with, client doesn't panic and doesn't get ready.
+#[test]
+fn welcome_and_ready_garbage_is_rejected() {
+    fn prop(welcome: Vec<u8>, ready: Vec<u8>) -> bool {
+        let server_lt = gen_keypair();
+        let mut client = ClientSession::new(server_lt.0, gen_keypair());
+        let id = client.id();
+        let welcome = client.make_initiate(&frame(FrameKind::Welcome, id, welcome));
+        let ready = client.read_ready(&frame(FrameKind::Ready, id, ready));
+        welcome.is_err() && ready.is_err()
+    }
+    QuickCheck::new()
+        .tests(TESTS)
+        .quickcheck(prop as fn(Vec<u8>, Vec<u8>) -> bool);
+}
+
+// Hello that server can't open gets no Welcome.
+#[test]
+fn hello_garbage_is_rejected() {
+    fn prop(payload: Vec<u8>) -> bool {
+        let server_lt = gen_keypair();
+        let (client_pk, _) = gen_keypair();
+        make_welcome(&frame(FrameKind::Hello, client_pk, payload),
+                     &server_lt.into(),
+                     &CookieJar::new())
+            .is_err()
+    }
+    QuickCheck::new()
+        .tests(TESTS)
+        .quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+// Parsers never panic on arbitrary bytes.
+#[test]
+fn parsers_dont_panic() {
+    fn prop(bytes: Vec<u8>) -> bool {
+        let _ = Frame::from_slice(&bytes);
+        let _ = Termination::from_slice(&bytes);
+        let _ = FrameKind::from_slice(&bytes);
+        HashMapStore::default().find(&bytes).is_none()
+    }
+    QuickCheck::new()
+        .tests(TESTS)
+        .quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+// Vouch proves that whoever owns the long-term key owns the session.
+#[test]
+fn honest_vouch() {
+    let client_lt = gen_keypair();
+    let server_lt = gen_keypair();
+
+    let (server, initiate) = forged_initiate(&server_lt, |client_st, server_st| {
+        vouched_content(&client_lt.0, &client_lt.1, client_st, server_st)
+    });
+    assert_eq!(server.validate_initiate(&initiate).unwrap(), client_lt.0);
+}
+
+#[test]
+fn stolen_long_term_key() {
+    let victim_lt = gen_keypair();
+    let attacker_lt = gen_keypair();
+    let server_lt = gen_keypair();
+
+    // Attacker knows victim's public key, but has to vouch with own secret.
+    let (server, initiate) = forged_initiate(&server_lt, |client_st, server_st| {
+        vouched_content(&victim_lt.0, &attacker_lt.1, client_st, server_st)
+    });
+    match server.validate_initiate(&initiate) {
+        Err(LlsdError::InvalidInitiateFrame) => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+}
+
+#[test]
+fn vouch_for_other_session() {
+    let client_lt = gen_keypair();
+    let server_lt = gen_keypair();
+    let (other_session, _) = gen_keypair();
+
+    let (server, initiate) = forged_initiate(&server_lt, |_, server_st| {
+        vouched_content(&client_lt.0, &client_lt.1, &other_session, server_st)
+    });
+    match server.validate_initiate(&initiate) {
+        Err(LlsdError::InvalidInitiateFrame) => {}
+        _ => panic!("WRONG ERROR KIND"),
+    }
+}
@@ -0,0 +1,104 @@
+#![cfg(all(feature = "protobuf", feature = "async-runtime"))]
+
+extern crate angel_whisper;
+extern crate prost;
+#[macro_use]
+extern crate prost_derive;
+extern crate tokio1;
+
+use angel_whisper::AngelSystem;
+use angel_whisper::angel_system::runtime::{Blocking, Server};
+use angel_whisper::crypto::gen_keypair;
+use angel_whisper::errors::AWResult;
+use angel_whisper::llsd::client::runtime::TcpEngine;
+use angel_whisper::llsd::errors::LlsdError;
+use angel_whisper::llsd::proto::Proto;
+use angel_whisper::llsd::route::Route;
+use angel_whisper::system::authenticator::DumbAuthenticator;
+use angel_whisper::system::hashmapstore::HashMapStore;
+use angel_whisper::system::proto::ProtoAction;
+use angel_whisper::system::router::DynamicRouter;
+use std::sync::Arc;
+use tokio1::net::TcpListener;
+use tokio1::runtime::Builder;
+
+// Same as `Add` in proto/frame.proto.
+#[derive(Clone, PartialEq, Debug, Message)]
+pub struct Add {
+    #[prost(int64, required, tag = "1")]
+    pub left: i64,
+    #[prost(int64, required, tag = "2")]
+    pub right: i64,
+}
+
+#[derive(Clone, PartialEq, Debug, Message)]
+pub struct Sum {
+    #[prost(int64, required, tag = "1")]
+    pub sum: i64,
+}
+
+#[derive(Clone, PartialEq, Debug, Message)]
+pub struct Negate {
+    #[prost(int64, required, tag = "1")]
+    pub value: i64,
+}
+
+#[test]
+fn test_protobuf_routes() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let router = DynamicRouter::default();
+    router
+        .register_route("calc::add",
+                        ProtoAction::new(|add: Add| -> AWResult<Sum> {
+                                             Ok(Sum { sum: add.left + add.right })
+                                         }))
+        .unwrap();
+    router
+        .register_route("calc::negate",
+                        ProtoAction::new(|negate: Negate| -> AWResult<Sum> {
+                                             Ok(Sum { sum: -negate.value })
+                                         }))
+        .unwrap();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           Blocking::new(router)));
+    let server = Server::new(system);
+
+    let rt = Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("Failed to create runtime");
+
+    rt.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio1::spawn(server.serve(listener));
+
+        let mut client = TcpEngine::connect(&addr, (our_pk, our_sk), server_pk)
+            .await
+            .expect("failed to connect");
+
+        let sum: Proto<Sum> = client
+            .request(Some(Route::from("calc::add")), Proto(Add { left: 40, right: 2 }))
+            .await
+            .unwrap();
+        assert_eq!(sum.into_inner(), Sum { sum: 42 });
+
+        let sum: Proto<Sum> = client
+            .request(Some(Route::from("calc::negate")), Proto(Negate { value: 7 }))
+            .await
+            .unwrap();
+        assert_eq!(sum.into_inner(), Sum { sum: -7 });
+
+        // Route doesn't exist.
+        let res: Result<Proto<Sum>, LlsdError> = client
+            .request(Some(Route::from("calc::mul")), Proto(Add { left: 6, right: 7 }))
+            .await;
+        assert!(res.is_err());
+    });
+}
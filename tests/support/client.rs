@@ -27,7 +27,7 @@ impl Client {
     pub fn connect(self,
                    addr: &SocketAddr,
                    handle: &Handle)
-                   -> Box<Future<Item = ClientHandle, Error = io::Error>> {
+                   -> Box<dyn Future<Item = ClientHandle, Error = io::Error>> {
         let ret = TcpClient::new(WhisperPipelinedProtocol)
             .connect(addr, handle)
             .map(|c| ClientHandle { inner: c });
@@ -40,9 +40,9 @@ impl Service for ClientHandle {
     type Request = Frame;
     type Response = Frame;
     type Error = io::Error;
-    type Future = Box<Future<Item = Frame, Error = io::Error>>;
+    type Future = Box<dyn Future<Item = Frame, Error = io::Error>>;
 
-    fn call(&self, req: Frame) -> Box<Future<Item = Frame, Error = io::Error>> {
+    fn call(&self, req: Frame) -> Box<dyn Future<Item = Frame, Error = io::Error>> {
         Box::new(self.inner.call(req))
     }
 }
@@ -0,0 +1,57 @@
+use angel_whisper::ServerSession;
+use angel_whisper::crypto::{PUBLICKEYBYTES, PublicKey, SecretKey, gen_keypair, gen_nonce, open,
+                            seal};
+use angel_whisper::frames::{Frame, FrameKind};
+use angel_whisper::llsd::session::{KeyPair, NULL_BYTES};
+use angel_whisper::llsd::session::loopback::cookie_exchange;
+
+/// Play client by hand up to Initiate, so it can put whatever it wants into
+/// Initiate box. `content` gets client's and server's short-term public keys
+/// and returns what goes into the box. Returns server side of the session
+/// made from the cookie and the Initiate itself.
+pub fn forged_initiate<F>(server_lt: &KeyPair, content: F) -> (ServerSession, Frame)
+where
+    F: FnOnce(&PublicKey, &PublicKey) -> Vec<u8>,
+{
+    let client_st = gen_keypair();
+    let nonce = gen_nonce();
+    let hello = Frame {
+        id: client_st.0,
+        nonce: nonce,
+        kind: FrameKind::Hello,
+        payload: seal(&NULL_BYTES, &nonce, &server_lt.0, &client_st.1).into(),
+    };
+    cookie_exchange(server_lt, &hello, |welcome| {
+        let welcome = open(&welcome.payload, &welcome.nonce, &server_lt.0, &client_st.1).unwrap();
+        let (server_st, cookie) = welcome.split_at(PUBLICKEYBYTES);
+        let server_st = PublicKey::from_slice(server_st).unwrap();
+
+        let content = content(&client_st.0, &server_st);
+        let nonce = gen_nonce();
+        let mut payload = cookie.to_vec();
+        payload.extend(seal(&content, &nonce, &server_st, &client_st.1));
+        Ok(Frame {
+               id: client_st.0,
+               nonce: nonce,
+               kind: FrameKind::Initiate,
+               payload: payload.into(),
+           })
+    })
+        .unwrap()
+}
+
+/// Initiate box content claiming `claimed_lt_pk`, with vouch made with
+/// `vouch_sk` for `vouched_pk`. Honest client uses its long-term secret key
+/// and session id.
+pub fn vouched_content(claimed_lt_pk: &PublicKey,
+                       vouch_sk: &SecretKey,
+                       vouched_pk: &PublicKey,
+                       server_st: &PublicKey)
+                       -> Vec<u8> {
+    let nonce = gen_nonce();
+    let mut content = Vec::new();
+    content.extend_from_slice(&claimed_lt_pk.0);
+    content.extend_from_slice(&nonce.0);
+    content.extend(seal(&vouched_pk.0, &nonce, server_st, vouch_sk));
+    content
+}
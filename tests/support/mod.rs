@@ -2,7 +2,8 @@
 pub mod service {
     use angel_whisper::ServerSession;
     use angel_whisper::errors::{AWError, AWResult};
-    use angel_whisper::system::{Handler, ServiceHub};
+    use angel_whisper::system::{Handler, ServiceHub, identity};
+    use angel_whisper::system::authenticator::Identity;
     use bytes::{Bytes, BytesMut};
     use std::default::Default;
     use std::sync::{Arc, RwLock};
@@ -26,6 +27,24 @@ pub mod service {
             }
         }
     }
+
+    /// Replies with user id of the caller, `anonymous` if it has none.
+    pub struct WhoAmIHandler;
+    impl Handler for WhoAmIHandler {
+        fn handle(&self,
+                  _: ServiceHub,
+                  session: Arc<RwLock<ServerSession>>,
+                  _: &mut BytesMut)
+                  -> AWResult<Bytes> {
+            match identity(&session) {
+                Some(Identity { user_id: Some(user_id), .. }) => Ok(user_id.into()),
+                Some(_) => Ok("anonymous".into()),
+                None => Err(AWError::ServerFault),
+            }
+        }
+    }
 }
 #[allow(dead_code)]
 pub mod client;
+#[allow(dead_code)]
+pub mod handshake;
@@ -1,5 +1,6 @@
 #[cfg(feature = "system-on-tokio")]
 
+#[macro_use]
 extern crate angel_whisper;
 extern crate tokio_proto;
 extern crate tokio_io;
@@ -12,16 +13,26 @@ use angel_whisper::{AngelSystem, ClientSession, Sendable};
 use angel_whisper::angel_system::tokio::InlineService;
 
 use angel_whisper::crypto::gen_keypair;
-use angel_whisper::llsd::client::Engine;
-use angel_whisper::llsd::client::tokio::TcpPipelineEngine;
-use angel_whisper::llsd::tokio::WhisperPipelinedProtocol;
+use angel_whisper::errors::AWResult;
+use angel_whisper::frames::{Frame, FrameKind};
+use angel_whisper::llsd::client::{Engine, EngineSugar};
+use angel_whisper::llsd::client::tokio::{TcpMultiplexEngine, TcpPipelineEngine};
+use angel_whisper::llsd::tokio::{WhisperMultiplexedProtocol, WhisperPipelinedProtocol};
+use angel_whisper::system::{Handler, ServiceHub, ShareSession};
 use angel_whisper::system::authenticator::DumbAuthenticator;
 use angel_whisper::system::hashmapstore::HashMapStore;
+use angel_whisper::system::router::DynamicRouter;
 use angel_whisper::tokio::Core;
 use angel_whisper::tokio::Service;
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use std::cell::RefCell;
+use std::io;
 use std::sync::Arc;
 use std::thread;
-use tokio_proto::TcpServer;
+use tokio_core::net::TcpListener;
+use tokio_proto::{BindServer, TcpServer};
 
 mod support;
 
@@ -106,7 +117,7 @@ fn test_ping_pong() {
     let ping = client.call(ping_frame);
     let pong = lp.run(ping).unwrap();
 
-    let pong_payload = session.read_msg(&pong).unwrap();
+    let pong_payload = session.read_reply(&pong).unwrap();
     assert_eq!(pong_payload, b"pong".to_vec());
 }
 
@@ -160,6 +171,158 @@ fn test_tokio_client_engine() {
     let ping = client.call_raw(ping_frame);
     let pong = core.run(ping).unwrap();
 
-    let pong_payload = session.borrow().read_msg(&pong).unwrap();
+    let pong_payload = session.borrow().read_reply(&pong).unwrap();
     assert_eq!(pong_payload, b"pong".to_vec());
 }
+
+// Echoes every message back.
+struct MirrorHandler;
+impl Handler for MirrorHandler {
+    fn handle(&self, _: ServiceHub, _: ShareSession, msg: &mut BytesMut) -> AWResult<Bytes> {
+        Ok(msg.clone().freeze())
+    }
+}
+
+// Holds reply to the first message back until reply to the second one is
+// ready, so replies go out in reverse order.
+struct Reversed<S> {
+    inner: S,
+    held: RefCell<Option<oneshot::Sender<()>>>,
+}
+
+impl<S> Service for Reversed<S>
+where
+    S: Service<Request = Frame, Response = Frame, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = Frame;
+    type Response = Frame;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Frame, Error = io::Error>>;
+
+    fn call(&self, req: Frame) -> Self::Future {
+        let is_message = req.kind == FrameKind::Message;
+        let reply = self.inner.call(req);
+        if !is_message {
+            return Box::new(reply);
+        }
+        let mut held = self.held.borrow_mut();
+        match held.take() {
+            Some(first) => {
+                Box::new(reply.map(move |res| {
+                                       let _ = first.send(());
+                                       res
+                                   }))
+            }
+            None => {
+                let (tx, rx) = oneshot::channel();
+                *held = Some(tx);
+                Box::new(rx.then(move |_| reply))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tokio_multiplex_out_of_order() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           MirrorHandler));
+    let service = InlineService::new(system);
+
+    let mut core = Core::new().expect("Failed to create reactor [thread]");
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = listener
+        .incoming()
+        .for_each(move |(socket, _)| {
+                      let reversed = Reversed {
+                          inner: service.clone(),
+                          held: RefCell::new(None),
+                      };
+                      WhisperMultiplexedProtocol.bind_server(&handle, socket, reversed);
+                      Ok(())
+                  });
+    core.handle().spawn(server.map_err(|_| ()));
+
+    let client_future =
+        TcpMultiplexEngine::connect(&addr, core.handle(), (our_pk, our_sk), server_pk);
+    let mut client = core.run(client_future).expect("failed to connect");
+    core.run(client.authenticate()).expect("handshake failed");
+
+    // Each reply finds its own request even though they come back swapped.
+    let first = client.request::<_, BytesMut>(None, Bytes::from(&b"first"[..]));
+    let second = client.request::<_, BytesMut>(None, Bytes::from(&b"second"[..]));
+    let (first, second) = core.run(first.join(second)).unwrap();
+    assert_eq!(first, b"first".to_vec());
+    assert_eq!(second, b"second".to_vec());
+}
+
+service! {
+    /// Changes text.
+    pub trait Text {
+        /// Uppercase the text.
+        fn shout(&self, session: &ShareSession, req: Bytes) -> AWResult<Bytes>;
+        /// Reverse the text.
+        fn reverse(&self, session: &ShareSession, req: Bytes) -> AWResult<Bytes>;
+    }
+    /// Client for `Text`.
+    pub client TextClient;
+}
+
+struct TextService;
+impl Text for TextService {
+    fn shout(&self, _: &ShareSession, req: Bytes) -> AWResult<Bytes> {
+        Ok(req.to_ascii_uppercase().into())
+    }
+
+    fn reverse(&self, _: &ShareSession, req: Bytes) -> AWResult<Bytes> {
+        Ok(req.iter().rev().cloned().collect::<Vec<u8>>().into())
+    }
+}
+
+#[test]
+fn test_service_client_stub() {
+    let (our_pk, our_sk) = gen_keypair();
+    let (server_pk, server_sk) = gen_keypair();
+
+    let router = DynamicRouter::default();
+    TextService.register_routes(&router).unwrap();
+
+    let system = Arc::new(AngelSystem::new(HashMapStore::default(),
+                                           DumbAuthenticator::new(vec![our_pk]),
+                                           server_pk,
+                                           server_sk,
+                                           router));
+    let service = InlineService::new(system);
+
+    // Server shares reactor with the client and listens on whatever port is
+    // free.
+    let mut core = Core::new().expect("Failed to create reactor [thread]");
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = listener
+        .incoming()
+        .for_each(move |(socket, _)| {
+                      WhisperPipelinedProtocol.bind_server(&handle, socket, service.clone());
+                      Ok(())
+                  });
+    core.handle().spawn(server.map_err(|_| ()));
+
+    let client_future =
+        TcpPipelineEngine::connect(&addr, core.handle(), (our_pk, our_sk), server_pk);
+    let mut text = TextClient::new(core.run(client_future).expect("failed to connect"));
+
+    let shouted = core.run(text.shout(Bytes::from(&b"hello"[..]))).unwrap();
+    assert_eq!(shouted, Bytes::from(&b"HELLO"[..]));
+
+    let reversed = core.run(text.reverse(Bytes::from(&b"hello"[..]))).unwrap();
+    assert_eq!(reversed, Bytes::from(&b"olleh"[..]));
+}